@@ -2,17 +2,40 @@ main();
 
 async function main() {
   console.log('main()');
-  document.querySelector('[name="board"]').addEventListener('change', (event) => {
-    onSelectBoard(event.target.value);
+  document.querySelector('[name="board"][value="seed"]').addEventListener('change', (event) => {
+    onToggleSeedBoard(event.target.checked);
+  })
+  document.querySelector('[name="pd_patch"]').addEventListener('change', (event) => {
+    onChoosePatchFiles(event.target.files);
   })
 }
 
-function onSelectBoard(boardValue) {
-  console.log('onSelectBoard', boardValue);
+function onToggleSeedBoard(checked) {
+  console.log('onToggleSeedBoard', checked);
 
-  if (boardValue === 'seed') {
+  if (checked) {
     document.getElementById('board-def-container').classList.remove('hidden');
   } else {
     document.getElementById('board-def-container').classList.add('hidden');
   }
 }
+
+async function onChoosePatchFiles(files) {
+  const mappingsInput = document.querySelector('[name="parameter_mappings"]');
+  if (files.length !== 1 || !files[0].name.endsWith('.pd') || mappingsInput.value.trim() !== '') {
+    return;
+  }
+
+  const parameterNames = findParameterNames(await files[0].text());
+  console.log('onChoosePatchFiles', parameterNames);
+
+  mappingsInput.value = parameterNames.map((name) => `${name}=`).join('\n');
+}
+
+// Names from every `[r name @hv_param]` and `[s name @hv_param]` in the patch
+function findParameterNames(patchContents) {
+  const parameterPattern = /#X obj -?\d+ -?\d+ (?:r|receive|s|send) (\S+) @hv_param/g;
+  const names = [...patchContents.matchAll(parameterPattern)].map((match) => match[1]);
+
+  return [...new Set(names)];
+}
@@ -0,0 +1,81 @@
+const MAX_ATTEMPTS_PER_PATCH = 60;
+const ATTEMPT_INTERVAL_MS = 1000;
+const FINISHED_STATUSES = ['Compiled', 'Failed'];
+
+main();
+
+async function main() {
+  const groupId = window.GROUP_ID;
+
+  console.log('Group ID:', groupId);
+
+  // The patches in a group compile one after another, so give each of them time to finish
+  const maxAttempts = MAX_ATTEMPTS_PER_PATCH * document.querySelectorAll('[data-patch-id]').length;
+
+  let completed = false;
+  for (let attempts = 0; attempts < maxAttempts; attempts++) {
+    const group = await fetchGroup(groupId);
+
+    const statusNames = group.patches.map(patch => {
+      const statusName = getStatusName(patch.status);
+      updatePatchRow(patch.id, statusName);
+
+      return statusName;
+    });
+
+    if (statusNames.every(statusName => FINISHED_STATUSES.includes(statusName))) {
+      completed = true;
+
+      break;
+    }
+
+    await pause(ATTEMPT_INTERVAL_MS);
+  }
+
+  if (!completed) {
+    alert(`Patches never finished compiling after ${maxAttempts} polling attempts!`);
+  }
+}
+
+function updatePatchRow(patchId, statusName) {
+  const row = document.querySelector(`[data-patch-id="${patchId}"]`);
+
+  row.querySelector('.group-status').innerHTML = getStatusMessage(statusName);
+
+  if (statusName === 'Compiled') {
+    row.querySelector('.group-download').classList.remove('download-disabled');
+  }
+}
+
+async function fetchGroup(groupId) {
+  const response = await fetch(`/api/groups/${groupId}`);
+  const responseBody = await response.json();
+
+  return responseBody;
+}
+
+async function pause(ms) {
+  return new Promise(resolve => {
+    setTimeout(resolve, ms);
+  });
+}
+
+function getStatusName(status) {
+  let statusName = status;
+  if (typeof status === 'object' && status['Failed']) {
+    statusName = 'Failed';
+  }
+
+  return statusName;
+}
+
+function getStatusMessage(statusName) {
+  const messages = {
+    'Uploaded': 'waiting to compile...',
+    'Compiling': 'compiling...',
+    'Compiled': 'compiled successfully',
+    'Failed': 'failed to compile!',
+  };
+
+  return messages[statusName];
+}
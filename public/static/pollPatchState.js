@@ -26,7 +26,7 @@ async function main() {
     if (FINISHED_STATUSES.includes(statusName)) {
 
       if (statusName === 'Compiled') {
-        handleCompiled();
+        handleCompiled(patch);
       } else if (statusName === 'Failed') {
         handleFailed(patch.status);
       }
@@ -48,14 +48,39 @@ function updateStatusMessage(statusName) {
   document.getElementById('status').innerHTML = getStatusMessage(statusName);
 }
 
-function handleCompiled() {
+function handleCompiled(patch) {
   document.getElementById('download').classList.remove('download-disabled');
+
+  if (patch.memory_layout !== 'flash') {
+    showBootloaderNotice(patch);
+  }
+}
+
+function showBootloaderNotice(patch) {
+  const layoutName = getMemoryLayoutName(patch.memory_layout);
+
+  let summaryText = `This program was built for the ${layoutName} memory layout.`;
+  if (patch.memory_layout !== patch.memory_layout_requested) {
+    const requestedName = getMemoryLayoutName(patch.memory_layout_requested);
+    summaryText = `This program did not fit in ${requestedName}, so it was rebuilt for the ${layoutName} memory layout.`;
+  }
+
+  document.getElementById('bootloader-summary').innerHTML = summaryText;
+  document.getElementById('bootloader-notice').classList.remove('hidden');
 }
 
 function handleFailed(status) {
   const summaryText = status['Failed'].summary;
   document.getElementById('error-summary').innerHTML = `Reason: ${summaryText}`;
 
+  const explanation = status['Failed'].explanation;
+  if (!!explanation) {
+    document.getElementById('error-explanation-title').textContent = explanation.title;
+    document.getElementById('error-explanation-text').textContent = explanation.explanation;
+    document.getElementById('error-explanation-fix').textContent = explanation.suggested_fix;
+    document.getElementById('error-explanation').classList.remove('hidden');
+  }
+
   if (!!status['Failed'].details) {
     document.getElementById('error-details').innerHTML = status['Failed'].details;
     document.getElementById('error-details').classList.remove('hidden');
@@ -86,6 +111,16 @@ function getStatusName(status) {
   return statusName;
 }
 
+function getMemoryLayoutName(memoryLayout) {
+  const names = {
+    'flash': 'internal flash',
+    'boot_sram': 'BOOT_SRAM',
+    'boot_qspi': 'BOOT_QSPI',
+  };
+
+  return names[memoryLayout];
+}
+
 function getStatusMessage(statusName) {
   const messages = {
     'Uploaded': 'waiting to compile...',
@@ -0,0 +1,378 @@
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::collections::HashSet;
+
+use crate::patch_edits::PatchEdits;
+use crate::pd_parser::{Canvas, Element, ElementKind, ElementRef, PdPatch, Record};
+
+/// Vanilla names that Heavy only knows by another name
+pub const OBJECT_ALIASES: &[(&str, &str)] = &[
+    ("delread4~", "vd~"),
+    ("fswap", "swap"),
+    ("hdl", "hradio"),
+    ("vdl", "vradio"),
+];
+
+/// Objects whose outlets only send bangs, so a fan-out can be ordered with `[t b b]`
+const BANG_OBJECTS: &[&str] = &[
+    "b", "bang", "bng", "loadbang", "metro", "del", "delay", "until",
+];
+
+/// Objects whose outlets only send floats, so a fan-out can be ordered with `[t f f]`
+const FLOAT_OBJECTS: &[&str] = &[
+    "f",
+    "float",
+    "i",
+    "int",
+    "nbx",
+    "hsl",
+    "vsl",
+    "tgl",
+    "env~",
+    "snapshot~",
+];
+
+/// How far below its source an inserted `[trigger]` is placed
+const TRIGGER_OFFSET_Y: i32 = 25;
+
+/// What the auto-fix changed in an uploaded patch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AutofixReport {
+    pub changes: Vec<String>,
+    /// Unified diff from the uploaded patch to the rewritten one
+    pub diff: String,
+}
+
+/// Rewrite the constructs Heavy can't handle, returning `None` when there is nothing to fix.
+/// Fan-outs from `known_abstractions` are left alone, since their outlets could be signals.
+pub fn autofix_patch(
+    contents: &str,
+    patch: &PdPatch,
+    known_abstractions: &HashSet<String>,
+) -> Option<(String, AutofixReport)> {
+    let mut edits = PatchEdits::default();
+    let mut changes: Vec<String> = vec![];
+
+    fix_canvas(
+        &patch.root,
+        patch.records.len(),
+        &mut vec![],
+        &patch.records,
+        known_abstractions,
+        &mut edits,
+        &mut changes,
+    );
+
+    if changes.is_empty() {
+        return None;
+    }
+
+    let fixed_contents = edits.apply(contents, &patch.records);
+    let diff = TextDiff::from_lines(contents, &fixed_contents)
+        .unified_diff()
+        .context_radius(2)
+        .header("original.pd", "fixed.pd")
+        .to_string();
+
+    Some((fixed_contents, AutofixReport { changes, diff }))
+}
+
+/// Fix one canvas, whose new records go before `end_record`, then the subpatches inside it
+fn fix_canvas(
+    canvas: &Canvas,
+    end_record: usize,
+    canvas_path: &mut Vec<String>,
+    records: &[Record],
+    known_abstractions: &HashSet<String>,
+    edits: &mut PatchEdits,
+    changes: &mut Vec<String>,
+) {
+    replace_aliases(canvas, canvas_path, records, edits, changes);
+    order_fan_outs(
+        canvas,
+        end_record,
+        canvas_path,
+        known_abstractions,
+        edits,
+        changes,
+    );
+
+    for element in canvas.elements.iter() {
+        if let ElementKind::Subpatch { canvas: subpatch }
+        | ElementKind::Graph { canvas: subpatch } = &element.kind
+        {
+            canvas_path.push(subpatch.name.clone().unwrap_or_default());
+            // A subpatch ends with the `#X restore` record that places it in its parent
+            fix_canvas(
+                subpatch,
+                element.position.record,
+                canvas_path,
+                records,
+                known_abstractions,
+                edits,
+                changes,
+            );
+            canvas_path.pop();
+        }
+    }
+}
+
+fn location(element: &Element, canvas_path: &[String]) -> String {
+    ElementRef {
+        element,
+        canvas_path: canvas_path.to_vec(),
+    }
+    .location()
+}
+
+fn replace_aliases(
+    canvas: &Canvas,
+    canvas_path: &[String],
+    records: &[Record],
+    edits: &mut PatchEdits,
+    changes: &mut Vec<String>,
+) {
+    for element in canvas.elements.iter() {
+        let name = match &element.kind {
+            ElementKind::Object { name, .. } => name,
+            _ => continue,
+        };
+
+        if let Some((alias, replacement)) = OBJECT_ALIASES.iter().find(|(alias, _)| alias == name) {
+            // `#X obj x y name args...`
+            let mut tokens = records[element.position.record].tokens.clone();
+            tokens[4] = replacement.to_string();
+            edits.replaced.insert(element.position.record, tokens);
+
+            changes.push(format!(
+                "Replaced [{}] with [{}] at {}",
+                alias,
+                replacement,
+                location(element, canvas_path)
+            ));
+        }
+    }
+}
+
+/// The `[trigger]` type that passes on whatever an element sends
+fn trigger_type(element: &Element) -> &'static str {
+    match &element.kind {
+        ElementKind::Object { name, .. } if BANG_OBJECTS.contains(&name.as_str()) => "b",
+        ElementKind::Object { name, .. } if FLOAT_OBJECTS.contains(&name.as_str()) => "f",
+        ElementKind::AtomBox { kind, .. } if kind == "floatatom" => "f",
+        _ => "a",
+    }
+}
+
+fn describe(element: &Element) -> String {
+    match &element.kind {
+        ElementKind::Object { name, .. } => format!("[{name}]"),
+        ElementKind::Message { .. } => "message box".to_string(),
+        ElementKind::AtomBox { kind, .. } => kind.clone(),
+        _ => "box".to_string(),
+    }
+}
+
+/// Put a `[trigger]` between every control outlet and the inlets it fans out to.
+/// Pd fires fanned-out connections in the order they were made, so the first connection
+/// goes on the trigger's rightmost outlet to keep the patch behaving the same.
+fn order_fan_outs(
+    canvas: &Canvas,
+    end_record: usize,
+    canvas_path: &[String],
+    known_abstractions: &HashSet<String>,
+    edits: &mut PatchEdits,
+    changes: &mut Vec<String>,
+) {
+    let mut fan_outs: Vec<((usize, usize), Vec<usize>)> = vec![];
+    for (connection_index, connection) in canvas.connections.iter().enumerate() {
+        let key = (connection.source, connection.outlet);
+
+        match fan_outs
+            .iter_mut()
+            .find(|(fan_out_key, _)| *fan_out_key == key)
+        {
+            Some((_, connection_indices)) => connection_indices.push(connection_index),
+            None => fan_outs.push((key, vec![connection_index])),
+        }
+    }
+
+    let mut next_index = canvas.elements.len();
+
+    for ((source, outlet), connection_indices) in fan_outs {
+        let element = &canvas.elements[source];
+
+        if connection_indices.len() < 2 || !element.is_control_source(known_abstractions) {
+            continue;
+        }
+
+        let trigger_index = next_index;
+        next_index += 1;
+
+        let trigger_type = trigger_type(element);
+        let mut trigger_tokens: Vec<String> = vec![
+            "#X".to_string(),
+            "obj".to_string(),
+            element.x.to_string(),
+            (element.y + TRIGGER_OFFSET_Y).to_string(),
+            "t".to_string(),
+        ];
+        trigger_tokens.extend(connection_indices.iter().map(|_| trigger_type.to_string()));
+
+        let mut new_records = vec![
+            trigger_tokens,
+            connect_tokens(source, outlet, trigger_index, 0),
+        ];
+
+        for (order, connection_index) in connection_indices.iter().enumerate() {
+            let connection = &canvas.connections[*connection_index];
+            let trigger_outlet = connection_indices.len() - 1 - order;
+
+            edits.removed.insert(connection.position.record);
+            new_records.push(connect_tokens(
+                trigger_index,
+                trigger_outlet,
+                connection.sink,
+                connection.inlet,
+            ));
+        }
+
+        edits
+            .inserted
+            .entry(end_record)
+            .or_default()
+            .extend(new_records);
+
+        changes.push(format!(
+            "Inserted [t {}] after outlet {} of {} at {} so its {} connections fire in a fixed order",
+            vec![trigger_type; connection_indices.len()].join(" "),
+            outlet,
+            describe(element),
+            location(element, canvas_path),
+            connection_indices.len()
+        ));
+    }
+}
+
+fn connect_tokens(source: usize, outlet: usize, sink: usize, inlet: usize) -> Vec<String> {
+    vec![
+        "#X".to_string(),
+        "connect".to_string(),
+        source.to_string(),
+        outlet.to_string(),
+        sink.to_string(),
+        inlet.to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pd_parser::parse_patch;
+
+    fn autofix(contents: &str, known_abstractions: &[&str]) -> Option<String> {
+        let patch = parse_patch(contents).unwrap();
+        let known_abstractions = known_abstractions
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        autofix_patch(contents, &patch, &known_abstractions).map(|(fixed, _)| fixed)
+    }
+
+    #[test]
+    fn orders_fan_outs_in_the_order_pd_fired_them() {
+        let fixed = autofix(
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 loadbang;
+#X obj 10 60 print a;
+#X obj 80 60 print b;
+#X obj 150 60 print c;
+#X connect 0 0 2 0;
+#X connect 0 0 1 0;
+#X connect 0 0 3 0;
+",
+            &[],
+        )
+        .unwrap();
+
+        // The first connection Pd made fires first, so it goes on the rightmost outlet
+        assert_eq!(
+            fixed,
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 loadbang;
+#X obj 10 60 print a;
+#X obj 80 60 print b;
+#X obj 150 60 print c;
+#X obj 10 35 t b b b;
+#X connect 0 0 4 0;
+#X connect 4 2 2 0;
+#X connect 4 1 1 0;
+#X connect 4 0 3 0;
+"
+        );
+
+        assert_eq!(parse_patch(&fixed).unwrap().root.elements.len(), 5);
+    }
+
+    #[test]
+    fn orders_fan_outs_inside_subpatches() {
+        let fixed = autofix(
+            "#N canvas 0 50 450 300 12;
+#N canvas 0 50 450 300 sub 0;
+#X floatatom 10 10 5 0 0 0 - - -;
+#X obj 10 60 print a;
+#X obj 80 60 print b;
+#X connect 0 0 1 0;
+#X connect 0 0 2 0;
+#X restore 10 10 pd sub;
+",
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(
+            fixed,
+            "#N canvas 0 50 450 300 12;
+#N canvas 0 50 450 300 sub 0;
+#X floatatom 10 10 5 0 0 0 - - -;
+#X obj 10 60 print a;
+#X obj 80 60 print b;
+#X obj 10 35 t f f;
+#X connect 0 0 3 0;
+#X connect 3 1 1 0;
+#X connect 3 0 2 0;
+#X restore 10 10 pd sub;
+"
+        );
+    }
+
+    #[test]
+    fn leaves_signal_and_abstraction_fan_outs_alone() {
+        let contents = "#N canvas 0 50 450 300 12;
+#X obj 10 10 osc~ 440;
+#X obj 10 60 my-abstraction;
+#X obj 10 110 dac~;
+#X connect 0 0 2 0;
+#X connect 0 0 2 1;
+#X connect 1 0 2 0;
+#X connect 1 0 2 1;
+";
+
+        assert_eq!(autofix(contents, &["my-abstraction"]), None);
+    }
+
+    #[test]
+    fn replaces_aliases() {
+        let fixed = autofix(
+            "#N canvas 0 50 450 300 12;\n#X obj 10 10 delread4~ d 100;\n",
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(
+            fixed,
+            "#N canvas 0 50 450 300 12;\n#X obj 10 10 vd~ d 100;\n"
+        );
+    }
+}
@@ -1,10 +1,11 @@
-use serde::Serialize;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct ParseBoardError;
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Board {
     #[serde(rename = "seed")]
     SeedCustomJson,
@@ -41,6 +42,31 @@ impl FromStr for Board {
     }
 }
 
+impl Board {
+    pub fn all() -> Vec<Board> {
+        vec![
+            Board::SeedCustomJson,
+            Board::Pod,
+            Board::Patch,
+            Board::PatchInit,
+            Board::Field,
+            Board::Petal,
+        ]
+    }
+
+    /// Name shown to people in the UI
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            Board::SeedCustomJson => "Seed",
+            Board::Pod => "Pod",
+            Board::Patch => "Patch",
+            Board::PatchInit => "patch.Init()",
+            Board::Field => "Field",
+            Board::Petal => "Petal",
+        }
+    }
+}
+
 // TODO: there's got to be a more clever way to convert back and forth, maybe with `serde`
 impl Board {
     pub fn to_str(&self) -> String {
@@ -54,3 +80,182 @@ impl Board {
         }
     }
 }
+
+/// Hardware component types, named the way pd2dsy's board definitions name them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ControlKind {
+    AnalogControl,
+    AnalogControlBipolar,
+    Switch,
+    Switch3,
+    Encoder,
+    GateIn,
+    Led,
+    RgbLed,
+    GateOut,
+    CvOuts,
+    /// A component type gardener doesn't know about, assumed to be a single input
+    Other,
+}
+
+impl ControlKind {
+    fn from_component_name(component: &str) -> ControlKind {
+        match component {
+            "AnalogControl" => ControlKind::AnalogControl,
+            "AnalogControlBipolar" => ControlKind::AnalogControlBipolar,
+            "Switch" => ControlKind::Switch,
+            "Switch3" => ControlKind::Switch3,
+            "Encoder" => ControlKind::Encoder,
+            "GateIn" => ControlKind::GateIn,
+            "Led" => ControlKind::Led,
+            "RgbLed" => ControlKind::RgbLed,
+            "GateOut" => ControlKind::GateOut,
+            "CVOuts" => ControlKind::CvOuts,
+            _ => ControlKind::Other,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParameterDirection {
+    /// Read by the patch with `[r name @hv_param]`
+    Input,
+    /// Written by the patch with `[s name @hv_param]`
+    Output,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BoardControl {
+    pub name: String,
+    pub kind: ControlKind,
+}
+
+impl BoardControl {
+    fn new(name: &str, kind: ControlKind) -> Self {
+        BoardControl {
+            name: name.to_string(),
+            kind,
+        }
+    }
+
+    /// Every `@hv_param` name that pd2dsy connects to this control
+    pub fn parameter_names(&self) -> Vec<(String, ParameterDirection)> {
+        let input = |suffix: &str| {
+            (
+                format!("{}{}", self.name, suffix),
+                ParameterDirection::Input,
+            )
+        };
+        let output = |suffix: &str| {
+            (
+                format!("{}{}", self.name, suffix),
+                ParameterDirection::Output,
+            )
+        };
+
+        match self.kind {
+            ControlKind::Switch => {
+                vec![input(""), input("_rise"), input("_fall"), input("_seconds")]
+            }
+            ControlKind::Encoder => vec![
+                input(""),
+                input("_press"),
+                input("_rise"),
+                input("_fall"),
+                input("_seconds"),
+            ],
+            ControlKind::RgbLed => vec![output("_red"), output("_green"), output("_blue")],
+            ControlKind::Led | ControlKind::GateOut | ControlKind::CvOuts => vec![output("")],
+            ControlKind::AnalogControl
+            | ControlKind::AnalogControlBipolar
+            | ControlKind::Switch3
+            | ControlKind::GateIn
+            | ControlKind::Other => vec![input("")],
+        }
+    }
+}
+
+impl Board {
+    /// Controls of the built-in boards. A Seed's controls come from its board definition instead.
+    pub fn controls(&self) -> Vec<BoardControl> {
+        use ControlKind::*;
+
+        let numbered = |prefix: &str, count: usize, kind: ControlKind| -> Vec<BoardControl> {
+            (1..=count)
+                .map(|i| BoardControl::new(&format!("{prefix}{i}"), kind.clone()))
+                .collect()
+        };
+
+        match self {
+            Board::SeedCustomJson => vec![],
+            Board::Pod => [
+                numbered("knob", 2, AnalogControl),
+                numbered("sw", 2, Switch),
+                vec![BoardControl::new("encoder", Encoder)],
+                numbered("led", 2, RgbLed),
+            ]
+            .concat(),
+            Board::Patch => [
+                numbered("knob", 4, AnalogControl),
+                vec![BoardControl::new("encoder", Encoder)],
+                numbered("gate_in", 2, GateIn),
+                vec![BoardControl::new("gate_out", GateOut)],
+                numbered("cvout", 2, CvOuts),
+            ]
+            .concat(),
+            Board::PatchInit => [
+                numbered("knob", 4, AnalogControl),
+                numbered("cv", 4, AnalogControlBipolar),
+                numbered("sw", 2, Switch),
+                numbered("gate_in", 2, GateIn),
+                numbered("gate_out", 2, GateOut),
+                numbered("cvout", 1, CvOuts),
+                numbered("led", 1, Led),
+            ]
+            .concat(),
+            Board::Field => [
+                numbered("knob", 8, AnalogControl),
+                numbered("cv", 4, AnalogControlBipolar),
+                numbered("sw", 2, Switch),
+                vec![
+                    BoardControl::new("gate_in", GateIn),
+                    BoardControl::new("gate_out", GateOut),
+                ],
+                numbered("cvout", 2, CvOuts),
+            ]
+            .concat(),
+            Board::Petal => [
+                numbered("knob", 6, AnalogControl),
+                numbered("sw", 7, Switch),
+                vec![BoardControl::new("encoder", Encoder)],
+                numbered("led", 4, Led),
+            ]
+            .concat(),
+        }
+    }
+}
+
+/// Read the controls out of a custom board definition's `components`
+pub fn get_custom_board_controls(board_def_contents: &str) -> Result<Vec<BoardControl>> {
+    let board_def: serde_json::Value = serde_json::from_str(board_def_contents)
+        .map_err(|err| anyhow!("Board definition is not valid JSON: {err}"))?;
+
+    let components = match board_def.get("components").and_then(|c| c.as_object()) {
+        Some(components) => components,
+        None => return Ok(vec![]),
+    };
+
+    let controls = components
+        .iter()
+        .map(|(name, component)| {
+            let component_name = component
+                .get("component")
+                .and_then(|c| c.as_str())
+                .unwrap_or_default();
+
+            BoardControl::new(name, ControlKind::from_component_name(component_name))
+        })
+        .collect();
+
+    Ok(controls)
+}
@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+const SUPPORTED_SAMPLE_RATES: [u32; 5] = [8000, 16000, 32000, 48000, 96000];
+const MAX_BLOCK_SIZE: u32 = 256;
+
+/// pd2dsy runs the patch at 48 kHz unless told otherwise
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+const MAX_COPYRIGHT_LENGTH: usize = 200;
+
+/// Upload form fields that map onto `BuildOptions`
+pub const BUILD_OPTION_FORM_FIELDS: [&str; 4] =
+    ["sample_rate", "block_size", "copyright", "verbose"];
+
+/// Options forwarded to pd2dsy when generating the C++ code.
+/// Anything left as `None` falls back to pd2dsy's own default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct BuildOptions {
+    pub sample_rate: Option<u32>,
+    pub block_size: Option<u32>,
+    pub copyright: Option<String>,
+    pub verbose: bool,
+}
+
+impl BuildOptions {
+    /// Set a single option from one of the `BUILD_OPTION_FORM_FIELDS`
+    pub fn set_form_field(&mut self, name: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+
+        match name {
+            "sample_rate" => {
+                self.sample_rate = parse_optional_number(value)
+                    .map_err(|_| anyhow!("Invalid sample rate: {value}"))?;
+            }
+            "block_size" => {
+                self.block_size = parse_optional_number(value)
+                    .map_err(|_| anyhow!("Invalid block size: {value}"))?;
+            }
+            "copyright" => {
+                self.copyright = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_string())
+                };
+            }
+            "verbose" => {
+                self.verbose = matches!(value, "on" | "true");
+            }
+            _ => return Err(anyhow!("Unknown build option: {name}")),
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(sample_rate) = self.sample_rate {
+            if !SUPPORTED_SAMPLE_RATES.contains(&sample_rate) {
+                return Err(anyhow!(
+                    "Unsupported sample rate {sample_rate}, expected one of {:?}",
+                    SUPPORTED_SAMPLE_RATES
+                ));
+            }
+        }
+
+        if let Some(block_size) = self.block_size {
+            if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+                return Err(anyhow!(
+                    "Unsupported block size {block_size}, expected 1 to {MAX_BLOCK_SIZE}"
+                ));
+            }
+        }
+
+        if let Some(copyright) = &self.copyright {
+            if copyright.chars().count() > MAX_COPYRIGHT_LENGTH {
+                return Err(anyhow!(
+                    "Copyright notice is longer than {MAX_COPYRIGHT_LENGTH} characters"
+                ));
+            }
+
+            // The notice ends up in a comment block of the generated code
+            if copyright.chars().any(|c| c.is_control()) || copyright.contains("*/") {
+                return Err(anyhow!("Copyright notice contains unsupported characters"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The sample rate the patch will run at
+    pub fn effective_sample_rate(&self) -> u32 {
+        self.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE)
+    }
+
+    pub fn to_pd2dsy_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec![];
+
+        if let Some(sample_rate) = self.sample_rate {
+            args.push("--samplerate".to_string());
+            args.push(sample_rate.to_string());
+        }
+
+        if let Some(block_size) = self.block_size {
+            args.push("--blocksize".to_string());
+            args.push(block_size.to_string());
+        }
+
+        if let Some(copyright) = &self.copyright {
+            args.push("--copyright".to_string());
+            args.push(copyright.clone());
+        }
+
+        if self.verbose {
+            args.push("--verbose".to_string());
+        }
+
+        args
+    }
+}
+
+fn parse_optional_number(value: &str) -> Result<Option<u32>, std::num::ParseIntError> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        value.parse::<u32>().map(Some)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseBuildProfileError;
+
+/// Compiler settings passed through to libDaisy's Makefile
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum BuildProfile {
+    #[serde(rename = "release")]
+    Release,
+
+    #[serde(rename = "size")]
+    Size,
+
+    #[serde(rename = "debug")]
+    Debug,
+}
+
+impl FromStr for BuildProfile {
+    type Err = ParseBuildProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "release" => Ok(BuildProfile::Release),
+            "size" => Ok(BuildProfile::Size),
+            "debug" => Ok(BuildProfile::Debug),
+            _ => Err(ParseBuildProfileError),
+        }
+    }
+}
+
+impl BuildProfile {
+    pub fn to_str(&self) -> String {
+        match self {
+            BuildProfile::Release => "release".to_string(),
+            BuildProfile::Size => "size".to_string(),
+            BuildProfile::Debug => "debug".to_string(),
+        }
+    }
+
+    /// Variable overrides for `make`, release builds use the Makefile defaults
+    pub fn to_make_args(&self) -> Vec<&'static str> {
+        match self {
+            BuildProfile::Release => vec![],
+            BuildProfile::Size => vec!["OPT=-Os"],
+            BuildProfile::Debug => vec!["DEBUG=1", "OPT=-O0"],
+        }
+    }
+
+    pub fn has_debug_symbols(&self) -> bool {
+        matches!(self, BuildProfile::Debug)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_form(fields: &[(&str, &str)]) -> Result<BuildOptions> {
+        let mut build_options = BuildOptions::default();
+        for (name, value) in fields {
+            build_options.set_form_field(name, value)?;
+        }
+
+        Ok(build_options)
+    }
+
+    #[test]
+    fn forwards_only_the_options_that_were_set() {
+        assert!(BuildOptions::default().to_pd2dsy_args().is_empty());
+
+        let build_options = from_form(&[
+            ("sample_rate", "32000"),
+            ("block_size", " 64 "),
+            ("copyright", "Jane Doe"),
+            ("verbose", "on"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            build_options.to_pd2dsy_args(),
+            vec![
+                "--samplerate",
+                "32000",
+                "--blocksize",
+                "64",
+                "--copyright",
+                "Jane Doe",
+                "--verbose"
+            ]
+        );
+        assert_eq!(build_options.effective_sample_rate(), 32000);
+        assert_eq!(BuildOptions::default().effective_sample_rate(), 48000);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        assert!(from_form(&[("sample_rate", "fast")]).is_err());
+        assert!(from_form(&[("unknown", "1")]).is_err());
+
+        for fields in [
+            [("sample_rate", "44100")],
+            [("block_size", "0")],
+            [("block_size", "512")],
+            [("copyright", "ends the comment */")],
+            [("copyright", "line\nbreak")],
+        ] {
+            assert!(
+                from_form(&fields).unwrap().validate().is_err(),
+                "{fields:?}"
+            );
+        }
+
+        let too_long = "x".repeat(MAX_COPYRIGHT_LENGTH + 1);
+        assert!(from_form(&[("copyright", &too_long)])
+            .unwrap()
+            .validate()
+            .is_err());
+    }
+}
@@ -1,24 +1,38 @@
 use lazy_static::lazy_static;
-use log::{debug, error, info, trace, warn};
+use log::{debug, info, trace, warn};
 use regex::Regex;
 use std::collections::{HashMap, VecDeque};
-use std::path::PathBuf;
-use std::process::Stdio;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::result::Result;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
 use std::time::Duration;
 use thiserror::Error;
-use tokio::process::Command;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
 
 use crate::boards::Board;
+use crate::compiler_cache::{
+    get_ccache_env, get_ccache_make_args, read_ccache_stats_log, CompilerCacheStats,
+};
 use crate::env_config::{get_env_config, EnvConfig};
-use crate::patches::{DateTime, PatchMeta, PatchStatus, PatchesStore};
+use crate::failure_explainer::explain_failure;
+use crate::memory_layout::MemoryLayout;
+use crate::patches::{requeue_patch, DateTime, PatchMeta, PatchStatus, PatchesStore};
+use crate::resource_usage::{get_dir_size, run_command, ResourceUsage};
+use crate::shutdown::{restore_queue, run_until_drain_deadline};
+use crate::toolchains::{get_toolchain, Toolchain};
+use crate::worker_supervisor::{supervise_worker, WorkerHealth};
 
 lazy_static! {
     static ref REGEX_ESCAPE_SEQUENCE: Regex = Regex::new(r#"\x1b\[([0-9]+;)?[0-9]+m"#).unwrap();
+    static ref REGEX_REGION_OVERFLOW: Regex =
+        Regex::new(r#"region `(\w+)' overflowed by"#).unwrap();
+
+    /// Held for every build, so the self-test never competes with the worker for CPU and the toolchain
+    static ref BUILD_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
 }
 
 #[derive(Error, Debug)]
@@ -27,46 +41,85 @@ pub enum CompilationError {
     Pd2dsyFailed { stdout: String },
 
     #[error("make command failed")]
-    MakeFailed,
+    MakeFailed { output: String },
+
+    #[error("program does not fit in the {region} memory region")]
+    MemoryRegionOverflowed { region: String, output: String },
 
     #[error("move command failed")]
     MoveFailed,
 
+    #[error("zip command failed")]
+    ZipFailed,
+
     #[error("rm command failed")]
     RemoveFailed,
 
+    #[error("unknown toolchain {name}")]
+    UnknownToolchain { name: String },
+
     #[error("I/O error occurred")]
     UnknownIOError(#[from] std::io::Error),
 }
 
-pub fn init_compilation_worker() -> (Arc<PatchesStore>, JoinHandle<()>, CancellationToken) {
+pub struct CompilationOutcome {
+    pub memory_layout: MemoryLayout,
+    pub compiler_cache: Option<CompilerCacheStats>,
+}
+
+pub fn init_compilation_worker() -> (
+    Arc<PatchesStore>,
+    Arc<WorkerHealth>,
+    JoinHandle<()>,
+    CancellationToken,
+) {
     let patches_store = PatchesStore {
         patches: Mutex::new(HashMap::new()),
         compilation_queue: Mutex::new(VecDeque::new()),
+        leases: Mutex::new(HashMap::new()),
+        groups: Mutex::new(HashMap::new()),
     };
 
+    restore_queue(&patches_store, &get_env_config());
+
     let patches_store_container = Arc::new(patches_store);
+    let worker_health = Arc::new(WorkerHealth::new());
 
     let worker_cancel = CancellationToken::new();
 
     (
         Arc::clone(&patches_store_container),
-        tokio::spawn(spawn_worker(
+        Arc::clone(&worker_health),
+        tokio::spawn(supervise_worker(
             Arc::clone(&patches_store_container),
+            worker_health,
             worker_cancel.clone(),
         )),
         worker_cancel,
     )
 }
 
-async fn spawn_worker(patches_store: Arc<PatchesStore>, stop_signal: CancellationToken) {
+pub async fn spawn_worker(
+    patches_store: Arc<PatchesStore>,
+    worker_health: Arc<WorkerHealth>,
+    stop_signal: CancellationToken,
+) {
+    if !get_env_config().local_compilation_worker {
+        info!("Local compilation worker is disabled, waiting for remote workers");
+
+        stop_signal.cancelled().await;
+        return;
+    }
+
     loop {
+        worker_health.polled();
+
         let patch_to_compile: Option<PatchMeta> = 'queue_result: {
             // only _try_ to lock so reads and writes from route handlers do not get blocked
-            let queue_lock = patches_store.compilation_queue.try_lock();
-            let patches_lock = patches_store.patches.try_lock();
+            let queue_lock = try_lock_unpoisoned(&patches_store.compilation_queue);
+            let patches_lock = try_lock_unpoisoned(&patches_store.patches);
 
-            if let (Ok(mut queue), Ok(patches)) = (queue_lock, patches_lock) {
+            if let (Some(mut queue), Some(patches)) = (queue_lock, patches_lock) {
                 trace!("Found {} items in the queue", queue.len());
 
                 if let Some(patch_id) = queue.pop_front() {
@@ -80,7 +133,22 @@ async fn spawn_worker(patches_store: Arc<PatchesStore>, stop_signal: Cancellatio
         };
 
         if let Some(patch) = patch_to_compile {
-            process_patch(patch, Arc::clone(&patches_store)).await;
+            worker_health.set_current_patch(Some(patch.id.clone()));
+
+            let processing = process_patch(patch.clone(), Arc::clone(&patches_store));
+            let drain_deadline = Duration::from_secs(get_env_config().shutdown_drain_secs);
+            let finished = run_until_drain_deadline(processing, &stop_signal, drain_deadline).await;
+
+            worker_health.set_current_patch(None);
+
+            if finished.is_none() {
+                info!("Re-queueing aborted patch {}", patch.id);
+
+                remove_aborted_build(&patch).await;
+                requeue_patch(&patches_store, &patch.id);
+
+                break;
+            }
         }
 
         tokio::select! {
@@ -96,121 +164,252 @@ async fn spawn_worker(patches_store: Arc<PatchesStore>, stop_signal: Cancellatio
     }
 }
 
+/// Like `try_lock`, but takes over a mutex poisoned by a crashed worker instead of failing forever
+fn try_lock_unpoisoned<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
 async fn process_patch(patch: PatchMeta, patches_store: Arc<PatchesStore>) {
     let patch_id = patch.id.clone();
 
-    info!("Compiling patch {}...", patch_id);
+    let compiling_patch = get_compiling_patch(patch);
+    update_patches_store_item(&patch_id, &compiling_patch, Arc::clone(&patches_store));
 
-    let compiling_patch = PatchMeta {
+    let mut resource_usage = ResourceUsage::default();
+    let compilation_result = compile_patch(&compiling_patch, &mut resource_usage).await;
+
+    let finished_patch = get_finished_patch(compiling_patch, compilation_result, resource_usage);
+    update_patches_store_item(&patch_id, &finished_patch, Arc::clone(&patches_store));
+}
+
+pub fn get_compiling_patch(patch: PatchMeta) -> PatchMeta {
+    info!("Compiling patch {}...", patch.id);
+
+    PatchMeta {
         status: PatchStatus::Compiling,
         time_compile_start: Some(DateTime::now()),
-        ..patch.clone()
-    };
-    update_patches_store_item(&patch_id, &compiling_patch, Arc::clone(&patches_store));
+        ..patch
+    }
+}
 
-    let compilation_result = compile_patch(&patch_id, &patch.board).await;
+pub fn get_finished_patch(
+    compiling_patch: PatchMeta,
+    compilation_result: Result<CompilationOutcome, CompilationError>,
+    resource_usage: ResourceUsage,
+) -> PatchMeta {
+    let patch_id = compiling_patch.id.clone();
+
+    let compiling_patch = PatchMeta {
+        resource_usage: Some(resource_usage),
+        ..compiling_patch
+    };
 
     match compilation_result {
-        Ok(()) => {
+        Ok(outcome) => {
             info!("Finished compiling patch {}", patch_id);
 
-            let compiled_patch = PatchMeta {
+            PatchMeta {
                 status: PatchStatus::Compiled,
+                memory_layout: outcome.memory_layout,
+                compiler_cache: outcome.compiler_cache,
                 time_compile_end: Some(DateTime::now()),
                 ..compiling_patch
-            };
-            update_patches_store_item(&patch_id, &compiled_patch, Arc::clone(&patches_store));
+            }
         }
         Err(err) => {
             warn!("Failed to compile patch {}", patch_id);
 
-            let failed_status = match &err {
-                CompilationError::Pd2dsyFailed { stdout } => PatchStatus::Failed {
-                    summary: err.to_string(),
-                    details: Some(remove_escape_sequences(stdout)),
-                },
-                _ => PatchStatus::Failed {
-                    summary: err.to_string(),
-                    details: None,
-                },
+            let details = match &err {
+                CompilationError::Pd2dsyFailed { stdout } => Some(remove_escape_sequences(stdout)),
+                CompilationError::MakeFailed { output }
+                | CompilationError::MemoryRegionOverflowed { output, .. } => {
+                    Some(remove_escape_sequences(output))
+                }
+                _ => None,
+            };
+
+            let explanation = details.as_deref().and_then(explain_failure);
+
+            let failed_status = PatchStatus::Failed {
+                summary: err.to_string(),
+                details,
+                explanation,
             };
 
-            let failed_patch = PatchMeta {
+            PatchMeta {
                 status: failed_status,
                 ..compiling_patch
-            };
-            update_patches_store_item(&patch_id, &failed_patch, Arc::clone(&patches_store));
+            }
         }
-    };
+    }
 }
 
 fn update_patches_store_item(patch_id: &str, patch: &PatchMeta, patches_store: Arc<PatchesStore>) {
-    if let Ok(mut patches) = patches_store.patches.try_lock() {
-        patches.insert(patch_id.to_string(), patch.clone());
+    // wait for route handlers to release the lock, the update must not get lost
+    let mut patches = patches_store
+        .patches
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    patches.insert(patch_id.to_string(), patch.clone());
+}
+
+/// Build a patch, adding the resources used by every step to `resource_usage`
+pub async fn compile_patch(
+    patch: &PatchMeta,
+    resource_usage: &mut ResourceUsage,
+) -> Result<CompilationOutcome, CompilationError> {
+    let env_config = get_env_config();
+    let patch_id = patch.id.as_str();
+
+    let toolchain =
+        get_toolchain(&patch.toolchain).ok_or_else(|| CompilationError::UnknownToolchain {
+            name: patch.toolchain.clone(),
+        })?;
+
+    let _build_lock = BUILD_LOCK.lock().await;
+
+    let build_result = build_with_fallback(patch, toolchain, resource_usage, &env_config).await;
+
+    // The log covers every attempt, including the ones that overflowed or failed
+    let compiler_cache = if env_config.ccache_enabled {
+        read_ccache_stats_log(&get_ccache_stats_log(patch_id, toolchain)).await
     } else {
-        error!("TODO: could not update PatchesStore, handle gracefully");
+        None
+    };
+
+    let packaging_result = match build_result {
+        Ok(memory_layout) => package_build(patch, toolchain, resource_usage, &env_config)
+            .await
+            .map(|()| memory_layout),
+        Err(err) => Err(err),
+    };
 
-        panic!();
+    // Nothing looks at the build directory of a failed build again
+    if packaging_result.is_err() {
+        remove_aborted_build(patch).await;
     }
+
+    Ok(CompilationOutcome {
+        memory_layout: packaging_result?,
+        compiler_cache,
+    })
 }
 
-async fn compile_patch(patch_id: &str, board: &Board) -> Result<(), CompilationError> {
-    let env_config = get_env_config();
+/// Move the build's artifacts into the workspace, then remove its build directory
+async fn package_build(
+    patch: &PatchMeta,
+    toolchain: &Toolchain,
+    resource_usage: &mut ResourceUsage,
+    env_config: &EnvConfig,
+) -> Result<(), CompilationError> {
+    move_binary_into_workspace(patch, toolchain, resource_usage, env_config).await?;
 
-    generate_cpp_code(patch_id, board, &env_config).await?;
+    package_source_archive(patch, toolchain, resource_usage, env_config).await?;
 
-    compile_binary(patch_id, &env_config).await?;
+    resource_usage.build_dir_bytes = get_dir_size(&get_dir_patch_build(&patch.id, toolchain));
 
-    move_binary_into_workspace(patch_id, &env_config).await?;
+    remove_build_dir(&patch.id, toolchain, resource_usage, env_config).await
+}
 
-    remove_build_dir(patch_id, &env_config).await?;
+/// Generate and compile the patch, moving to the next memory layout whenever it doesn't fit,
+/// and return the layout it was built with
+async fn build_with_fallback(
+    patch: &PatchMeta,
+    toolchain: &Toolchain,
+    resource_usage: &mut ResourceUsage,
+    env_config: &EnvConfig,
+) -> Result<MemoryLayout, CompilationError> {
+    let patch_id = patch.id.as_str();
+    let mut memory_layout = patch.memory_layout_requested.clone();
 
-    Ok(())
+    loop {
+        generate_cpp_code(patch, &memory_layout, toolchain, resource_usage, env_config).await?;
+
+        match compile_binary(patch, toolchain, resource_usage, env_config).await {
+            Ok(()) => return Ok(memory_layout),
+            Err(CompilationError::MemoryRegionOverflowed { region, output }) => {
+                let Some(fallback_layout) = memory_layout.fallback() else {
+                    return Err(CompilationError::MemoryRegionOverflowed { region, output });
+                };
+
+                info!(
+                    "Patch {} overflowed {} with layout {}, retrying with {}",
+                    patch_id,
+                    region,
+                    memory_layout.to_str(),
+                    fallback_layout.to_str()
+                );
+
+                memory_layout = fallback_layout;
+            }
+            Err(err) => return Err(err),
+        }
+
+        remove_build_dir(patch_id, toolchain, resource_usage, env_config).await?;
+    }
 }
 
 async fn generate_cpp_code(
-    patch_id: &str,
-    board: &Board,
+    patch: &PatchMeta,
+    memory_layout: &MemoryLayout,
+    toolchain: &Toolchain,
+    resource_usage: &mut ResourceUsage,
     env_config: &EnvConfig,
 ) -> Result<(), CompilationError> {
-    debug!("Generating C++ code...");
+    debug!("Generating C++ code with toolchain {}...", toolchain.name);
 
-    let mut filename_pd2dsy_script = env_config.dir_pd2dsy.clone();
+    let mut filename_pd2dsy_script = toolchain.dir_pd2dsy.clone();
     filename_pd2dsy_script.push("pd2dsy.py");
 
     let mut filename_patch = env_config.dir_workspace.clone();
     filename_patch.push("uploads");
-    filename_patch.push(format!("{patch_id}.pd"));
+    filename_patch.push(patch.patch_upload_filename());
 
     let mut filename_board_def = env_config.dir_workspace.clone();
     filename_board_def.push("uploads");
-    filename_board_def.push(format!("{patch_id}_board_def.json"));
+    filename_board_def.push(patch.board_def_upload_filename());
 
     let mut command = Command::new("python3");
     command.arg(filename_pd2dsy_script.as_path());
 
-    if let Board::SeedCustomJson = board {
+    if let Board::SeedCustomJson = patch.board {
         command
             .arg("--custom-json")
             .arg(filename_board_def.as_path());
     }
 
+    for search_path in patch.search_paths.iter() {
+        let mut dir_search_path = env_config.dir_workspace.clone();
+        dir_search_path.push("uploads");
+        dir_search_path.push(patch.search_path_upload_dirname(search_path));
+
+        command.arg("-p").arg(dir_search_path.as_path());
+    }
+
     command
         .arg("--board")
-        .arg(board.to_str())
+        .arg(patch.board.to_str())
         .arg("--directory")
         .arg("builds")
         .arg("--libdaisy-depth")
         .arg("2")
+        .arg("--rom")
+        .arg(memory_layout.rom_option())
+        .arg("--ram")
+        .arg(memory_layout.ram_option())
+        .args(patch.build_options.to_pd2dsy_args())
         .arg("--no-build")
         .arg(filename_patch.as_path())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .current_dir(env_config.dir_pd2dsy.as_path());
-
-    let child = command.spawn()?;
+        .current_dir(toolchain.dir_pd2dsy.as_path());
 
-    let output = child.wait_with_output().await?;
+    let output = run_command(command, resource_usage).await?;
 
     let status_code = output.status;
     let stdout = String::from_utf8_lossy(&output.stdout);
@@ -220,65 +419,145 @@ async fn generate_cpp_code(
     }
 
     if !status_code.success() {
+        // Python tracebacks, like a board definition missing a field, only show up on stderr
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
         return Err(CompilationError::Pd2dsyFailed {
-            stdout: stdout.to_string(),
+            stdout: format!("{stdout}{stderr}"),
         });
     }
 
     Ok(())
 }
 
-async fn compile_binary(patch_id: &str, env_config: &EnvConfig) -> Result<(), CompilationError> {
+async fn compile_binary(
+    patch: &PatchMeta,
+    toolchain: &Toolchain,
+    resource_usage: &mut ResourceUsage,
+    env_config: &EnvConfig,
+) -> Result<(), CompilationError> {
     debug!("Compiling binary...");
 
-    let dir_patch_build = get_dir_patch_build(patch_id, env_config);
+    let dir_patch_build = get_dir_patch_build(&patch.id, toolchain);
 
     let mut command = Command::new("make");
-    command.current_dir(dir_patch_build);
 
-    if !env_config.display_compilation_output {
-        command.stdout(Stdio::null()).stderr(Stdio::null());
+    if let Some(dir_arm_gcc_bin) = &toolchain.dir_arm_gcc_bin {
+        let path = env::var("PATH").unwrap_or_default();
+        command.env("PATH", format!("{}:{}", dir_arm_gcc_bin.display(), path));
+    }
+
+    if env_config.ccache_enabled {
+        let mut dir_builds = toolchain.dir_pd2dsy.clone();
+        dir_builds.push("builds");
+
+        command.args(get_ccache_make_args()).envs(get_ccache_env(
+            &dir_builds,
+            &get_ccache_stats_log(&patch.id, toolchain),
+        ));
     }
 
-    let mut child = command.spawn()?;
+    command
+        .arg(format!("--jobs={}", env_config.make_jobs))
+        .args(patch.build_profile.to_make_args())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .current_dir(dir_patch_build);
 
-    let status_code = child.wait().await?;
+    let output = run_command(command, resource_usage).await?;
+
+    let status_code = output.status;
+    let make_output = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    if env_config.display_compilation_output {
+        debug!("Command output:\n{}", make_output);
+    }
 
     if !status_code.success() {
-        return Err(CompilationError::MakeFailed);
+        if let Some(captures) = REGEX_REGION_OVERFLOW.captures(&make_output) {
+            return Err(CompilationError::MemoryRegionOverflowed {
+                region: captures[1].to_string(),
+                output: make_output,
+            });
+        }
+
+        return Err(CompilationError::MakeFailed {
+            output: make_output,
+        });
     }
 
     Ok(())
 }
 
 async fn move_binary_into_workspace(
-    patch_id: &str,
+    patch: &PatchMeta,
+    toolchain: &Toolchain,
+    resource_usage: &mut ResourceUsage,
     env_config: &EnvConfig,
 ) -> Result<(), CompilationError> {
     debug!("Moving binary into workspace...");
 
-    let dir_patch_build = get_dir_patch_build(patch_id, env_config);
+    let dir_patch_build = get_dir_patch_build(&patch.id, toolchain);
 
-    let mut filename_compiled_binary = dir_patch_build.to_path_buf();
-    filename_compiled_binary.push("build");
-    filename_compiled_binary.push(format!("HeavyDaisy_{}.bin", patch_id.replace('-', "_")));
+    move_build_artifact_into_workspace(
+        &patch.id,
+        &dir_patch_build,
+        "bin",
+        &patch.binary_filename(),
+        resource_usage,
+        env_config,
+    )
+    .await?;
+
+    if patch.build_profile.has_debug_symbols() {
+        move_build_artifact_into_workspace(
+            &patch.id,
+            &dir_patch_build,
+            "elf",
+            &patch.elf_filename(),
+            resource_usage,
+            env_config,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn move_build_artifact_into_workspace(
+    patch_id: &str,
+    dir_patch_build: &Path,
+    extension: &str,
+    filename_artifact: &str,
+    resource_usage: &mut ResourceUsage,
+    env_config: &EnvConfig,
+) -> Result<(), CompilationError> {
+    let mut filename_compiled_artifact = dir_patch_build.to_path_buf();
+    filename_compiled_artifact.push("build");
+    filename_compiled_artifact.push(format!(
+        "HeavyDaisy_{}.{}",
+        patch_id.replace('-', "_"),
+        extension
+    ));
 
     let mut filename_in_downloads = env_config.dir_workspace.clone();
     filename_in_downloads.push("downloads");
-    filename_in_downloads.push(format!("daisy-{patch_id}.bin"));
+    filename_in_downloads.push(filename_artifact);
 
     let mut command = Command::new("mv");
     command
-        .arg(filename_compiled_binary.as_path())
+        .arg(filename_compiled_artifact.as_path())
         .arg(filename_in_downloads.as_path());
 
     if !env_config.display_compilation_output {
         command.stdout(Stdio::null()).stderr(Stdio::null());
     }
 
-    let mut child = command.spawn()?;
-
-    let status_code = child.wait().await?;
+    let status_code = run_command(command, resource_usage).await?.status;
 
     if !status_code.success() {
         return Err(CompilationError::MoveFailed);
@@ -287,10 +566,53 @@ async fn move_binary_into_workspace(
     Ok(())
 }
 
-async fn remove_build_dir(patch_id: &str, env_config: &EnvConfig) -> Result<(), CompilationError> {
+async fn package_source_archive(
+    patch: &PatchMeta,
+    toolchain: &Toolchain,
+    resource_usage: &mut ResourceUsage,
+    env_config: &EnvConfig,
+) -> Result<(), CompilationError> {
+    debug!("Packaging generated source code...");
+
+    let dir_patch_build = get_dir_patch_build(&patch.id, toolchain);
+
+    let mut filename_in_downloads = env_config.dir_workspace.clone();
+    filename_in_downloads.push("downloads");
+    filename_in_downloads.push(patch.source_archive_filename());
+
+    // Leave out the object files from `make`, only the generated source is useful
+    let mut command = Command::new("zip");
+    command
+        .arg("-r")
+        .arg("-q")
+        .arg(filename_in_downloads.as_path())
+        .arg(".")
+        .arg("-x")
+        .arg("build/*")
+        .current_dir(dir_patch_build);
+
+    if !env_config.display_compilation_output {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+
+    let status_code = run_command(command, resource_usage).await?.status;
+
+    if !status_code.success() {
+        return Err(CompilationError::ZipFailed);
+    }
+
+    Ok(())
+}
+
+async fn remove_build_dir(
+    patch_id: &str,
+    toolchain: &Toolchain,
+    resource_usage: &mut ResourceUsage,
+    env_config: &EnvConfig,
+) -> Result<(), CompilationError> {
     debug!("Cleaning up...");
 
-    let dir_patch_build = get_dir_patch_build(patch_id, env_config);
+    let dir_patch_build = get_dir_patch_build(patch_id, toolchain);
 
     let mut command = Command::new("rm");
     command.arg("-rf").arg(dir_patch_build.as_path());
@@ -299,9 +621,7 @@ async fn remove_build_dir(patch_id: &str, env_config: &EnvConfig) -> Result<(),
         command.stdout(Stdio::null()).stderr(Stdio::null());
     }
 
-    let mut child = command.spawn()?;
-
-    let status_code = child.wait().await?;
+    let status_code = run_command(command, resource_usage).await?.status;
 
     if !status_code.success() {
         return Err(CompilationError::RemoveFailed);
@@ -310,16 +630,74 @@ async fn remove_build_dir(patch_id: &str, env_config: &EnvConfig) -> Result<(),
     Ok(())
 }
 
-fn get_dir_patch_build(patch_id: &str, env_config: &EnvConfig) -> PathBuf {
-    let mut dir_patch_build = env_config.dir_pd2dsy.clone();
+/// Remove whatever a build left behind when it failed or was killed part of the way through
+pub async fn remove_aborted_build(patch: &PatchMeta) {
+    if let Some(toolchain) = get_toolchain(&patch.toolchain) {
+        let dir_patch_build = get_dir_patch_build(&patch.id, toolchain);
+
+        if let Err(err) = tokio::fs::remove_dir_all(&dir_patch_build).await {
+            debug!("Could not remove {}: {}", dir_patch_build.display(), err);
+        }
+
+        // Only there when ccache is enabled
+        let _ = tokio::fs::remove_file(get_ccache_stats_log(&patch.id, toolchain)).await;
+    }
+}
+
+fn get_dir_patch_build(patch_id: &str, toolchain: &Toolchain) -> PathBuf {
+    let mut dir_patch_build = toolchain.dir_pd2dsy.clone();
     dir_patch_build.push("builds");
     dir_patch_build.push(patch_id);
 
     dir_patch_build
 }
 
+/// Kept next to the build directory, which is removed between attempts
+fn get_ccache_stats_log(patch_id: &str, toolchain: &Toolchain) -> PathBuf {
+    let mut stats_log = toolchain.dir_pd2dsy.clone();
+    stats_log.push("builds");
+    stats_log.push(format!("{patch_id}.ccache-stats.log"));
+
+    stats_log
+}
+
 fn remove_escape_sequences(terminal_output: &str) -> String {
     REGEX_ESCAPE_SEQUENCE
         .replace_all(terminal_output, "")
         .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env_config::tests::init_test_env;
+    use crate::patches::get_workspace_path;
+    use crate::patches::tests::uploaded_patch;
+    use crate::toolchains::DEFAULT_TOOLCHAIN_NAME;
+
+    /// Generates a build directory without a makefile, so `make` fails
+    const FAKE_PD2DSY: &str = r#"import os, sys
+name = os.path.splitext(os.path.basename(sys.argv[-1]))[0]
+os.makedirs(os.path.join("builds", name), exist_ok=True)
+open(os.path.join("builds", name, name + ".cpp"), "w").write("// generated")
+"#;
+
+    #[tokio::test]
+    async fn failed_builds_leave_no_build_dir_behind() {
+        let env_config = init_test_env();
+        let toolchain = get_toolchain(DEFAULT_TOOLCHAIN_NAME).unwrap();
+        std::fs::write(toolchain.dir_pd2dsy.join("pd2dsy.py"), FAKE_PD2DSY).unwrap();
+
+        let patch = uploaded_patch("failing-build");
+        std::fs::write(
+            get_workspace_path(&env_config, "uploads", &patch.patch_upload_filename()),
+            "#N canvas 0 50 450 300 12;\n",
+        )
+        .unwrap();
+
+        let result = compile_patch(&patch, &mut ResourceUsage::default()).await;
+
+        assert!(matches!(result, Err(CompilationError::MakeFailed { .. })));
+        assert!(!get_dir_patch_build(&patch.id, toolchain).exists());
+    }
+}
@@ -0,0 +1,100 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::Path;
+use tokio::fs;
+
+/// ccache hits and misses while building one patch, read from the build's own stats log
+/// so that other builds running at the same time don't count towards it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompilerCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+}
+
+/// `make` variable overrides that route the ARM compilers through ccache
+pub fn get_ccache_make_args() -> Vec<&'static str> {
+    vec![
+        "CC=ccache arm-none-eabi-gcc",
+        "CXX=ccache arm-none-eabi-g++",
+    ]
+}
+
+/// Environment for `make` so builds share cache entries, even though each one runs in its own
+/// `builds/<patch id>` directory. Paths under `dir_builds` are hashed relative to the build,
+/// and the working directory that `-g` puts in the debug info is left out of the hash.
+pub fn get_ccache_env(dir_builds: &Path, stats_log: &Path) -> Vec<(&'static str, OsString)> {
+    vec![
+        ("CCACHE_BASEDIR", dir_builds.as_os_str().to_os_string()),
+        ("CCACHE_NOHASHDIR", OsString::from("1")),
+        ("CCACHE_STATSLOG", stats_log.as_os_str().to_os_string()),
+    ]
+}
+
+/// Count the results in a build's stats log, then remove it
+pub async fn read_ccache_stats_log(stats_log: &Path) -> Option<CompilerCacheStats> {
+    let contents = match fs::read_to_string(stats_log).await {
+        Ok(contents) => contents,
+        // Nothing was compiled, like when pd2dsy failed
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!("Could not read the ccache stats log: {err}");
+            return None;
+        }
+    };
+
+    if let Err(err) = fs::remove_file(stats_log).await {
+        warn!("Could not remove the ccache stats log: {err}");
+    }
+
+    Some(count_ccache_results(&contents))
+}
+
+/// Each compilation is logged as a `# <source file>` line followed by the counters it touched
+fn count_ccache_results(stats_log: &str) -> CompilerCacheStats {
+    let count = |names: &[&str]| {
+        stats_log
+            .lines()
+            .filter(|line| names.contains(&line.trim()))
+            .count() as u64
+    };
+
+    let hits = count(&["direct_cache_hit", "preprocessed_cache_hit"]);
+    let misses = count(&["cache_miss"]);
+
+    let hit_rate = match hits + misses {
+        0 => 0.0,
+        total => hits as f64 / total as f64,
+    };
+
+    CompilerCacheStats {
+        hits,
+        misses,
+        hit_rate,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_results_in_stats_log() {
+        let stats = count_ccache_results(
+            "# /builds/a/main.cpp\ndirect_cache_hit\n# /builds/a/Heavy_main.c\ncache_miss\n# /builds/a/HvTable.c\npreprocessed_cache_hit\n",
+        );
+
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert!((stats.hit_rate - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_stats_log_has_no_hit_rate() {
+        let stats = count_ccache_results("");
+
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+        assert_eq!(stats.hit_rate, 0.0);
+    }
+}
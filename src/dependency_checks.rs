@@ -0,0 +1,233 @@
+use log::{error, info};
+use serde::Serialize;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs;
+use tokio::process::Command;
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+
+use crate::env_config::{get_env_config, EnvConfig};
+use crate::toolchains::{get_toolchains, Toolchain};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the checks run again, so the readiness probe notices a dependency going missing
+const DEPENDENCY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DependencyStatus {
+    pub name: String,
+    pub ok: bool,
+    pub version: Option<String>,
+    pub error: Option<String>,
+}
+
+impl DependencyStatus {
+    fn found(name: String, version: Option<String>) -> Self {
+        DependencyStatus {
+            name,
+            ok: true,
+            version,
+            error: None,
+        }
+    }
+
+    fn missing(name: String, error: String) -> Self {
+        DependencyStatus {
+            name,
+            ok: false,
+            version: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DependencyStore {
+    /// `None` until the first checks finish
+    pub latest_statuses: Mutex<Option<Vec<DependencyStatus>>>,
+}
+
+/// Check the dependencies on startup and again on an interval, so probes only read the latest results
+pub async fn spawn_dependency_checks(
+    dependency_store: Arc<DependencyStore>,
+    stop_signal: CancellationToken,
+) {
+    let env_config = get_env_config();
+    let mut is_first_check = true;
+
+    loop {
+        let statuses = tokio::select! {
+            statuses = check_dependencies(&env_config) => statuses,
+
+            _ = stop_signal.cancelled() => {
+                break;
+            }
+        };
+
+        // Log everything once, after that only what is missing
+        if is_first_check {
+            log_dependency_statuses(&statuses);
+            is_first_check = false;
+        } else {
+            let missing: Vec<DependencyStatus> = statuses
+                .iter()
+                .filter(|status| !status.ok)
+                .cloned()
+                .collect();
+            log_dependency_statuses(&missing);
+        }
+
+        *dependency_store.latest_statuses.lock().unwrap() = Some(statuses);
+
+        tokio::select! {
+            _ = sleep(DEPENDENCY_CHECK_INTERVAL) => {
+                continue;
+            }
+
+            _ = stop_signal.cancelled() => {
+                break;
+            }
+        };
+    }
+
+    info!("gracefully shutting down dependency checks...");
+}
+
+/// Check every program and directory that compiling a patch relies on
+pub async fn check_dependencies(env_config: &EnvConfig) -> Vec<DependencyStatus> {
+    let mut statuses = vec![
+        check_command("python3", None).await,
+        check_command("make", None).await,
+        check_command("zip", Some("-v")).await,
+    ];
+
+    if env_config.ccache_enabled {
+        statuses.push(check_command("ccache", None).await);
+    }
+
+    for toolchain in get_toolchains() {
+        statuses.push(check_arm_gcc(toolchain).await);
+        statuses.push(check_pd2dsy_script(toolchain).await);
+        statuses.push(
+            check_writable_dir(
+                format!("toolchain {}: pd2dsy directory", toolchain.name),
+                &toolchain.dir_pd2dsy,
+            )
+            .await,
+        );
+    }
+
+    for dir_name in ["uploads", "downloads"] {
+        let mut dir = env_config.dir_workspace.clone();
+        dir.push(dir_name);
+
+        statuses.push(check_writable_dir(format!("workspace/{dir_name}"), &dir).await);
+    }
+
+    statuses
+}
+
+/// Run the checks once on startup, so a broken install shows up in the logs right away
+pub async fn log_dependency_checks(env_config: &EnvConfig) {
+    log_dependency_statuses(&check_dependencies(env_config).await);
+}
+
+fn log_dependency_statuses(statuses: &[DependencyStatus]) {
+    for status in statuses {
+        match (status.ok, &status.version, &status.error) {
+            (true, Some(version), _) => info!("Found {}: {}", status.name, version),
+            (true, None, _) => info!("Found {}", status.name),
+            (false, _, error) => error!(
+                "Missing dependency {}: {}",
+                status.name,
+                error.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+async fn check_command(program: &str, version_arg: Option<&str>) -> DependencyStatus {
+    let mut command = Command::new(program);
+    command.arg(version_arg.unwrap_or("--version"));
+
+    check_version_output(program.to_string(), command).await
+}
+
+async fn check_arm_gcc(toolchain: &Toolchain) -> DependencyStatus {
+    let name = format!("toolchain {}: arm-none-eabi-gcc", toolchain.name);
+
+    // Use the same PATH that `make` gets when compiling with this toolchain
+    let mut command = Command::new("arm-none-eabi-gcc");
+    command.arg("--version");
+
+    if let Some(dir_arm_gcc_bin) = &toolchain.dir_arm_gcc_bin {
+        let path = env::var("PATH").unwrap_or_default();
+        command.env("PATH", format!("{}:{}", dir_arm_gcc_bin.display(), path));
+    }
+
+    check_version_output(name, command).await
+}
+
+async fn check_version_output(name: String, mut command: Command) -> DependencyStatus {
+    command.kill_on_drop(true);
+
+    let output = match timeout(COMMAND_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(err)) => return DependencyStatus::missing(name, err.to_string()),
+        Err(_) => return DependencyStatus::missing(name, "timed out".to_string()),
+    };
+
+    if !output.status.success() {
+        return DependencyStatus::missing(name, format!("exited with {}", output.status));
+    }
+
+    // Some programs print their version to stderr instead of stdout, and `zip` starts with a copyright notice
+    let version = [&output.stdout, &output.stderr]
+        .into_iter()
+        .flat_map(|bytes| {
+            String::from_utf8_lossy(bytes)
+                .lines()
+                .map(|line| line.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .find(|line| !line.is_empty() && !line.starts_with("Copyright"));
+
+    DependencyStatus::found(name, version)
+}
+
+async fn check_pd2dsy_script(toolchain: &Toolchain) -> DependencyStatus {
+    let name = format!("toolchain {}: pd2dsy.py", toolchain.name);
+
+    let mut script_path = toolchain.dir_pd2dsy.clone();
+    script_path.push("pd2dsy.py");
+
+    match fs::metadata(&script_path).await {
+        Ok(metadata) if metadata.is_file() => {
+            DependencyStatus::found(name, Some(toolchain.pd2dsy_revision.clone()))
+        }
+        Ok(_) => {
+            DependencyStatus::missing(name, format!("{} is not a file", script_path.display()))
+        }
+        Err(err) => DependencyStatus::missing(name, format!("{}: {}", script_path.display(), err)),
+    }
+}
+
+async fn check_writable_dir(name: String, dir: &Path) -> DependencyStatus {
+    let mut probe_path = PathBuf::from(dir);
+    probe_path.push(".gardener-write-check");
+
+    if let Err(err) = fs::write(&probe_path, b"").await {
+        return DependencyStatus::missing(
+            name,
+            format!("{} is not writable: {}", dir.display(), err),
+        );
+    }
+
+    let _ = fs::remove_file(&probe_path).await;
+
+    DependencyStatus::found(name, None)
+}
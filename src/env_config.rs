@@ -1,31 +1,175 @@
 use std::env;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::thread;
+
+pub enum AppMode {
+    /// Serve the web UI and compile patches from the queue
+    Server,
+
+    /// Lease jobs from a remote server's queue and compile them locally
+    Worker,
+}
 
 pub struct EnvConfig {
+    pub mode: AppMode,
     pub dir_workspace: PathBuf,
     pub dir_pd2dsy: PathBuf,
+    pub toolchains_file: Option<PathBuf>,
     pub display_compilation_output: bool,
-    pub admin_token: String,
+    /// Number of parallel jobs for `make`
+    pub make_jobs: usize,
+    pub ccache_enabled: bool,
+    /// Only required when running as a server
+    pub admin_token: Option<String>,
+    /// Shared secret for the remote worker API, which is disabled on the server when this is missing
+    pub worker_token: Option<String>,
+    pub local_compilation_worker: bool,
+    /// Base URL of the server that a remote worker leases jobs from
+    pub server_url: Option<String>,
+    pub worker_name: String,
+    /// Compile a canary patch for every board on startup and on an interval
+    pub self_test_enabled: bool,
+    pub self_test_interval_secs: u64,
+    /// How long a build may keep running after shutdown starts before it is aborted and re-queued
+    pub shutdown_drain_secs: u64,
 }
 
 pub fn get_env_config() -> EnvConfig {
     // TODO: lazy_static trickery
 
+    let mode = match env::var("GARDENER_MODE") {
+        Ok(value) if value == "worker" => AppMode::Worker,
+        Ok(value) if value == "server" => AppMode::Server,
+        Ok(value) => panic!("Invalid env var: GARDENER_MODE={value}"),
+        Err(_) => AppMode::Server,
+    };
+
     let env_var_dir_workspace =
         env::var("DIR_WORKSPACE").expect("Missing required env var: DIR_WORKSPACE");
     let env_var_dir_pd2dsy = env::var("DIR_PD2DSY").expect("Missing required env var: DIR_PD2DSY");
 
+    let toolchains_file = env::var("TOOLCHAINS_FILE").ok().map(PathBuf::from);
+
     let display_compilation_output = match env::var("DISPLAY_COMPILATION_OUTPUT") {
         Ok(value) => value == "true",
         Err(_) => false,
     };
 
-    let admin_token = env::var("ADMIN_TOKEN").expect("Missing required env var: ADMIN_TOKEN");
+    let make_jobs = match env::var("MAKE_JOBS") {
+        // `make -j0` is an error, so zero is rejected along with anything that isn't a number
+        Ok(value) => value
+            .parse::<NonZeroUsize>()
+            .expect("Invalid env var: MAKE_JOBS must be a positive number")
+            .get(),
+        Err(_) => thread::available_parallelism()
+            .map(|jobs| jobs.get())
+            .unwrap_or(1),
+    };
+
+    let ccache_enabled = match env::var("CCACHE_ENABLED") {
+        Ok(value) => value == "true",
+        Err(_) => false,
+    };
+
+    let admin_token = match mode {
+        AppMode::Server => {
+            Some(env::var("ADMIN_TOKEN").expect("Missing required env var: ADMIN_TOKEN"))
+        }
+        AppMode::Worker => None,
+    };
+
+    let worker_token = match mode {
+        AppMode::Server => env::var("WORKER_TOKEN").ok(),
+        AppMode::Worker => {
+            Some(env::var("WORKER_TOKEN").expect("Missing required env var: WORKER_TOKEN"))
+        }
+    };
+
+    let local_compilation_worker = match env::var("LOCAL_COMPILATION_WORKER") {
+        Ok(value) => value != "false",
+        Err(_) => true,
+    };
+
+    let server_url = match mode {
+        AppMode::Server => None,
+        AppMode::Worker => Some(
+            env::var("SERVER_URL")
+                .expect("Missing required env var: SERVER_URL")
+                .trim_end_matches('/')
+                .to_string(),
+        ),
+    };
+
+    let worker_name = env::var("WORKER_NAME").unwrap_or_else(|_| "gardener-worker".to_string());
+
+    let self_test_enabled = match env::var("SELF_TEST_ENABLED") {
+        Ok(value) => value != "false",
+        Err(_) => true,
+    };
+
+    let self_test_interval_secs = match env::var("SELF_TEST_INTERVAL_SECS") {
+        Ok(value) => value
+            .parse()
+            .expect("Invalid env var: SELF_TEST_INTERVAL_SECS must be a number"),
+        Err(_) => 6 * 60 * 60,
+    };
+
+    let shutdown_drain_secs = match env::var("SHUTDOWN_DRAIN_SECS") {
+        Ok(value) => value
+            .parse()
+            .expect("Invalid env var: SHUTDOWN_DRAIN_SECS must be a number"),
+        Err(_) => 20,
+    };
 
     EnvConfig {
+        mode,
         dir_workspace: PathBuf::from(env_var_dir_workspace),
         dir_pd2dsy: PathBuf::from(env_var_dir_pd2dsy),
+        toolchains_file,
         display_compilation_output,
+        make_jobs,
+        ccache_enabled,
         admin_token,
+        worker_token,
+        local_compilation_worker,
+        server_url,
+        worker_name,
+        self_test_enabled,
+        self_test_interval_secs,
+        shutdown_drain_secs,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Once;
+
+    static INIT_TEST_ENV: Once = Once::new();
+
+    /// Point the env config at a scratch directory, shared by every test since toolchains are
+    /// only loaded once. The workspace is deliberately not called `workspace`.
+    pub fn init_test_env() -> EnvConfig {
+        INIT_TEST_ENV.call_once(|| {
+            let dir_root = env::temp_dir().join(format!("gardener-test-{}", std::process::id()));
+            let dir_workspace = dir_root.join("custom-workspace");
+            let dir_pd2dsy = dir_root.join("pd2dsy");
+
+            for dir in [
+                dir_workspace.join("uploads"),
+                dir_workspace.join("downloads"),
+                dir_pd2dsy.join("builds"),
+            ] {
+                fs::create_dir_all(dir).unwrap();
+            }
+
+            env::set_var("DIR_WORKSPACE", &dir_workspace);
+            env::set_var("DIR_PD2DSY", &dir_pd2dsy);
+            env::set_var("ADMIN_TOKEN", "test");
+        });
+
+        get_env_config()
     }
 }
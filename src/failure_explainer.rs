@@ -0,0 +1,80 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// A plain-language description of why a build failed, shown above the raw compiler output
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailureExplanation {
+    pub title: String,
+    pub explanation: String,
+    pub suggested_fix: String,
+}
+
+/// A common mistake, recognized by a pattern in the pd2dsy or make output.
+/// Capture groups can be referenced in the text as `$1`, `$2`, etc.
+struct KnownFailure {
+    pattern: Regex,
+    title: &'static str,
+    explanation: &'static str,
+    suggested_fix: &'static str,
+}
+
+lazy_static! {
+    static ref KNOWN_FAILURES: Vec<KnownFailure> = vec![
+        KnownFailure {
+            pattern: Regex::new(r#"(?i)abstraction "?([^"\s]+?)"? (?:was )?not found"#).unwrap(),
+            title: "Abstraction not found",
+            explanation: "Your patch uses an abstraction called [$1], but its .pd file wasn't uploaded with the patch.",
+            suggested_fix: "Upload the abstraction along with your patch, or copy its contents into a subpatch.",
+        },
+        KnownFailure {
+            pattern: Regex::new(r#"Don't know how to parse object "([^"]+)""#).unwrap(),
+            title: "Unsupported object",
+            explanation: "[$1] is not one of the objects supported by Heavy, the compiler pd2dsy uses to turn your patch into C++. If it is an abstraction, it wasn't uploaded with the patch.",
+            suggested_fix: "Replace [$1] with supported vanilla objects. The hvcc documentation lists every supported object.",
+        },
+        KnownFailure {
+            pattern: Regex::new(r#"(?i)(?:no (?:audio )?output|dac~ (?:was )?not found|does not have any outputs)"#).unwrap(),
+            title: "No audio output",
+            explanation: "Your patch doesn't send any audio to [dac~], so the program would be silent.",
+            suggested_fix: "Connect the signal you want to hear to a [dac~] object.",
+        },
+        KnownFailure {
+            pattern: Regex::new(r#"KeyError: '(\w+)'"#).unwrap(),
+            title: "Board definition is missing a field",
+            explanation: "pd2dsy expected a \"$1\" field in your custom board definition JSON, but couldn't find it.",
+            suggested_fix: "Add \"$1\" to your board definition. The board files that ship with pd2dsy are good examples to start from.",
+        },
+        KnownFailure {
+            pattern: Regex::new(r#"JSONDecodeError"#).unwrap(),
+            title: "Board definition is not valid JSON",
+            explanation: "pd2dsy couldn't read your custom board definition because it isn't valid JSON.",
+            suggested_fix: "Check the board definition for trailing commas, missing quotes or unbalanced brackets.",
+        },
+        KnownFailure {
+            pattern: Regex::new(r#"region `(\w+)' overflowed by (\d+) bytes"#).unwrap(),
+            title: "Program is too big",
+            explanation: "The compiled program is $2 bytes too big for the $1 memory region, even after trying the bootloader memory layouts.",
+            suggested_fix: "Simplify the patch, or try the \"size\" build profile.",
+        },
+    ];
+}
+
+/// Find the first known failure that matches the build output
+pub fn explain_failure(output: &str) -> Option<FailureExplanation> {
+    KNOWN_FAILURES.iter().find_map(|known_failure| {
+        let captures = known_failure.pattern.captures(output)?;
+
+        let expand = |template: &str| {
+            let mut expanded = String::new();
+            captures.expand(template, &mut expanded);
+            expanded
+        };
+
+        Some(FailureExplanation {
+            title: known_failure.title.to_string(),
+            explanation: expand(known_failure.explanation),
+            suggested_fix: expand(known_failure.suggested_fix),
+        })
+    })
+}
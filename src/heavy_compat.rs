@@ -0,0 +1,413 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::autofix::OBJECT_ALIASES;
+use crate::pd_parser::{ElementKind, ElementRef, PdPatch};
+
+/// Vanilla objects that Heavy can compile, from the hvcc documentation
+const HEAVY_SUPPORTED_OBJECTS: &[&str] = &[
+    // Message objects
+    "!=",
+    "%",
+    "&",
+    "&&",
+    "|",
+    "||",
+    "*",
+    "+",
+    "-",
+    "/",
+    "<",
+    "<<",
+    "<=",
+    "==",
+    ">",
+    ">=",
+    ">>",
+    "abs",
+    "atan",
+    "atan2",
+    "b",
+    "bang",
+    "bendin",
+    "bendout",
+    "bng",
+    "change",
+    "clip",
+    "cos",
+    "ctlin",
+    "ctlout",
+    "dbtopow",
+    "dbtorms",
+    "declare",
+    "del",
+    "delay",
+    "div",
+    "exp",
+    "f",
+    "float",
+    "floatatom",
+    "ftom",
+    "hradio",
+    "hsl",
+    "hslider",
+    "i",
+    "inlet",
+    "int",
+    "line",
+    "loadbang",
+    "log",
+    "makenote",
+    "max",
+    "metro",
+    "midiin",
+    "midiout",
+    "midirealtimein",
+    "min",
+    "mod",
+    "moses",
+    "mtof",
+    "nbx",
+    "notein",
+    "noteout",
+    "outlet",
+    "pack",
+    "pgmin",
+    "pgmout",
+    "pipe",
+    "poly",
+    "polytouchin",
+    "polytouchout",
+    "pow",
+    "powtodb",
+    "print",
+    "r",
+    "random",
+    "receive",
+    "rmstodb",
+    "s",
+    "sel",
+    "select",
+    "send",
+    "sin",
+    "spigot",
+    "sqrt",
+    "swap",
+    "symbol",
+    "symbolatom",
+    "t",
+    "table",
+    "tabread",
+    "tabwrite",
+    "tan",
+    "tgl",
+    "timer",
+    "touchin",
+    "touchout",
+    "trigger",
+    "unpack",
+    "until",
+    "vradio",
+    "vsl",
+    "vslider",
+    "wrap",
+    // Signal objects
+    "*~",
+    "+~",
+    "-~",
+    "/~",
+    "abs~",
+    "adc~",
+    "biquad~",
+    "bp~",
+    "catch~",
+    "clip~",
+    "cos~",
+    "cpole~",
+    "czero_rev~",
+    "czero~",
+    "dac~",
+    "dbtopow~",
+    "dbtorms~",
+    "delread~",
+    "delwrite~",
+    "env~",
+    "exp~",
+    "ftom~",
+    "hip~",
+    "inlet~",
+    "line~",
+    "lop~",
+    "max~",
+    "min~",
+    "mtof~",
+    "noise~",
+    "osc~",
+    "outlet~",
+    "phasor~",
+    "pow~",
+    "powtodb~",
+    "receive~",
+    "rmstodb~",
+    "rpole~",
+    "rsqrt~",
+    "rzero_rev~",
+    "rzero~",
+    "r~",
+    "samphold~",
+    "samplerate~",
+    "s~",
+    "send~",
+    "sig~",
+    "snapshot~",
+    "sqrt~",
+    "tabosc4~",
+    "tabplay~",
+    "tabread4~",
+    "tabread~",
+    "tabwrite~",
+    "throw~",
+    "vcf~",
+    "vd~",
+    "wrap~",
+];
+
+lazy_static! {
+    static ref SUPPORTED_OBJECTS: HashSet<&'static str> =
+        HEAVY_SUPPORTED_OBJECTS.iter().copied().collect();
+
+    /// Common vanilla objects that Heavy rejects, with what to do instead
+    static ref UNSUPPORTED_VANILLA_HINTS: HashMap<&'static str, &'static str> = HashMap::from([
+        ("expr", "rewrite the expression with math objects like [+] and [*]"),
+        ("expr~", "rewrite the expression with signal math objects like [+~] and [*~]"),
+        ("fexpr~", "rewrite the filter with [rpole~], [rzero~] or [biquad~]"),
+        ("soundfiler", "Daisy has no file system to load sounds from, store the samples in an array instead"),
+        ("readsf~", "Daisy has no file system to stream sounds from"),
+        ("writesf~", "Daisy has no file system to record to"),
+        ("vline~", "use [line~] instead"),
+        ("delread4~", "use [vd~] instead"),
+        ("fswap", "use [swap] instead"),
+        ("hdl", "use [hradio] instead"),
+        ("vdl", "use [vradio] instead"),
+        ("list", "split the list with [unpack] or build it with [pack]"),
+        ("array", "use a graph array with [tabread] and [tabwrite] instead"),
+        ("text", "Heavy cannot store text, use an array or [table] instead"),
+        ("value", "use [send] and [receive] instead"),
+        ("v", "use [send] and [receive] instead"),
+        ("netsend", "Daisy has no network connection"),
+        ("netreceive", "Daisy has no network connection"),
+    ]);
+
+    /// Objects that plugdata provides through its bundled ELSE and cyclone libraries
+    static ref PLUGDATA_OBJECTS: HashSet<&'static str> = HashSet::from([
+        "knob", "keyboard", "pic", "note", "button", "colors", "function", "oscope~",
+        "numbox~", "scope~", "messbox", "bicoeff", "graph~", "slider2d", "circle",
+    ]);
+
+    /// Supported objects that build fine but behave differently on Daisy hardware
+    static ref OBJECT_WARNINGS: HashMap<&'static str, &'static str> = HashMap::from([
+        ("print", "output from [print] is not visible on Daisy hardware by default"),
+        ("declare", "Heavy ignores [declare], so libraries it adds are not available. Paths in the main patch are only used to find uploaded abstractions"),
+    ]);
+}
+
+/// An object in an uploaded patch that Heavy cannot compile, or that behaves differently
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompatibilityIssue {
+    pub object: String,
+    pub location: String,
+    pub reason: String,
+}
+
+impl fmt::Display for CompatibilityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] at {}: {}", self.object, self.location, self.reason)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CompatibilityReport {
+    /// Objects that will make the build fail
+    pub unsupported: Vec<CompatibilityIssue>,
+    pub warnings: Vec<CompatibilityIssue>,
+}
+
+/// Check every object in the patch against what Heavy supports, besides the uploaded abstractions.
+/// `can_autofix` is whether automatic fixes would rewrite this patch, so hints can suggest them.
+pub fn check_heavy_compatibility(
+    patch: &PdPatch,
+    known_abstractions: &HashSet<String>,
+    can_autofix: bool,
+) -> CompatibilityReport {
+    let mut report = CompatibilityReport::default();
+
+    for element_ref in patch.elements() {
+        let issue = |object: &str, reason: &str| CompatibilityIssue {
+            object: object.to_string(),
+            location: element_ref.location(),
+            reason: reason.to_string(),
+        };
+
+        match &element_ref.element.kind {
+            ElementKind::Object { name, .. } if name.is_empty() => {
+                report
+                    .warnings
+                    .push(issue("", "empty object boxes are ignored by Heavy"));
+            }
+            ElementKind::Object { name, .. } if known_abstractions.contains(name) => {}
+            ElementKind::Object { name, .. } => {
+                check_object(name, &element_ref, can_autofix, &mut report);
+            }
+            ElementKind::AtomBox { kind, .. } if !SUPPORTED_OBJECTS.contains(kind.as_str()) => {
+                report
+                    .unsupported
+                    .push(issue(kind, "not supported by Heavy"));
+            }
+            _ => {}
+        }
+    }
+
+    report
+}
+
+fn check_object(
+    name: &str,
+    element_ref: &ElementRef,
+    can_autofix: bool,
+    report: &mut CompatibilityReport,
+) {
+    let issue = |reason: String| CompatibilityIssue {
+        object: name.to_string(),
+        location: element_ref.location(),
+        reason,
+    };
+
+    if SUPPORTED_OBJECTS.contains(name) {
+        if let Some(warning) = OBJECT_WARNINGS.get(name) {
+            report.warnings.push(issue(warning.to_string()));
+        }
+
+        return;
+    }
+
+    let reason = if let Some(hint) = UNSUPPORTED_VANILLA_HINTS.get(name) {
+        let is_alias = OBJECT_ALIASES.iter().any(|(alias, _)| *alias == name);
+
+        if can_autofix && is_alias {
+            format!("not supported by Heavy, {hint}, or turn on automatic fixes")
+        } else {
+            format!("not supported by Heavy, {hint}")
+        }
+    } else if let Some((library, _)) = name.split_once('/') {
+        format!("comes from the {library} library, but Heavy only supports vanilla objects")
+    } else if PLUGDATA_OBJECTS.contains(name) {
+        "is a plugdata object from the ELSE library, but Heavy only supports vanilla objects"
+            .to_string()
+    } else {
+        "not supported by Heavy, it may be an external or an abstraction that was not uploaded"
+            .to_string()
+    };
+
+    report.unsupported.push(issue(reason));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pd_parser::parse_patch;
+
+    fn check(
+        contents: &str,
+        known_abstractions: &[&str],
+        can_autofix: bool,
+    ) -> CompatibilityReport {
+        let known_abstractions = known_abstractions
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        check_heavy_compatibility(
+            &parse_patch(contents).unwrap(),
+            &known_abstractions,
+            can_autofix,
+        )
+    }
+
+    fn objects(issues: &[CompatibilityIssue]) -> Vec<&str> {
+        issues.iter().map(|issue| issue.object.as_str()).collect()
+    }
+
+    #[test]
+    fn reports_unsupported_objects_with_where_they_come_from() {
+        let report = check(
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 osc~ 440;
+#X obj 10 40 expr \\$f1 * 2;
+#X obj 10 70 else/knob;
+#X obj 10 100 knob;
+#X obj 10 130 mystery~;
+#X listbox 10 160 5 0 0 0 - - - 0;
+#N canvas 0 50 450 300 sub 0;
+#X obj 10 10 vline~;
+#X restore 10 190 pd sub;
+",
+            &[],
+            false,
+        );
+
+        assert_eq!(
+            objects(&report.unsupported),
+            vec!["expr", "else/knob", "knob", "mystery~", "listbox", "vline~"]
+        );
+        assert!(report.warnings.is_empty());
+
+        let reasons: Vec<&str> = report
+            .unsupported
+            .iter()
+            .map(|issue| issue.reason.as_str())
+            .collect();
+        assert!(reasons[0].contains("[+] and [*]"));
+        assert!(reasons[1].contains("from the else library"));
+        assert!(reasons[2].contains("plugdata object"));
+        assert!(reasons[3].contains("abstraction that was not uploaded"));
+        assert_eq!(report.unsupported[5].location, "line 9 in [pd sub]");
+    }
+
+    #[test]
+    fn suggests_automatic_fixes_only_for_objects_they_replace() {
+        let contents = "#N canvas 0 50 450 300 12;
+#X obj 10 10 fswap;
+#X obj 10 40 vline~;
+";
+
+        let report = check(contents, &[], true);
+        assert!(report.unsupported[0]
+            .reason
+            .ends_with("use [swap] instead, or turn on automatic fixes"));
+        assert!(report.unsupported[1]
+            .reason
+            .ends_with("use [line~] instead"));
+
+        let report = check(contents, &[], false);
+        assert!(report.unsupported[0].reason.ends_with("use [swap] instead"));
+    }
+
+    #[test]
+    fn skips_uploaded_abstractions_and_warns_about_behaviour() {
+        let report = check(
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 my-synth 440;
+#X obj 10 40 print;
+#X obj 10 70;
+#X floatatom 10 100 5 0 0 0 - - - 0;
+",
+            &["my-synth"],
+            false,
+        );
+
+        assert!(report.unsupported.is_empty());
+        assert_eq!(objects(&report.warnings), vec!["print", ""]);
+    }
+}
@@ -0,0 +1,316 @@
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::compilation_worker::get_compiling_patch;
+use crate::patches::{requeue_patch, PatchMeta, PatchesStore};
+
+/// How long a remote worker may go without a heartbeat before its job is re-queued
+pub const LEASE_DURATION: Duration = Duration::from_secs(60);
+
+/// A patch that a remote worker has taken off the compilation queue
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub id: String,
+    pub patch_id: String,
+    pub worker_name: String,
+    pub expires_at: Instant,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaseRequest {
+    pub worker_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaseResponse {
+    pub lease_id: String,
+    pub lease_duration_secs: u64,
+    pub patch: PatchMeta,
+}
+
+/// Pop the next patch off the queue and lease it to a remote worker
+pub fn lease_next_patch(patches_store: &PatchesStore, worker_name: &str) -> Option<LeaseResponse> {
+    let mut queue = patches_store.compilation_queue.lock().unwrap();
+    let mut patches = patches_store.patches.lock().unwrap();
+
+    let patch = loop {
+        let patch_id = queue.pop_front()?;
+
+        if let Some(patch) = patches.get(&patch_id) {
+            break patch.clone();
+        }
+    };
+
+    let compiling_patch = get_compiling_patch(patch);
+    patches.insert(compiling_patch.id.clone(), compiling_patch.clone());
+
+    let lease = Lease {
+        id: Uuid::new_v4().to_string(),
+        patch_id: compiling_patch.id.clone(),
+        worker_name: worker_name.to_string(),
+        expires_at: Instant::now() + LEASE_DURATION,
+    };
+    info!(
+        "Leased patch {} to worker {} (lease {})",
+        lease.patch_id, lease.worker_name, lease.id
+    );
+
+    let response = LeaseResponse {
+        lease_id: lease.id.clone(),
+        lease_duration_secs: LEASE_DURATION.as_secs(),
+        patch: compiling_patch,
+    };
+
+    patches_store
+        .leases
+        .lock()
+        .unwrap()
+        .insert(lease.id.clone(), lease);
+
+    Some(response)
+}
+
+/// Look up the patch for an active lease
+pub fn get_leased_patch(patches_store: &PatchesStore, lease_id: &str) -> Option<PatchMeta> {
+    let patch_id = {
+        let leases = patches_store.leases.lock().unwrap();
+        leases.get(lease_id)?.patch_id.clone()
+    };
+
+    patches_store
+        .patches
+        .lock()
+        .unwrap()
+        .get(&patch_id)
+        .cloned()
+}
+
+/// Extend a lease, returning `false` if it has already expired or finished
+pub fn renew_lease(patches_store: &PatchesStore, lease_id: &str) -> bool {
+    let mut leases = patches_store.leases.lock().unwrap();
+
+    match leases.get_mut(lease_id) {
+        Some(lease) => {
+            trace!("Renewing lease {} for patch {}", lease.id, lease.patch_id);
+            lease.expires_at = Instant::now() + LEASE_DURATION;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Store the results a remote worker reported for its patch, and release the lease
+pub fn complete_lease(
+    patches_store: &PatchesStore,
+    lease_id: &str,
+    finished_patch: PatchMeta,
+) -> bool {
+    let lease = match patches_store.leases.lock().unwrap().remove(lease_id) {
+        Some(lease) => lease,
+        None => return false,
+    };
+
+    let mut patches = patches_store.patches.lock().unwrap();
+
+    if let Some(patch) = patches.get(&lease.patch_id) {
+        info!(
+            "Worker {} finished patch {} (lease {})",
+            lease.worker_name, lease.patch_id, lease.id
+        );
+
+        // Only take the build results from the worker, not the patch's identity or options
+        let updated_patch = PatchMeta {
+            status: finished_patch.status,
+            memory_layout: finished_patch.memory_layout,
+            compiler_cache: finished_patch.compiler_cache,
+            resource_usage: finished_patch.resource_usage,
+            time_compile_end: finished_patch.time_compile_end,
+            ..patch.clone()
+        };
+        patches.insert(lease.patch_id.clone(), updated_patch);
+    }
+
+    true
+}
+
+/// Give up a lease without results, so the patch can be built by someone else
+pub fn release_lease(patches_store: &PatchesStore, lease_id: &str) -> bool {
+    let lease = match patches_store.leases.lock().unwrap().remove(lease_id) {
+        Some(lease) => lease,
+        None => return false,
+    };
+
+    info!(
+        "Worker {} handed back patch {} (lease {}). Re-queueing.",
+        lease.worker_name, lease.patch_id, lease.id
+    );
+
+    requeue_patch(patches_store, &lease.patch_id);
+
+    true
+}
+
+/// Put patches back on the queue when their worker stops sending heartbeats
+pub async fn spawn_lease_reaper(patches_store: Arc<PatchesStore>, stop_signal: CancellationToken) {
+    loop {
+        expire_leases(&patches_store);
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {
+                continue;
+            }
+
+            _ = stop_signal.cancelled() => {
+                info!("gracefully shutting down lease reaper...");
+                break;
+            }
+        };
+    }
+}
+
+fn expire_leases(patches_store: &PatchesStore) {
+    let now = Instant::now();
+
+    let expired_leases: Vec<Lease> = {
+        let mut leases = patches_store.leases.lock().unwrap();
+        let expired_ids: Vec<String> = leases
+            .values()
+            .filter(|lease| lease.expires_at <= now)
+            .map(|lease| lease.id.clone())
+            .collect();
+
+        expired_ids
+            .iter()
+            .filter_map(|lease_id| leases.remove(lease_id))
+            .collect()
+    };
+
+    for lease in expired_leases {
+        warn!(
+            "Lease {} for patch {} expired, worker {} stopped responding. Re-queueing.",
+            lease.id, lease.patch_id, lease.worker_name
+        );
+
+        requeue_patch(patches_store, &lease.patch_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::tests::{queued_store, uploaded_patch};
+    use crate::patches::PatchStatus;
+
+    fn queue(patches_store: &PatchesStore) -> Vec<String> {
+        patches_store
+            .compilation_queue
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn status(patches_store: &PatchesStore, patch_id: &str) -> PatchStatus {
+        patches_store.patches.lock().unwrap()[patch_id]
+            .status
+            .clone()
+    }
+
+    #[test]
+    fn leases_the_next_queued_patch() {
+        let patches_store = queued_store(vec![uploaded_patch("a"), uploaded_patch("b")]);
+
+        let lease = lease_next_patch(&patches_store, "worker-1").unwrap();
+
+        assert_eq!(lease.patch.id, "a");
+        assert_eq!(queue(&patches_store), vec!["b"]);
+        assert!(matches!(
+            status(&patches_store, "a"),
+            PatchStatus::Compiling
+        ));
+        assert_eq!(
+            get_leased_patch(&patches_store, &lease.lease_id)
+                .unwrap()
+                .id,
+            "a"
+        );
+        assert!(renew_lease(&patches_store, &lease.lease_id));
+
+        lease_next_patch(&patches_store, "worker-2").unwrap();
+        assert!(lease_next_patch(&patches_store, "worker-3").is_none());
+    }
+
+    #[test]
+    fn requeues_expired_leases_at_the_front() {
+        let patches_store = queued_store(vec![uploaded_patch("a"), uploaded_patch("b")]);
+        let lease = lease_next_patch(&patches_store, "worker-1").unwrap();
+
+        // Still running, nothing to expire
+        expire_leases(&patches_store);
+        assert_eq!(queue(&patches_store), vec!["b"]);
+
+        patches_store
+            .leases
+            .lock()
+            .unwrap()
+            .get_mut(&lease.lease_id)
+            .unwrap()
+            .expires_at = Instant::now();
+        expire_leases(&patches_store);
+
+        assert_eq!(queue(&patches_store), vec!["a", "b"]);
+        assert!(matches!(status(&patches_store, "a"), PatchStatus::Uploaded));
+        assert!(!renew_lease(&patches_store, &lease.lease_id));
+        assert!(get_leased_patch(&patches_store, &lease.lease_id).is_none());
+    }
+
+    #[test]
+    fn releasing_requeues_and_ends_the_lease() {
+        let patches_store = queued_store(vec![uploaded_patch("a")]);
+        let lease = lease_next_patch(&patches_store, "worker-1").unwrap();
+
+        assert!(release_lease(&patches_store, &lease.lease_id));
+        assert!(!release_lease(&patches_store, &lease.lease_id));
+
+        assert_eq!(queue(&patches_store), vec!["a"]);
+        assert!(matches!(status(&patches_store, "a"), PatchStatus::Uploaded));
+    }
+
+    #[test]
+    fn completing_keeps_the_results_but_not_the_identity() {
+        let patches_store = queued_store(vec![uploaded_patch("a")]);
+        let lease = lease_next_patch(&patches_store, "worker-1").unwrap();
+
+        let finished_patch = PatchMeta {
+            status: PatchStatus::Failed {
+                summary: "make command failed".to_string(),
+                details: None,
+                explanation: None,
+            },
+            filename: "renamed-by-worker.pd".to_string(),
+            ..lease.patch.clone()
+        };
+
+        assert!(complete_lease(
+            &patches_store,
+            &lease.lease_id,
+            finished_patch.clone()
+        ));
+        assert!(!complete_lease(
+            &patches_store,
+            &lease.lease_id,
+            finished_patch
+        ));
+
+        let patch = patches_store.patches.lock().unwrap()["a"].clone();
+        assert!(matches!(patch.status, PatchStatus::Failed { .. }));
+        assert_eq!(patch.filename, "patch.pd");
+        assert!(queue(&patches_store).is_empty());
+    }
+}
@@ -0,0 +1,385 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::parameters::extract_parameters;
+use crate::pd_parser::{Atom, Canvas, Element, ElementKind, ElementRef, PdPatch};
+
+/// Objects that take audio out of a chain, so signals reaching them are not wasted
+const SIGNAL_SINKS: &[&str] = &[
+    "dac~",
+    "outlet~",
+    "throw~",
+    "s~",
+    "send~",
+    "delwrite~",
+    "tabwrite~",
+    "snapshot~",
+    "env~",
+];
+
+/// Signal math objects whose right inlet only takes signals when they have no argument
+const SIGNAL_MATH_OBJECTS: &[&str] = &["*~", "+~", "-~", "/~", "max~", "min~"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum LintSeverity {
+    /// The firmware will misbehave
+    Error,
+    /// The firmware probably won't do what was intended
+    Warning,
+    /// Worth a look, but often deliberate
+    Info,
+}
+
+impl LintSeverity {
+    pub fn to_str(&self) -> String {
+        match self {
+            LintSeverity::Error => "error".to_string(),
+            LintSeverity::Warning => "warning".to_string(),
+            LintSeverity::Info => "info".to_string(),
+        }
+    }
+}
+
+/// A likely mistake in a patch that Heavy will still compile
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LintFinding {
+    pub rule: String,
+    pub severity: LintSeverity,
+    pub message: String,
+    pub location: Option<String>,
+}
+
+/// Run every lint rule over the patch, including the subpatches.
+/// Objects named in `known_abstractions` are the project's own abstractions, whose outlets could be either rate.
+pub fn lint_patch(patch: &PdPatch, known_abstractions: &HashSet<String>) -> Vec<LintFinding> {
+    let mut findings = vec![];
+
+    check_missing_dac(patch, &mut findings);
+    check_duplicate_parameters(patch, &mut findings);
+    lint_canvas(&patch.root, known_abstractions, &mut vec![], &mut findings);
+
+    findings
+}
+
+fn lint_canvas(
+    canvas: &Canvas,
+    known_abstractions: &HashSet<String>,
+    canvas_path: &mut Vec<String>,
+    findings: &mut Vec<LintFinding>,
+) {
+    check_unconnected_audio(canvas, canvas_path, findings);
+    check_control_into_signal(canvas, known_abstractions, canvas_path, findings);
+    check_fan_out(canvas, known_abstractions, canvas_path, findings);
+
+    for element in canvas.elements.iter() {
+        if let ElementKind::Subpatch { canvas: subpatch }
+        | ElementKind::Graph { canvas: subpatch } = &element.kind
+        {
+            canvas_path.push(subpatch.name.clone().unwrap_or_default());
+            lint_canvas(subpatch, known_abstractions, canvas_path, findings);
+            canvas_path.pop();
+        }
+    }
+}
+
+fn location(element: &Element, canvas_path: &[String]) -> String {
+    ElementRef {
+        element,
+        canvas_path: canvas_path.to_vec(),
+    }
+    .location()
+}
+
+fn object_name(element: &Element) -> Option<&str> {
+    match &element.kind {
+        ElementKind::Object { name, .. } => Some(name.as_str()),
+        _ => None,
+    }
+}
+
+fn check_missing_dac(patch: &PdPatch, findings: &mut Vec<LintFinding>) {
+    let has_dac = patch
+        .elements()
+        .iter()
+        .any(|element_ref| object_name(element_ref.element) == Some("dac~"));
+
+    if !has_dac {
+        findings.push(LintFinding {
+            rule: "missing-dac".to_string(),
+            severity: LintSeverity::Info,
+            message: "The patch has no [dac~], so it won't make any sound. That's fine if it only drives LEDs or CV outputs.".to_string(),
+            location: None,
+        });
+    }
+}
+
+fn check_duplicate_parameters(patch: &PdPatch, findings: &mut Vec<LintFinding>) {
+    let mut seen = HashSet::new();
+
+    for parameter in extract_parameters(patch) {
+        if !seen.insert((parameter.name.clone(), parameter.direction)) {
+            findings.push(LintFinding {
+                rule: "duplicate-parameter".to_string(),
+                severity: LintSeverity::Warning,
+                message: format!(
+                    "\"{}\" is already exposed with @hv_param, only one of them will follow the control. Use a plain [r {}] for the others.",
+                    parameter.name, parameter.name
+                ),
+                location: Some(parameter.location),
+            });
+        }
+    }
+}
+
+/// Flag the end of every audio chain that never reaches an output
+fn check_unconnected_audio(
+    canvas: &Canvas,
+    canvas_path: &[String],
+    findings: &mut Vec<LintFinding>,
+) {
+    // Subpatches, abstractions and control objects could pass the signal on, so they count as outputs
+    let is_output = |element: &Element| {
+        !element.has_signal_outlets()
+            || object_name(element).is_some_and(|name| SIGNAL_SINKS.contains(&name))
+    };
+
+    // Work back from the outputs to every object whose audio reaches one
+    let mut heard: HashSet<usize> = canvas
+        .elements
+        .iter()
+        .filter(|element| is_output(element))
+        .map(|element| element.index)
+        .collect();
+    let mut pending: Vec<usize> = heard.iter().copied().collect();
+
+    while let Some(sink) = pending.pop() {
+        for connection in canvas.connections.iter() {
+            if connection.sink == sink && heard.insert(connection.source) {
+                pending.push(connection.source);
+            }
+        }
+    }
+
+    for element in canvas.elements.iter() {
+        if heard.contains(&element.index) {
+            continue;
+        }
+
+        // Only the last object of a silent chain is reported
+        let feeds_silent_object = canvas.connections.iter().any(|connection| {
+            connection.source == element.index && !heard.contains(&connection.sink)
+        });
+        if feeds_silent_object {
+            continue;
+        }
+
+        findings.push(LintFinding {
+            rule: "unconnected-audio".to_string(),
+            severity: LintSeverity::Warning,
+            message: format!(
+                "The output of [{}] never reaches [dac~] or another output, so it will never be heard. Connect it to [dac~] or remove it.",
+                object_name(element).unwrap_or_default()
+            ),
+            location: Some(location(element, canvas_path)),
+        });
+    }
+}
+
+fn is_signal_only_inlet(element: &Element, inlet: usize) -> bool {
+    match &element.kind {
+        ElementKind::Object { name, .. } if name == "dac~" => true,
+        ElementKind::Object { name, args } if SIGNAL_MATH_OBJECTS.contains(&name.as_str()) => {
+            inlet == 1 && args.is_empty()
+        }
+        _ => false,
+    }
+}
+
+fn check_control_into_signal(
+    canvas: &Canvas,
+    known_abstractions: &HashSet<String>,
+    canvas_path: &[String],
+    findings: &mut Vec<LintFinding>,
+) {
+    for connection in canvas.connections.iter() {
+        let source = &canvas.elements[connection.source];
+        let sink = &canvas.elements[connection.sink];
+
+        if source.is_control_source(known_abstractions)
+            && is_signal_only_inlet(sink, connection.inlet)
+        {
+            findings.push(LintFinding {
+                rule: "control-into-signal".to_string(),
+                severity: LintSeverity::Error,
+                message: format!(
+                    "A control-rate {} is connected to inlet {} of [{}], which only takes signals. Convert it with [sig~] or [line~] first.",
+                    describe(source),
+                    connection.inlet,
+                    object_name(sink).unwrap_or_default()
+                ),
+                location: Some(location(sink, canvas_path)),
+            });
+        }
+    }
+}
+
+/// A control outlet connected to several inlets fires them in an order Heavy doesn't guarantee
+fn check_fan_out(
+    canvas: &Canvas,
+    known_abstractions: &HashSet<String>,
+    canvas_path: &[String],
+    findings: &mut Vec<LintFinding>,
+) {
+    let mut fan_outs: HashMap<(usize, usize), usize> = HashMap::new();
+    for connection in canvas.connections.iter() {
+        *fan_outs
+            .entry((connection.source, connection.outlet))
+            .or_default() += 1;
+    }
+
+    let mut fan_outs: Vec<((usize, usize), usize)> = fan_outs
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .collect();
+    fan_outs.sort();
+
+    for ((source, outlet), count) in fan_outs {
+        let element = &canvas.elements[source];
+
+        if element.is_control_source(known_abstractions) {
+            findings.push(LintFinding {
+                rule: "fan-out".to_string(),
+                severity: LintSeverity::Warning,
+                message: format!(
+                    "Outlet {} of {} is connected to {} inlets, so the order they fire in is undefined. Use [t b b] or [t f f] to set the order.",
+                    outlet,
+                    describe(element),
+                    count
+                ),
+                location: Some(location(element, canvas_path)),
+            });
+        }
+    }
+}
+
+fn describe(element: &Element) -> String {
+    match &element.kind {
+        ElementKind::Object { name, .. } => format!("[{name}]"),
+        ElementKind::Message { atoms } => {
+            let atoms: Vec<String> = atoms.iter().map(Atom::to_string).collect();
+            format!("message [{}(", atoms.join(" "))
+        }
+        ElementKind::AtomBox { kind, .. } => kind.clone(),
+        _ => "box".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pd_parser::parse_patch;
+
+    fn rules(contents: &str, known_abstractions: &[&str]) -> Vec<String> {
+        let patch = parse_patch(contents).unwrap();
+        let known_abstractions = known_abstractions
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        lint_patch(&patch, &known_abstractions)
+            .into_iter()
+            .map(|finding| finding.rule)
+            .collect()
+    }
+
+    #[test]
+    fn flags_fan_outs_from_env_and_snapshot() {
+        let rules = rules(
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 adc~;
+#X obj 10 40 env~;
+#X obj 10 70 print a;
+#X obj 80 70 print b;
+#X obj 10 100 dac~;
+#X connect 0 0 1 0;
+#X connect 0 0 4 0;
+#X connect 1 0 2 0;
+#X connect 1 0 3 0;
+",
+            &[],
+        );
+
+        assert_eq!(rules, vec!["fan-out"]);
+    }
+
+    #[test]
+    fn treats_abstractions_as_either_rate() {
+        let contents = "#N canvas 0 50 450 300 12;
+#X obj 10 10 my-osc 440;
+#X obj 10 40 *~;
+#X obj 10 70 dac~;
+#X connect 0 0 1 1;
+#X connect 0 0 2 0;
+#X connect 1 0 2 1;
+";
+
+        assert!(rules(contents, &["my-osc"]).is_empty());
+        assert_eq!(
+            rules(contents, &[]),
+            vec!["control-into-signal", "control-into-signal", "fan-out"]
+        );
+    }
+
+    #[test]
+    fn follows_audio_chains_to_an_output() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 osc~ 440;
+#X obj 10 40 lop~ 1000;
+#X obj 10 70 *~ 0.5;
+#X obj 10 100 dac~;
+#X obj 100 10 phasor~ 2;
+#X obj 100 40 *~ 0.5;
+#X obj 100 70 hip~ 5;
+#X obj 200 10 noise~;
+#X obj 200 40 pd fx;
+#X obj 300 10 samplerate~;
+#X connect 0 0 1 0;
+#X connect 1 0 2 0;
+#X connect 2 0 3 0;
+#X connect 4 0 5 0;
+#X connect 5 0 6 0;
+#X connect 5 0 2 1;
+#X connect 7 0 8 0;
+",
+        )
+        .unwrap();
+
+        let findings = lint_patch(&patch, &HashSet::new());
+
+        // [phasor~] reaches [dac~] through the right inlet of the first [*~], the [hip~] it
+        // also feeds is a dead end
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].rule, "unconnected-audio");
+        assert!(findings[0].message.contains("[hip~]"));
+        assert_eq!(findings[0].location.as_deref(), Some("line 8"));
+    }
+
+    #[test]
+    fn reports_the_end_of_a_silent_chain_once() {
+        assert_eq!(
+            rules(
+                "#N canvas 0 50 450 300 12;
+#X obj 10 10 osc~ 440;
+#X obj 10 40 lop~ 1000;
+#X obj 10 70 *~ 0.5;
+#X obj 100 10 dac~;
+#X connect 0 0 1 0;
+#X connect 1 0 2 0;
+",
+                &[],
+            ),
+            vec!["unconnected-audio"]
+        );
+    }
+}
@@ -1,45 +1,154 @@
 use actix_files::Files;
 use actix_web::middleware::Logger;
-use actix_web::{web, App, HttpServer};
+use actix_web::rt::signal::unix::SignalKind;
+use actix_web::{rt, web, App, HttpServer};
 use env_logger::Env;
 use log::info;
 use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 
+mod autofix;
 mod boards;
+mod build_options;
 mod compilation_worker;
+mod compiler_cache;
+mod dependency_checks;
 mod env_config;
+mod failure_explainer;
+mod heavy_compat;
+mod leases;
+mod lint;
+mod memory_estimate;
+mod memory_layout;
+mod parameter_mapping;
+mod parameters;
+mod patch_edits;
+mod patch_files;
+mod patch_graph;
 mod patches;
+mod pd_parser;
+mod remote_worker;
+mod resource_usage;
+mod retarget;
 mod routes;
+mod samples;
+mod self_test;
+mod shutdown;
+mod toolchains;
 mod upload;
+mod worker_supervisor;
 
 use crate::compilation_worker::init_compilation_worker;
-use crate::env_config::get_env_config;
+use crate::dependency_checks::{log_dependency_checks, spawn_dependency_checks, DependencyStore};
+use crate::env_config::{get_env_config, AppMode};
+use crate::leases::spawn_lease_reaper;
+use crate::remote_worker::run_remote_worker;
 use crate::routes::{
-    about_route, get_patch_by_id_route, index_route, list_patches_route, liveness_probe_route,
-    patch_page_route, readiness_probe_route, upload_route,
+    about_route, admin_stats_route, get_group_by_id_route, get_patch_by_id_route, group_page_route,
+    index_route, lint_route, list_patches_route, list_toolchains_route, liveness_probe_route,
+    patch_graph_route, patch_page_route, patch_thumbnail_route, readiness_probe_route,
+    retarget_patch_route, self_test_route, upload_route, worker_artifact_route,
+    worker_complete_route, worker_file_route, worker_heartbeat_route, worker_lease_route,
+    worker_release_route,
 };
+use crate::self_test::{spawn_self_test, SelfTestStore};
+use crate::shutdown::save_queue;
+use crate::toolchains::get_toolchains;
+
+/// Remote workers upload compiled programs and source archives, which can be several megabytes
+const MAX_ARTIFACT_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
 
     // Make sure we have configured our env correctly
-    let _ = get_env_config();
+    let env_config = get_env_config();
+    let _ = get_toolchains();
+
+    if let AppMode::Worker = env_config.mode {
+        log_dependency_checks(&env_config).await;
+
+        let worker_cancel = CancellationToken::new();
+
+        let worker_join_handle = tokio::spawn(run_remote_worker(worker_cancel.clone()));
+
+        let mut terminate = rt::signal::unix::signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            result = rt::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        };
+
+        info!("Stopping remote worker...");
+        worker_cancel.cancel();
+        worker_join_handle.await.unwrap();
+
+        info!("All processes shut down gracefully.");
+
+        return Ok(());
+    }
 
-    let (patches_store, worker_join_handle, worker_cancel) = init_compilation_worker();
+    let (patches_store, worker_health, worker_join_handle, worker_cancel) =
+        init_compilation_worker();
+
+    // Remote workers are only allowed to lease jobs when a worker token is configured
+    let lease_reaper_join_handle = env_config.worker_token.as_ref().map(|_| {
+        tokio::spawn(spawn_lease_reaper(
+            Arc::clone(&patches_store),
+            worker_cancel.clone(),
+        ))
+    });
+
+    let dependency_store = Arc::new(DependencyStore::default());
+    let dependency_checks_join_handle = tokio::spawn(spawn_dependency_checks(
+        Arc::clone(&dependency_store),
+        worker_cancel.clone(),
+    ));
+
+    let self_test_store = Arc::new(SelfTestStore::default());
+    let self_test_join_handle = if env_config.self_test_enabled {
+        Some(tokio::spawn(spawn_self_test(
+            Arc::clone(&self_test_store),
+            worker_cancel.clone(),
+        )))
+    } else {
+        None
+    };
+
+    let patches_store_for_shutdown = Arc::clone(&patches_store);
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::from(Arc::clone(&patches_store)))
+            .app_data(web::Data::from(Arc::clone(&worker_health)))
+            .app_data(web::Data::from(Arc::clone(&self_test_store)))
+            .app_data(web::Data::from(Arc::clone(&dependency_store)))
+            .app_data(web::PayloadConfig::new(MAX_ARTIFACT_UPLOAD_BYTES))
             .wrap(Logger::default())
             .service(Files::new("/static", "./public/static").use_etag(true))
             .service(Files::new("/downloads", "./workspace/downloads"))
             .service(index_route)
             .service(about_route)
             .service(patch_page_route)
+            .service(patch_graph_route)
+            .service(patch_thumbnail_route)
+            .service(group_page_route)
             .service(upload_route)
             .service(list_patches_route)
+            .service(admin_stats_route)
             .service(get_patch_by_id_route)
+            .service(get_group_by_id_route)
+            .service(retarget_patch_route)
+            .service(lint_route)
+            .service(list_toolchains_route)
+            .service(self_test_route)
+            .service(worker_lease_route)
+            .service(worker_heartbeat_route)
+            .service(worker_file_route)
+            .service(worker_artifact_route)
+            .service(worker_complete_route)
+            .service(worker_release_route)
             .service(liveness_probe_route)
             .service(readiness_probe_route)
     })
@@ -47,10 +156,26 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await?;
 
+    info!(
+        "HTTP server stopped, draining the compilation worker ({}s deadline)...",
+        env_config.shutdown_drain_secs
+    );
     worker_cancel.cancel();
 
     worker_join_handle.await.unwrap();
 
+    if let Some(lease_reaper_join_handle) = lease_reaper_join_handle {
+        lease_reaper_join_handle.await.unwrap();
+    }
+
+    if let Some(self_test_join_handle) = self_test_join_handle {
+        self_test_join_handle.await.unwrap();
+    }
+
+    dependency_checks_join_handle.await.unwrap();
+
+    save_queue(&patches_store_for_shutdown, &env_config);
+
     info!("All processes shut down gracefully.");
 
     Ok(())
@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+
+use crate::memory_layout::MemoryLayout;
+use crate::pd_parser::{Atom, Canvas, Element, ElementKind, ElementRef, PdPatch};
+
+/// Heavy stores every sample and table value as a 32-bit float
+const BYTES_PER_VALUE: usize = 4;
+
+/// Size Pd gives a `[table]` without one
+const DEFAULT_TABLE_SIZE: usize = 100;
+
+/// Rough size of the code pd2dsy generates for a small patch, before any saved array contents
+const BASE_PROGRAM_BYTES: usize = 96 * 1024;
+
+/// Layouts from the least to the most demanding, in the order they are suggested
+const LAYOUTS: [MemoryLayout; 3] = [
+    MemoryLayout::Flash,
+    MemoryLayout::BootSram,
+    MemoryLayout::BootQspi,
+];
+
+/// A delay line, array or table that Heavy allocates when the patch starts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryAllocation {
+    pub object: String,
+    pub name: String,
+    pub bytes: usize,
+    pub location: String,
+}
+
+impl MemoryAllocation {
+    pub fn kb(&self) -> usize {
+        self.bytes.div_ceil(1024)
+    }
+}
+
+/// How much memory a patch needs, worked out from the patch before it is compiled
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryEstimate {
+    pub allocations: Vec<MemoryAllocation>,
+    pub heap_bytes: usize,
+    /// Generated code plus the array contents saved in the patch, which are built into the program
+    pub program_bytes: usize,
+    pub heap_budget_bytes: usize,
+    pub program_budget_bytes: usize,
+    /// The least demanding layout the patch fits in, `None` if it is too big for all of them
+    pub suggested_layout: Option<MemoryLayout>,
+}
+
+impl MemoryEstimate {
+    pub fn fits(&self) -> bool {
+        self.heap_bytes <= self.heap_budget_bytes && self.program_bytes <= self.program_budget_bytes
+    }
+
+    pub fn heap_kb(&self) -> usize {
+        self.heap_bytes.div_ceil(1024)
+    }
+
+    pub fn heap_budget_kb(&self) -> usize {
+        self.heap_budget_bytes / 1024
+    }
+
+    pub fn program_kb(&self) -> usize {
+        self.program_bytes.div_ceil(1024)
+    }
+
+    pub fn program_budget_kb(&self) -> usize {
+        self.program_budget_bytes / 1024
+    }
+}
+
+/// Add up the statically allocated buffers in the patch and compare them to what the layout can hold.
+/// Abstractions are not counted, since how often they are instantiated isn't known here.
+pub fn estimate_memory(
+    patch: &PdPatch,
+    memory_layout: &MemoryLayout,
+    sample_rate: u32,
+) -> MemoryEstimate {
+    let mut allocations = vec![];
+    let mut saved_bytes = 0;
+
+    for element_ref in patch.elements() {
+        if let Some(allocation) = object_allocation(&element_ref, sample_rate) {
+            allocations.push(allocation);
+        }
+    }
+
+    collect_arrays(&patch.root, &mut vec![], &mut allocations, &mut saved_bytes);
+
+    // Sizes come from the patch, so saturate instead of overflowing on absurd ones
+    let heap_bytes = allocations.iter().fold(0, |total: usize, allocation| {
+        total.saturating_add(allocation.bytes)
+    });
+    let program_bytes = BASE_PROGRAM_BYTES.saturating_add(saved_bytes);
+
+    let suggested_layout = LAYOUTS
+        .iter()
+        .find(|layout| {
+            heap_bytes <= layout.heap_budget_bytes()
+                && program_bytes <= layout.program_budget_bytes()
+        })
+        .cloned();
+
+    MemoryEstimate {
+        allocations,
+        heap_bytes,
+        program_bytes,
+        heap_budget_bytes: memory_layout.heap_budget_bytes(),
+        program_budget_bytes: memory_layout.program_budget_bytes(),
+        suggested_layout,
+    }
+}
+
+fn float_arg(args: &[Atom], index: usize) -> Option<f64> {
+    match args.get(index) {
+        Some(Atom::Float(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+/// `[delwrite~ name ms]` and `[table name size]`
+fn object_allocation(element_ref: &ElementRef, sample_rate: u32) -> Option<MemoryAllocation> {
+    let (name, args) = match &element_ref.element.kind {
+        ElementKind::Object { name, args } => (name.as_str(), args),
+        _ => return None,
+    };
+
+    let values = match name {
+        "delwrite~" => {
+            let ms = float_arg(args, 1).unwrap_or(0.0).max(0.0);
+            (ms * sample_rate as f64 / 1000.0).ceil() as usize
+        }
+        "table" => float_arg(args, 1)
+            .map(|size| size.max(0.0) as usize)
+            .unwrap_or(DEFAULT_TABLE_SIZE),
+        _ => return None,
+    };
+
+    Some(MemoryAllocation {
+        object: name.to_string(),
+        name: args.first().map(Atom::to_string).unwrap_or_default(),
+        bytes: values.saturating_mul(BYTES_PER_VALUE),
+        location: element_ref.location(),
+    })
+}
+
+/// Graph arrays live on the heap like tables, and the ones that save their contents
+/// also need those contents in the program to start from
+fn collect_arrays(
+    canvas: &Canvas,
+    canvas_path: &mut Vec<String>,
+    allocations: &mut Vec<MemoryAllocation>,
+    saved_bytes: &mut usize,
+) {
+    for element in canvas.elements.iter() {
+        if let ElementKind::Subpatch { canvas: subpatch }
+        | ElementKind::Graph { canvas: subpatch } = &element.kind
+        {
+            let graph_location = location(element, canvas_path);
+            canvas_path.push(subpatch.name.clone().unwrap_or_default());
+
+            for array in subpatch.arrays.iter() {
+                let bytes = array.size.saturating_mul(BYTES_PER_VALUE);
+                if array.save_contents {
+                    *saved_bytes = saved_bytes.saturating_add(bytes);
+                }
+
+                allocations.push(MemoryAllocation {
+                    object: "array".to_string(),
+                    name: array.name.clone(),
+                    bytes,
+                    location: graph_location.clone(),
+                });
+            }
+
+            collect_arrays(subpatch, canvas_path, allocations, saved_bytes);
+            canvas_path.pop();
+        }
+    }
+}
+
+fn location(element: &Element, canvas_path: &[String]) -> String {
+    ElementRef {
+        element,
+        canvas_path: canvas_path.to_vec(),
+    }
+    .location()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pd_parser::parse_patch;
+
+    #[test]
+    fn saturates_instead_of_overflowing() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 delwrite~ a 1e300;
+#X obj 10 40 delwrite~ b 1e300;
+#X obj 10 70 table c 1e300;
+",
+        )
+        .unwrap();
+
+        let estimate = estimate_memory(&patch, &MemoryLayout::Flash, 48000);
+
+        assert_eq!(estimate.heap_bytes, usize::MAX);
+        assert!(!estimate.fits());
+        assert!(estimate.suggested_layout.is_none());
+    }
+
+    #[test]
+    fn adds_up_delay_lines_and_tables() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 delwrite~ a 1000;
+#X obj 10 40 table b;
+",
+        )
+        .unwrap();
+
+        let estimate = estimate_memory(&patch, &MemoryLayout::Flash, 48000);
+
+        assert_eq!(
+            estimate.heap_bytes,
+            (48000 + DEFAULT_TABLE_SIZE) * BYTES_PER_VALUE
+        );
+        assert_eq!(estimate.program_bytes, BASE_PROGRAM_BYTES);
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+// Every supported board is built on the Daisy Seed, so they share its memory
+
+/// Internal flash, which holds the whole program when there is no bootloader
+const FLASH_BYTES: usize = 128 * 1024;
+/// The part of the 512 KB AXI SRAM the bootloader can load a program into
+const BOOT_SRAM_BYTES: usize = 480 * 1024;
+/// QSPI flash, minus the part the bootloader keeps for itself
+const BOOT_QSPI_BYTES: usize = 7936 * 1024;
+/// What is left of the AXI SRAM for the heap once the program's own data is in place
+const SRAM_HEAP_BYTES: usize = 384 * 1024;
+const SDRAM_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseMemoryLayoutError;
+
+/// Where the program lives on the Daisy, see the libDaisy `APP_TYPE` docs.
+/// Anything other than `Flash` requires the Daisy bootloader on the board.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum MemoryLayout {
+    #[serde(rename = "flash")]
+    Flash,
+
+    #[serde(rename = "boot_sram")]
+    BootSram,
+
+    #[serde(rename = "boot_qspi")]
+    BootQspi,
+}
+
+impl FromStr for MemoryLayout {
+    type Err = ParseMemoryLayoutError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flash" => Ok(MemoryLayout::Flash),
+            "boot_sram" => Ok(MemoryLayout::BootSram),
+            "boot_qspi" => Ok(MemoryLayout::BootQspi),
+            _ => Err(ParseMemoryLayoutError),
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn to_str(&self) -> String {
+        match self {
+            MemoryLayout::Flash => "flash".to_string(),
+            MemoryLayout::BootSram => "boot_sram".to_string(),
+            MemoryLayout::BootQspi => "boot_qspi".to_string(),
+        }
+    }
+
+    /// Name shown to people in the UI
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            MemoryLayout::Flash => "internal flash",
+            MemoryLayout::BootSram => "BOOT_SRAM",
+            MemoryLayout::BootQspi => "BOOT_QSPI",
+        }
+    }
+
+    /// Value for pd2dsy's `--rom` option
+    pub fn rom_option(&self) -> &'static str {
+        match self {
+            MemoryLayout::Flash => "FLASH",
+            MemoryLayout::BootSram => "SRAM",
+            MemoryLayout::BootQspi => "QSPI",
+        }
+    }
+
+    /// Value for pd2dsy's `--ram` option. When the program is loaded by the
+    /// bootloader, the internal SRAM is taken up by code, so the heap moves out to SDRAM.
+    pub fn ram_option(&self) -> &'static str {
+        match self {
+            MemoryLayout::Flash => "SRAM",
+            MemoryLayout::BootSram | MemoryLayout::BootQspi => "SDRAM",
+        }
+    }
+
+    /// Largest program this layout can hold
+    pub fn program_budget_bytes(&self) -> usize {
+        match self {
+            MemoryLayout::Flash => FLASH_BYTES,
+            MemoryLayout::BootSram => BOOT_SRAM_BYTES,
+            MemoryLayout::BootQspi => BOOT_QSPI_BYTES,
+        }
+    }
+
+    /// Room on the heap, where Heavy allocates delay lines and tables
+    pub fn heap_budget_bytes(&self) -> usize {
+        match self {
+            MemoryLayout::Flash => SRAM_HEAP_BYTES,
+            MemoryLayout::BootSram | MemoryLayout::BootQspi => SDRAM_BYTES,
+        }
+    }
+
+    /// The next layout to try when a build does not fit in this one
+    pub fn fallback(&self) -> Option<MemoryLayout> {
+        match self {
+            MemoryLayout::Flash => Some(MemoryLayout::BootSram),
+            MemoryLayout::BootSram => Some(MemoryLayout::BootQspi),
+            MemoryLayout::BootQspi => None,
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::boards::{BoardControl, ParameterDirection};
+use crate::parameters::{extract_parameters, DEFAULT_PARAMETER_MAX, DEFAULT_PARAMETER_MIN};
+use crate::patch_edits::PatchEdits;
+use crate::pd_parser::{Atom, ElementKind, PdPatch};
+
+/// Renames one of the patch's parameters to the name pd2dsy uses for a control
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ParameterMapping {
+    pub parameter: String,
+    /// Like `knob1`, or `sw1_press` for one of a control's other parameters
+    pub control: String,
+    /// Replaces the default value in the parameter's `@hv_param` range
+    #[serde(default)]
+    pub default: Option<f64>,
+}
+
+/// Parse mappings written like `cutoff=knob1, resonance=knob2:0.25`, separated by commas or lines
+pub fn parse_parameter_mappings(value: &str) -> Result<Vec<ParameterMapping>> {
+    value
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|mapping| !mapping.is_empty())
+        .map(|mapping| {
+            let invalid = || anyhow!("Invalid parameter mapping \"{mapping}\", expected parameter=control or parameter=control:default");
+
+            let (parameter, target) = mapping.split_once('=').ok_or_else(invalid)?;
+            let (control, default) = match target.split_once(':') {
+                Some((control, default)) => {
+                    (control, Some(default.trim().parse::<f64>().map_err(|_| invalid())?))
+                }
+                None => (target, None),
+            };
+
+            let (parameter, control) = (parameter.trim(), control.trim());
+            if parameter.is_empty() || control.is_empty() {
+                return Err(invalid());
+            }
+
+            Ok(ParameterMapping {
+                parameter: parameter.to_string(),
+                control: control.to_string(),
+                default,
+            })
+        })
+        .collect()
+}
+
+/// Rename the mapped parameters in every `[send]` and `[receive]` that uses them,
+/// after checking that each one lands on a control the board has
+pub fn apply_parameter_mappings(
+    contents: &str,
+    patch: &PdPatch,
+    mappings: &[ParameterMapping],
+    controls: &[BoardControl],
+) -> Result<String> {
+    let parameters = extract_parameters(patch);
+    let control_parameters: HashSet<_> = controls
+        .iter()
+        .flat_map(BoardControl::parameter_names)
+        .collect();
+
+    let mut targets: HashSet<&str> = HashSet::new();
+
+    for mapping in mappings {
+        let mapped_parameters: Vec<_> = parameters
+            .iter()
+            .filter(|parameter| parameter.name == mapping.parameter)
+            .collect();
+
+        if mapped_parameters.is_empty() {
+            return Err(anyhow!(
+                "The patch has no parameter called \"{}\" to map, expose it with [r {} @hv_param]",
+                mapping.parameter,
+                mapping.parameter
+            ));
+        }
+
+        for parameter in mapped_parameters.iter() {
+            if !control_parameters.contains(&(mapping.control.clone(), parameter.direction)) {
+                return Err(anyhow!(
+                    "\"{}\" can't be mapped to \"{}\", the board has no control by that name it could {}",
+                    mapping.parameter,
+                    mapping.control,
+                    match parameter.direction {
+                        ParameterDirection::Input => "be read from",
+                        ParameterDirection::Output => "be written to",
+                    }
+                ));
+            }
+
+            if let Some(default) = mapping.default {
+                if default < parameter.min || default > parameter.max {
+                    return Err(anyhow!(
+                        "The default {} for \"{}\" is outside of its range, {} to {}",
+                        default,
+                        mapping.parameter,
+                        parameter.min,
+                        parameter.max
+                    ));
+                }
+            }
+        }
+
+        let already_used = parameters.iter().any(|parameter| {
+            parameter.name == mapping.control
+                && !mappings
+                    .iter()
+                    .any(|other| other.parameter == parameter.name)
+        });
+
+        if already_used || !targets.insert(&mapping.control) {
+            return Err(anyhow!(
+                "More than one parameter would be mapped to \"{}\"",
+                mapping.control
+            ));
+        }
+    }
+
+    let mut edits = PatchEdits::default();
+
+    for element_ref in patch.elements() {
+        let element = element_ref.element;
+        let args = match &element.kind {
+            ElementKind::Object { name, args }
+                if matches!(name.as_str(), "r" | "receive" | "s" | "send") =>
+            {
+                args
+            }
+            _ => continue,
+        };
+
+        let mapping = match args.first() {
+            Some(Atom::Symbol(name)) => mappings.iter().find(|mapping| mapping.parameter == *name),
+            _ => None,
+        };
+
+        if let Some(mapping) = mapping {
+            // `#X obj x y name parameter @hv_param min max default`
+            let mut tokens = patch.records[element.position.record].tokens.clone();
+            tokens[5] = mapping.control.clone();
+
+            let is_exposed = matches!(args.get(1), Some(Atom::Symbol(flag)) if flag == "@hv_param");
+            if let (true, Some(default)) = (is_exposed, mapping.default) {
+                set_default(&mut tokens, default);
+            }
+
+            edits.replaced.insert(element.position.record, tokens);
+        }
+    }
+
+    Ok(edits.apply(contents, &patch.records))
+}
+
+/// Write the default into an `@hv_param` range, filling in Heavy's range if there was none
+fn set_default(tokens: &mut Vec<String>, default: f64) {
+    // Keep a box width like `, f 10` at the end
+    let width = match tokens.as_slice() {
+        [.., comma, f, _] if comma == "," && f == "f" => tokens.split_off(tokens.len() - 3),
+        _ => vec![],
+    };
+
+    let range_value = |tokens: &[String], index: usize, fallback: f64| {
+        tokens
+            .get(index)
+            .cloned()
+            .unwrap_or_else(|| fallback.to_string())
+    };
+    let min = range_value(tokens, 7, DEFAULT_PARAMETER_MIN);
+    let max = range_value(tokens, 8, DEFAULT_PARAMETER_MAX);
+
+    tokens.truncate(7);
+    tokens.extend([min, max, default.to_string()]);
+    tokens.extend(width);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boards::Board;
+    use crate::pd_parser::parse_patch;
+
+    const PATCH: &str = "#N canvas 0 50 450 300 12;
+#X obj 10 10 r cutoff @hv_param 20 2000 440;
+#X obj 10 40 r resonance @hv_param, f 12;
+#X obj 10 70 receive cutoff;
+#X obj 10 100 s light @hv_param;
+#X obj 10 130 r knob3 @hv_param;
+";
+
+    fn apply(mappings: &str) -> Result<String> {
+        let patch = parse_patch(PATCH).unwrap();
+
+        apply_parameter_mappings(
+            PATCH,
+            &patch,
+            &parse_parameter_mappings(mappings).unwrap(),
+            &Board::Patch.controls(),
+        )
+    }
+
+    #[test]
+    fn parses_mappings_separated_by_commas_and_lines() {
+        let mappings =
+            parse_parameter_mappings("cutoff=knob1, resonance = knob2:0.25\n\nlight=gate_out")
+                .unwrap();
+
+        assert_eq!(
+            mappings,
+            vec![
+                ParameterMapping {
+                    parameter: "cutoff".to_string(),
+                    control: "knob1".to_string(),
+                    default: None,
+                },
+                ParameterMapping {
+                    parameter: "resonance".to_string(),
+                    control: "knob2".to_string(),
+                    default: Some(0.25),
+                },
+                ParameterMapping {
+                    parameter: "light".to_string(),
+                    control: "gate_out".to_string(),
+                    default: None,
+                },
+            ]
+        );
+
+        for invalid in ["cutoff", "=knob1", "cutoff=", "cutoff=knob1:loud"] {
+            assert!(parse_parameter_mappings(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn renames_every_send_and_receive_and_sets_defaults() {
+        assert_eq!(
+            apply("cutoff=knob1:1000, resonance=knob2:0.25, light=gate_out").unwrap(),
+            "#N canvas 0 50 450 300 12;
+#X obj 10 10 r knob1 @hv_param 20 2000 1000;
+#X obj 10 40 r knob2 @hv_param 0 1 0.25 , f 12;
+#X obj 10 70 receive knob1;
+#X obj 10 100 s gate_out @hv_param;
+#X obj 10 130 r knob3 @hv_param;
+"
+        );
+    }
+
+    #[test]
+    fn rejects_parameters_that_are_not_in_the_patch() {
+        let err = apply("volume=knob1").unwrap_err();
+
+        assert!(err
+            .to_string()
+            .starts_with("The patch has no parameter called \"volume\""));
+    }
+
+    #[test]
+    fn rejects_controls_the_board_does_not_have() {
+        // The Patch has four knobs, and its gate output can't be read
+        for mappings in ["cutoff=knob9", "cutoff=gate_out", "light=knob1"] {
+            let err = apply(mappings).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("the board has no control by that name"),
+                "{mappings}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_defaults_out_of_range_and_shared_controls() {
+        assert!(apply("cutoff=knob1:5").is_err());
+        assert!(apply("cutoff=knob1, resonance=knob1").is_err());
+        // knob3 is already taken by a parameter that keeps its name
+        assert!(apply("cutoff=knob3").is_err());
+        assert!(apply("cutoff=knob3, knob3=knob4").is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::boards::{get_custom_board_controls, Board, BoardControl, ParameterDirection};
+use crate::pd_parser::{Atom, ElementKind, PdPatch};
+
+/// Heavy's defaults when `@hv_param` is not followed by a range
+pub const DEFAULT_PARAMETER_MIN: f64 = 0.0;
+pub const DEFAULT_PARAMETER_MAX: f64 = 1.0;
+const DEFAULT_PARAMETER_DEFAULT: f64 = 0.5;
+
+/// A `[r name @hv_param min max default]` or `[s name @hv_param]` in the patch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExposedParameter {
+    pub name: String,
+    pub direction: ParameterDirection,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+    pub location: String,
+}
+
+/// How a patch's exposed parameters line up with the controls on its board
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ParameterReport {
+    pub matched: Vec<ExposedParameter>,
+    /// Parameters that no control on the board will ever read or write
+    pub unmatched: Vec<ExposedParameter>,
+    /// Controls on the board that the patch doesn't use
+    pub unused_controls: Vec<String>,
+}
+
+/// The board's controls, reading a Seed's from its board definition
+pub fn board_controls(
+    board: &Board,
+    board_def_contents: Option<&str>,
+) -> Result<Vec<BoardControl>> {
+    match (board, board_def_contents) {
+        (Board::SeedCustomJson, Some(board_def_contents)) => {
+            get_custom_board_controls(board_def_contents)
+        }
+        _ => Ok(board.controls()),
+    }
+}
+
+/// Match the patch's parameters against a board's controls
+pub fn check_parameters(patch: &PdPatch, controls: &[BoardControl]) -> ParameterReport {
+    match_parameters(extract_parameters(patch), controls)
+}
+
+/// Find every receive and send that is exposed to the hardware with `@hv_param`
+pub fn extract_parameters(patch: &PdPatch) -> Vec<ExposedParameter> {
+    patch
+        .elements()
+        .iter()
+        .filter_map(|element_ref| {
+            let (name, args) = match &element_ref.element.kind {
+                ElementKind::Object { name, args } => (name.as_str(), args),
+                _ => return None,
+            };
+
+            let direction = match name {
+                "r" | "receive" => ParameterDirection::Input,
+                "s" | "send" => ParameterDirection::Output,
+                _ => return None,
+            };
+
+            let (parameter_name, rest) = match args.as_slice() {
+                [Atom::Symbol(parameter_name), Atom::Symbol(flag), rest @ ..]
+                    if flag == "@hv_param" =>
+                {
+                    (parameter_name, rest)
+                }
+                _ => return None,
+            };
+
+            let range_value = |index: usize, fallback: f64| match rest.get(index) {
+                Some(Atom::Float(value)) => *value,
+                _ => fallback,
+            };
+
+            Some(ExposedParameter {
+                name: parameter_name.clone(),
+                direction,
+                min: range_value(0, DEFAULT_PARAMETER_MIN),
+                max: range_value(1, DEFAULT_PARAMETER_MAX),
+                default: range_value(2, DEFAULT_PARAMETER_DEFAULT),
+                location: element_ref.location(),
+            })
+        })
+        .collect()
+}
+
+/// Compare the patch's parameters to the names pd2dsy generates for the board's controls
+pub fn match_parameters(
+    parameters: Vec<ExposedParameter>,
+    controls: &[BoardControl],
+) -> ParameterReport {
+    // Parameter name and direction, to the control it belongs to
+    let control_parameters: HashMap<(String, ParameterDirection), &str> = controls
+        .iter()
+        .flat_map(|control| {
+            control
+                .parameter_names()
+                .into_iter()
+                .map(move |parameter_name| (parameter_name, control.name.as_str()))
+        })
+        .collect();
+
+    let mut report = ParameterReport::default();
+    let mut used_controls: Vec<&str> = vec![];
+
+    for parameter in parameters {
+        match control_parameters.get(&(parameter.name.clone(), parameter.direction)) {
+            Some(control_name) => {
+                used_controls.push(control_name);
+                report.matched.push(parameter);
+            }
+            None => report.unmatched.push(parameter),
+        }
+    }
+
+    report.unused_controls = controls
+        .iter()
+        .filter(|control| !used_controls.contains(&control.name.as_str()))
+        .map(|control| control.name.clone())
+        .collect();
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pd_parser::parse_patch;
+
+    const PATCH: &str = "#N canvas 0 50 450 300 12;
+#X obj 10 10 r knob1 @hv_param 20 2000 440;
+#X obj 10 40 receive sw1_rise @hv_param;
+#X obj 10 70 r knob3 @hv_param -1;
+#X obj 10 100 r led1_red @hv_param;
+#X obj 10 130 r plain;
+#X obj 10 160 s led1_red @hv_param;
+#X obj 10 190 osc~ 440;
+";
+
+    fn names(parameters: &[ExposedParameter]) -> Vec<&str> {
+        parameters
+            .iter()
+            .map(|parameter| parameter.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn parses_ranges_and_falls_back_to_heavys_defaults() {
+        let parameters = extract_parameters(&parse_patch(PATCH).unwrap());
+
+        assert_eq!(
+            names(&parameters),
+            vec!["knob1", "sw1_rise", "knob3", "led1_red", "led1_red"]
+        );
+
+        let ranges: Vec<(f64, f64, f64)> = parameters
+            .iter()
+            .map(|parameter| (parameter.min, parameter.max, parameter.default))
+            .collect();
+        assert_eq!(
+            ranges[..3],
+            [(20.0, 2000.0, 440.0), (0.0, 1.0, 0.5), (-1.0, 1.0, 0.5)]
+        );
+
+        assert_eq!(parameters[0].direction, ParameterDirection::Input);
+        assert_eq!(parameters[4].direction, ParameterDirection::Output);
+        assert_eq!(parameters[4].location, "line 7");
+    }
+
+    #[test]
+    fn matches_parameters_to_controls_by_name_and_direction() {
+        let report = check_parameters(&parse_patch(PATCH).unwrap(), &Board::Pod.controls());
+
+        assert_eq!(
+            names(&report.matched),
+            vec!["knob1", "sw1_rise", "led1_red"]
+        );
+        assert_eq!(report.matched[2].direction, ParameterDirection::Output);
+
+        // The Pod has only two knobs, and its LEDs can't be read
+        assert_eq!(names(&report.unmatched), vec!["knob3", "led1_red"]);
+        assert_eq!(report.unmatched[1].direction, ParameterDirection::Input);
+
+        assert_eq!(
+            report.unused_controls,
+            vec!["knob2", "sw2", "encoder", "led2"]
+        );
+    }
+
+    #[test]
+    fn reads_a_seeds_controls_from_its_board_definition() {
+        let board_def = r#"{"components": {"pot": {"component": "AnalogControl"}}}"#;
+
+        let controls = board_controls(&Board::SeedCustomJson, Some(board_def)).unwrap();
+        assert_eq!(controls.len(), 1);
+        assert_eq!(controls[0].name, "pot");
+
+        assert!(board_controls(&Board::SeedCustomJson, Some("not json")).is_err());
+        assert_eq!(
+            board_controls(&Board::Pod, None).unwrap().len(),
+            Board::Pod.controls().len()
+        );
+    }
+}
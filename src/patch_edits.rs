@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::pd_parser::Record;
+
+/// Record-level changes to a patch file, applied in one go so record indices stay valid while they are collected
+#[derive(Default)]
+pub struct PatchEdits {
+    pub replaced: HashMap<usize, Vec<String>>,
+    pub removed: HashSet<usize>,
+    /// New records to write before the record at the given index
+    pub inserted: HashMap<usize, Vec<Vec<String>>>,
+}
+
+impl PatchEdits {
+    /// Write the patch back out, copying untouched records exactly as they were uploaded
+    pub fn apply(&self, contents: &str, records: &[Record]) -> String {
+        let mut edited = String::with_capacity(contents.len());
+        let mut cursor = 0;
+
+        let write_inserted = |edited: &mut String, record_index: usize| {
+            for tokens in self.inserted.get(&record_index).into_iter().flatten() {
+                edited.push_str(&tokens.join(" "));
+                edited.push_str(";\n");
+            }
+        };
+
+        for (record_index, record) in records.iter().enumerate() {
+            edited.push_str(&contents[cursor..record.start]);
+            write_inserted(&mut edited, record_index);
+            cursor = record.end;
+
+            if self.removed.contains(&record_index) {
+                // Drop the line break after the record too, so no blank line is left behind
+                if contents[cursor..].starts_with('\n') {
+                    cursor += 1;
+                }
+            } else if let Some(tokens) = self.replaced.get(&record_index) {
+                edited.push_str(&tokens.join(" "));
+                edited.push(';');
+            } else {
+                edited.push_str(&contents[record.start..record.end]);
+            }
+        }
+
+        edited.push_str(&contents[cursor..]);
+
+        if self.inserted.contains_key(&records.len()) {
+            if !edited.ends_with('\n') {
+                edited.push('\n');
+            }
+            write_inserted(&mut edited, records.len());
+        }
+
+        edited
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pd_parser::parse_patch;
+
+    /// Line breaks inside records, tabs, escapes, CRLF and no final line break
+    const UNUSUAL_FORMATTING: &str = "#N canvas 0 50 450 300 12;\r\n#X obj 10 10 osc~\n440;\r\n#X msg  10\t40 \\; pd dsp 1 \\, foo \\$1;\n#X text 10 70 a comment that Pd wrapped\nover two lines \\, with a comma, f 30;\n#X connect 0 0 1 0;";
+
+    #[test]
+    fn round_trips_untouched_records_byte_for_byte() {
+        let patch = parse_patch(UNUSUAL_FORMATTING).unwrap();
+
+        assert_eq!(
+            PatchEdits::default().apply(UNUSUAL_FORMATTING, &patch.records),
+            UNUSUAL_FORMATTING
+        );
+    }
+
+    #[test]
+    fn replaces_removes_and_inserts_records() {
+        let contents = "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~ 440;\n#X obj 10 40 dac~;\n#X connect 0 0 1 0;\n";
+        let patch = parse_patch(contents).unwrap();
+
+        let tokens = |text: &str| text.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let mut edits = PatchEdits::default();
+        edits.replaced.insert(1, tokens("#X obj 10 10 phasor~ 220"));
+        edits.removed.insert(3);
+        edits
+            .inserted
+            .insert(2, vec![tokens("#X obj 10 25 *~ 0.5")]);
+        edits.inserted.insert(
+            patch.records.len(),
+            vec![tokens("#X connect 0 0 1 0"), tokens("#X connect 1 0 2 0")],
+        );
+
+        assert_eq!(
+            edits.apply(contents, &patch.records),
+            "#N canvas 0 50 450 300 12;\n#X obj 10 10 phasor~ 220;\n#X obj 10 25 *~ 0.5;\n#X obj 10 40 dac~;\n#X connect 0 0 1 0;\n#X connect 1 0 2 0;\n"
+        );
+    }
+
+    #[test]
+    fn inserts_at_the_end_without_a_final_line_break() {
+        let contents = "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~;";
+        let patch = parse_patch(contents).unwrap();
+
+        let mut edits = PatchEdits::default();
+        edits.inserted.insert(
+            patch.records.len(),
+            vec![vec![
+                "#X".to_string(),
+                "obj".to_string(),
+                "10".to_string(),
+                "40".to_string(),
+                "dac~".to_string(),
+            ]],
+        );
+
+        assert_eq!(
+            edits.apply(contents, &patch.records),
+            "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~;\n#X obj 10 40 dac~;\n"
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+use std::path::{Component, Path};
+
+use crate::pd_parser::{Atom, PdPatch};
+
+/// Limits on what an archive may expand to, so a small upload can't fill the disk
+const MAX_FILES: usize = 256;
+const MAX_TOTAL_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Conventional names for a project's main patch, used when none is chosen
+const MAIN_PATCH_NAMES: &[&str] = &["main.pd", "_main.pd"];
+
+/// A file from an upload, with its path inside the project
+#[derive(Debug)]
+pub struct PatchFile {
+    pub path: String,
+    pub contents: Vec<u8>,
+}
+
+impl PatchFile {
+    pub fn is_patch(&self) -> bool {
+        self.path.ends_with(".pd")
+    }
+
+    pub fn is_sample(&self) -> bool {
+        self.path.to_lowercase().ends_with(".wav")
+    }
+
+    /// Directory the file is in, empty for the top of the project
+    pub fn dir(&self) -> &str {
+        match self.path.rsplit_once('/') {
+            Some((dir, _)) => dir,
+            None => "",
+        }
+    }
+}
+
+/// Turn the uploaded files into one project tree, unpacking any zip or tar archives
+pub fn expand_uploaded_files(uploaded_files: Vec<(String, Vec<u8>)>) -> Result<Vec<PatchFile>> {
+    let mut files: Vec<PatchFile> = vec![];
+
+    for (filename, contents) in uploaded_files {
+        let lowercase_filename = filename.to_lowercase();
+
+        if lowercase_filename.ends_with(".zip") {
+            read_zip(contents, &mut files)?;
+        } else if lowercase_filename.ends_with(".tar.gz") || lowercase_filename.ends_with(".tgz") {
+            read_tar(GzDecoder::new(Cursor::new(contents)), &mut files)?;
+        } else if lowercase_filename.ends_with(".tar") {
+            read_tar(Cursor::new(contents), &mut files)?;
+        } else if let Some(path) = sanitize_path(&filename)? {
+            add_file(&mut files, path, contents)?;
+        }
+    }
+
+    if !files.iter().any(PatchFile::is_patch) {
+        return Err(anyhow!("The upload doesn't contain any Pd patches"));
+    }
+
+    Ok(files)
+}
+
+fn read_zip(contents: Vec<u8>, files: &mut Vec<PatchFile>) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(contents))
+        .map_err(|err| anyhow!("Could not read the zip archive: {err}"))?;
+
+    for index in 0..archive.len() {
+        let entry = archive
+            .by_index(index)
+            .map_err(|err| anyhow!("Could not read the zip archive: {err}"))?;
+
+        if !entry.is_file() {
+            continue;
+        }
+
+        if let Some(path) = sanitize_path(entry.name())? {
+            let contents = read_limited(entry, files)?;
+            add_file(files, path, contents)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_tar<R: Read>(reader: R, files: &mut Vec<PatchFile>) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive
+        .entries()
+        .map_err(|err| anyhow!("Could not read the tar archive: {err}"))?;
+
+    for entry in entries {
+        let entry = entry.map_err(|err| anyhow!("Could not read the tar archive: {err}"))?;
+
+        // Links could point anywhere, so only regular files are taken
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let entry_path = entry
+            .path()
+            .map_err(|err| anyhow!("Could not read the tar archive: {err}"))?
+            .to_string_lossy()
+            .to_string();
+
+        if let Some(path) = sanitize_path(&entry_path)? {
+            let contents = read_limited(entry, files)?;
+            add_file(files, path, contents)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read an archive entry without going over the total size limit, whatever its header claims
+fn read_limited<R: Read>(entry: R, files: &[PatchFile]) -> Result<Vec<u8>> {
+    let used_bytes: u64 = files.iter().map(|file| file.contents.len() as u64).sum();
+    let remaining_bytes = MAX_TOTAL_BYTES.saturating_sub(used_bytes);
+
+    let mut contents = vec![];
+    entry.take(remaining_bytes + 1).read_to_end(&mut contents)?;
+
+    if contents.len() as u64 > remaining_bytes {
+        return Err(anyhow!(
+            "The upload is larger than {} MB once unpacked",
+            MAX_TOTAL_BYTES / 1024 / 1024
+        ));
+    }
+
+    Ok(contents)
+}
+
+fn add_file(files: &mut Vec<PatchFile>, path: String, contents: Vec<u8>) -> Result<()> {
+    if files.iter().any(|file| file.path == path) {
+        return Err(anyhow!("The upload contains {path} more than once"));
+    }
+
+    if files.len() >= MAX_FILES {
+        return Err(anyhow!("The upload contains more than {MAX_FILES} files"));
+    }
+
+    files.push(PatchFile { path, contents });
+
+    Ok(())
+}
+
+/// Normalize a path from an upload so it stays inside the project directory.
+/// Returns `None` for files that are safe to skip, like macOS metadata.
+fn sanitize_path(path: &str) -> Result<Option<String>> {
+    let path = path.replace('\\', "/");
+    let mut parts: Vec<&str> = vec![];
+
+    for component in Path::new(&path).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str().unwrap_or_default()),
+            Component::CurDir => {}
+            _ => return Err(anyhow!("The upload contains an unsafe path: {path}")),
+        }
+    }
+
+    let is_hidden = parts
+        .iter()
+        .any(|part| part.starts_with('.') || *part == "__MACOSX");
+
+    if parts.is_empty() || is_hidden {
+        return Ok(None);
+    }
+
+    Ok(Some(parts.join("/")))
+}
+
+/// Find the main patch, either the one that was asked for or the only likely candidate
+pub fn choose_main_patch(files: &[PatchFile], requested: Option<&str>) -> Result<usize> {
+    if let Some(requested) = requested {
+        return files
+            .iter()
+            .position(|file| file.path == requested)
+            .ok_or_else(|| anyhow!("The main patch {requested} is not in the upload"));
+    }
+
+    let patches: Vec<usize> = (0..files.len())
+        .filter(|index| files[*index].is_patch())
+        .collect();
+
+    if let [only_patch] = patches.as_slice() {
+        return Ok(*only_patch);
+    }
+
+    // Prefer a conventionally named patch closest to the top of the project
+    let conventional_patch = patches
+        .iter()
+        .filter(|index| {
+            let filename = files[**index].path.rsplit('/').next().unwrap_or_default();
+            MAIN_PATCH_NAMES.contains(&filename)
+        })
+        .min_by_key(|index| files[**index].path.matches('/').count());
+
+    match conventional_patch {
+        Some(index) => Ok(*index),
+        None => {
+            let candidates: Vec<&str> = patches
+                .iter()
+                .map(|index| files[*index].path.as_str())
+                .collect();
+
+            Err(anyhow!(
+                "Choose which patch is the main one: {}",
+                candidates.join(", ")
+            ))
+        }
+    }
+}
+
+/// Directories pd2dsy should search for abstractions: the main patch's own directory,
+/// plus any `[declare -path]` in it, as long as they stay inside the project
+pub fn resolve_search_paths(main_file: &PatchFile, main_patch: &PdPatch) -> Result<Vec<String>> {
+    let main_dir = main_file.dir();
+    let mut search_paths = vec![main_dir.to_string()];
+
+    for declaration in main_patch.root.declarations.iter() {
+        for pair in declaration.windows(2) {
+            let declared_path = match pair {
+                [Atom::Symbol(flag), Atom::Symbol(declared_path)] if flag == "-path" => {
+                    declared_path
+                }
+                _ => continue,
+            };
+
+            let search_path = join_inside_project(main_dir, declared_path).ok_or_else(|| {
+                anyhow!("[declare -path {declared_path}] points outside of the uploaded files")
+            })?;
+
+            if !search_paths.contains(&search_path) {
+                search_paths.push(search_path);
+            }
+        }
+    }
+
+    Ok(search_paths)
+}
+
+/// Join a relative path onto a project directory, or `None` if it would leave the project
+fn join_inside_project(dir: &str, relative_path: &str) -> Option<String> {
+    let mut parts: Vec<&str> = dir.split('/').filter(|part| !part.is_empty()).collect();
+
+    for component in Path::new(relative_path).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop()?;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(parts.join("/"))
+}
+
+/// Object names that refer to uploaded abstractions, as seen from the search paths
+pub fn abstraction_names(files: &[PatchFile], search_paths: &[String]) -> HashSet<String> {
+    let mut names = HashSet::new();
+
+    for file in files.iter().filter(|file| file.is_patch()) {
+        let name = file.path.trim_end_matches(".pd");
+
+        // Abstractions can also use others from their own directory
+        names.insert(name.rsplit('/').next().unwrap_or_default().to_string());
+
+        for search_path in search_paths {
+            if search_path.is_empty() {
+                names.insert(name.to_string());
+            } else if let Some(relative_name) = name.strip_prefix(&format!("{search_path}/")) {
+                names.insert(relative_name.to_string());
+            }
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(paths: &[&str]) -> Vec<PatchFile> {
+        paths
+            .iter()
+            .map(|path| PatchFile {
+                path: path.to_string(),
+                contents: vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_paths_inside_the_project() {
+        assert_eq!(
+            sanitize_path("lib\\gain.pd").unwrap().as_deref(),
+            Some("lib/gain.pd")
+        );
+        assert_eq!(
+            sanitize_path("./lib//samples/./kick.wav")
+                .unwrap()
+                .as_deref(),
+            Some("lib/samples/kick.wav")
+        );
+
+        for unsafe_path in [
+            "../main.pd",
+            "lib/../../main.pd",
+            "..\\main.pd",
+            "/etc/passwd",
+            "\\lib\\main.pd",
+        ] {
+            assert!(sanitize_path(unsafe_path).is_err(), "{unsafe_path}");
+        }
+    }
+
+    #[test]
+    fn skips_empty_and_hidden_paths() {
+        for skipped_path in ["", ".", "./", "__MACOSX/main.pd", "lib/.DS_Store"] {
+            assert_eq!(sanitize_path(skipped_path).unwrap(), None, "{skipped_path}");
+        }
+    }
+
+    #[test]
+    fn chooses_the_requested_or_only_patch() {
+        let project = files(&["synth.pd", "kick.wav"]);
+        assert_eq!(choose_main_patch(&project, None).unwrap(), 0);
+
+        let project = files(&["lib/voice.pd", "synth.pd"]);
+        assert_eq!(choose_main_patch(&project, Some("synth.pd")).unwrap(), 1);
+        assert!(choose_main_patch(&project, Some("missing.pd")).is_err());
+    }
+
+    #[test]
+    fn prefers_the_conventional_main_patch_closest_to_the_top() {
+        let project = files(&["lib/main.pd", "voice.pd", "_main.pd", "main.pd"]);
+
+        assert_eq!(choose_main_patch(&project, None).unwrap(), 2);
+    }
+
+    #[test]
+    fn asks_which_patch_is_main_when_it_is_ambiguous() {
+        let project = files(&["synth.pd", "kick.wav", "lib/voice.pd"]);
+
+        assert_eq!(
+            choose_main_patch(&project, None).unwrap_err().to_string(),
+            "Choose which patch is the main one: synth.pd, lib/voice.pd"
+        );
+    }
+}
@@ -0,0 +1,524 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Write as _;
+use tokio::fs;
+
+use crate::env_config::get_env_config;
+use crate::patches::{validate_patch_file_contents, PatchMeta};
+use crate::pd_parser::{Atom, Canvas, Element, ElementKind, PdPatch};
+
+/// Pd's box metrics at its default font size
+const CHAR_WIDTH: i64 = 7;
+const BOX_HEIGHT: i64 = 18;
+const BOX_PADDING: i64 = 4;
+const MIN_BOX_CHARS: i64 = 3;
+const IOLET_WIDTH: i64 = 7;
+const IOLET_HEIGHT: i64 = 2;
+
+/// Space around the outermost boxes
+const MARGIN: i64 = 10;
+
+/// Widest atom box drawn, Pd itself doesn't show anything wider than a screen
+const MAX_ATOM_BOX_CHARS: usize = 1000;
+
+const THUMBNAIL_MAX_WIDTH: u32 = 240;
+const THUMBNAIL_MAX_HEIGHT: u32 = 160;
+
+/// Where an element is drawn, along with its iolets.
+/// Everything is `i64`, so boxes at the far ends of the parser's coordinate range can't overflow.
+struct BoxLayout {
+    x: i64,
+    y: i64,
+    width: i64,
+    height: i64,
+    inlets: usize,
+    outlets: usize,
+    text: String,
+    bordered: bool,
+}
+
+impl BoxLayout {
+    fn inlet_x(&self, inlet: usize) -> i64 {
+        iolet_x(self.x, self.width, inlet, self.inlets)
+    }
+
+    fn outlet_x(&self, outlet: usize) -> i64 {
+        iolet_x(self.x, self.width, outlet, self.outlets)
+    }
+}
+
+fn iolet_x(box_x: i64, box_width: i64, index: usize, count: usize) -> i64 {
+    if count <= 1 {
+        return box_x;
+    }
+
+    box_x + (box_width - IOLET_WIDTH) * index as i64 / (count as i64 - 1)
+}
+
+/// The boxes of a canvas, plus the bounds they cover
+struct CanvasLayout {
+    boxes: Vec<Option<BoxLayout>>,
+    min_x: i64,
+    min_y: i64,
+    max_x: i64,
+    max_y: i64,
+}
+
+impl CanvasLayout {
+    fn new(canvas: &Canvas) -> Self {
+        // Pd doesn't save how many iolets an object has, so count the ones that are connected
+        let mut inlets = vec![0; canvas.elements.len()];
+        let mut outlets = vec![0; canvas.elements.len()];
+        for connection in canvas.connections.iter() {
+            outlets[connection.source] = outlets[connection.source].max(connection.outlet + 1);
+            inlets[connection.sink] = inlets[connection.sink].max(connection.inlet + 1);
+        }
+
+        let boxes: Vec<Option<BoxLayout>> = canvas
+            .elements
+            .iter()
+            .map(|element| layout_box(element, inlets[element.index], outlets[element.index]))
+            .collect();
+
+        let mut layout = CanvasLayout {
+            boxes,
+            min_x: 0,
+            min_y: 0,
+            max_x: 0,
+            max_y: 0,
+        };
+
+        let mut placed = layout.boxes.iter().flatten().peekable();
+        if let Some(first) = placed.peek() {
+            layout.min_x = first.x;
+            layout.min_y = first.y;
+            layout.max_x = first.x + first.width;
+            layout.max_y = first.y + first.height;
+        }
+
+        for layout_box in placed {
+            layout.min_x = layout.min_x.min(layout_box.x);
+            layout.min_y = layout.min_y.min(layout_box.y);
+            layout.max_x = layout.max_x.max(layout_box.x + layout_box.width);
+            layout.max_y = layout.max_y.max(layout_box.y + layout_box.height);
+        }
+
+        layout.min_x -= MARGIN;
+        layout.min_y -= MARGIN;
+        layout.max_x += MARGIN;
+        layout.max_y += MARGIN;
+
+        layout
+    }
+
+    fn width(&self) -> i64 {
+        self.max_x - self.min_x
+    }
+
+    fn height(&self) -> i64 {
+        self.max_y - self.min_y
+    }
+}
+
+fn layout_box(element: &Element, inlets: usize, outlets: usize) -> Option<BoxLayout> {
+    let (text, bordered, inlets, outlets) = match &element.kind {
+        ElementKind::Object { name, args } => {
+            let mut text = name.clone();
+            if !args.is_empty() {
+                text.push(' ');
+                text.push_str(&atoms_to_text(args));
+            }
+
+            (text, true, inlets, outlets)
+        }
+        ElementKind::Message { atoms } => (atoms_to_text(atoms), true, 1, 1),
+        ElementKind::Comment { text } => (text.clone(), false, 0, 0),
+        ElementKind::AtomBox { kind, args } => {
+            // The first argument is the box width in characters, 0 meaning automatic
+            let chars = match args.first() {
+                Some(Atom::Float(width)) if *width > 0.0 => {
+                    (*width as usize).min(MAX_ATOM_BOX_CHARS)
+                }
+                _ => 5,
+            };
+            let placeholder = match kind.as_str() {
+                "floatatom" => "0",
+                _ => "-",
+            };
+
+            (format!("{placeholder:<chars$}"), true, 1, 1)
+        }
+        ElementKind::Subpatch { canvas } => (
+            format!("pd {}", canvas.name.clone().unwrap_or_default()),
+            true,
+            inlets,
+            outlets,
+        ),
+        ElementKind::Graph { canvas } => (
+            format!("graph {}", canvas.name.clone().unwrap_or_default()),
+            true,
+            inlets,
+            outlets,
+        ),
+        ElementKind::Scalar { .. } => return None,
+    };
+
+    let chars = (text.chars().count() as i64).max(MIN_BOX_CHARS);
+
+    Some(BoxLayout {
+        x: i64::from(element.x),
+        y: i64::from(element.y),
+        width: chars * CHAR_WIDTH + BOX_PADDING,
+        height: BOX_HEIGHT,
+        inlets,
+        outlets,
+        text,
+        bordered,
+    })
+}
+
+/// Join atoms the way Pd shows them, with commas and semicolons attached to the previous atom
+fn atoms_to_text(atoms: &[Atom]) -> String {
+    let mut text = String::new();
+
+    for atom in atoms {
+        match atom {
+            Atom::Comma | Atom::Semicolon => {}
+            _ if text.is_empty() => {}
+            _ => text.push(' '),
+        }
+
+        text.push_str(&atom.to_string());
+    }
+
+    text
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Read and parse the patch that was uploaded for a build
+pub async fn read_uploaded_patch(patch_meta: &PatchMeta) -> Result<PdPatch> {
+    let mut filename_patch = get_env_config().dir_workspace;
+    filename_patch.push("uploads");
+    filename_patch.push(patch_meta.patch_upload_filename());
+
+    let contents = fs::read_to_string(filename_patch)
+        .await
+        .map_err(|_| anyhow!("Failed to read the uploaded patch"))?;
+
+    validate_patch_file_contents(&contents)
+}
+
+/// Find a subpatch from a path of element indices like `3.0`, an empty path being the main canvas
+pub fn find_canvas<'a>(patch: &'a PdPatch, subpatch_path: &str) -> Option<&'a Canvas> {
+    let mut canvas = &patch.root;
+
+    for index in subpatch_path.split('.').filter(|index| !index.is_empty()) {
+        let element = canvas.elements.get(index.parse::<usize>().ok()?)?;
+
+        canvas = match &element.kind {
+            ElementKind::Subpatch { canvas } | ElementKind::Graph { canvas } => canvas,
+            _ => return None,
+        };
+    }
+
+    Some(canvas)
+}
+
+/// Draw a canvas as SVG. Subpatches link to their own rendering, nested under `subpatch_path`.
+pub fn render_svg(canvas: &Canvas, subpatch_path: &str) -> String {
+    let layout = CanvasLayout::new(canvas);
+    let mut svg = String::new();
+
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}" width="{}" height="{}" font-family="DejaVu Sans Mono, monospace" font-size="12">"#,
+        layout.min_x,
+        layout.min_y,
+        layout.width(),
+        layout.height(),
+        layout.width(),
+        layout.height()
+    );
+    let _ = writeln!(
+        svg,
+        r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#ffffff"/>"##,
+        layout.min_x,
+        layout.min_y,
+        layout.width(),
+        layout.height()
+    );
+
+    for connection in canvas.connections.iter() {
+        let (source, sink) = match (
+            &layout.boxes[connection.source],
+            &layout.boxes[connection.sink],
+        ) {
+            (Some(source), Some(sink)) => (source, sink),
+            _ => continue,
+        };
+
+        let stroke_width = if canvas.elements[connection.source].is_signal_object() {
+            2
+        } else {
+            1
+        };
+
+        let _ = writeln!(
+            svg,
+            r##"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="#000000" stroke-width="{}"/>"##,
+            source.outlet_x(connection.outlet) + IOLET_WIDTH / 2,
+            source.y + source.height,
+            sink.inlet_x(connection.inlet) + IOLET_WIDTH / 2,
+            sink.y,
+            stroke_width
+        );
+    }
+
+    for (element, layout_box) in canvas.elements.iter().zip(layout.boxes.iter()) {
+        let layout_box = match layout_box {
+            Some(layout_box) => layout_box,
+            None => continue,
+        };
+
+        let link = match &element.kind {
+            ElementKind::Subpatch { .. } | ElementKind::Graph { .. } => {
+                if subpatch_path.is_empty() {
+                    Some(element.index.to_string())
+                } else {
+                    Some(format!("{}.{}", subpatch_path, element.index))
+                }
+            }
+            _ => None,
+        };
+
+        if let Some(link) = &link {
+            let _ = writeln!(svg, r#"<a href="graph.svg?subpatch={link}">"#);
+        }
+
+        if layout_box.bordered {
+            let _ = writeln!(
+                svg,
+                r##"<rect x="{}" y="{}" width="{}" height="{}" fill="{}" stroke="#000000"/>"##,
+                layout_box.x,
+                layout_box.y,
+                layout_box.width,
+                layout_box.height,
+                if let ElementKind::Message { .. } = element.kind {
+                    "#f2f2f2"
+                } else {
+                    "#ffffff"
+                }
+            );
+        }
+
+        for inlet in 0..layout_box.inlets {
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="{}"/>"#,
+                layout_box.inlet_x(inlet),
+                layout_box.y,
+                IOLET_WIDTH,
+                IOLET_HEIGHT
+            );
+        }
+
+        for outlet in 0..layout_box.outlets {
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="{}"/>"#,
+                layout_box.outlet_x(outlet),
+                layout_box.y + layout_box.height - IOLET_HEIGHT,
+                IOLET_WIDTH,
+                IOLET_HEIGHT
+            );
+        }
+
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" xml:space="preserve">{}</text>"#,
+            layout_box.x + BOX_PADDING / 2,
+            layout_box.y + BOX_HEIGHT - 5,
+            escape_xml(&layout_box.text)
+        );
+
+        if link.is_some() {
+            let _ = writeln!(svg, "</a>");
+        }
+    }
+
+    svg.push_str("</svg>\n");
+
+    svg
+}
+
+/// Draw a small PNG of the canvas with boxes and connections only, for listings
+pub fn render_png_thumbnail(canvas: &Canvas) -> Result<Vec<u8>> {
+    let layout = CanvasLayout::new(canvas);
+
+    // Shrink large patches to fit, but never enlarge small ones
+    let scale = (THUMBNAIL_MAX_WIDTH as f64 / layout.width() as f64)
+        .min(THUMBNAIL_MAX_HEIGHT as f64 / layout.height() as f64)
+        .min(1.0);
+    let width = ((layout.width() as f64 * scale).ceil() as u32).max(1);
+    let height = ((layout.height() as f64 * scale).ceil() as u32).max(1);
+
+    let mut image = Thumbnail {
+        width,
+        height,
+        pixels: vec![0xff; (width * height) as usize],
+    };
+    let to_pixel = |x: i64, y: i64| {
+        (
+            ((x - layout.min_x) as f64 * scale) as i64,
+            ((y - layout.min_y) as f64 * scale) as i64,
+        )
+    };
+
+    for connection in canvas.connections.iter() {
+        if let (Some(source), Some(sink)) = (
+            &layout.boxes[connection.source],
+            &layout.boxes[connection.sink],
+        ) {
+            image.draw_line(
+                to_pixel(source.outlet_x(connection.outlet), source.y + source.height),
+                to_pixel(sink.inlet_x(connection.inlet), sink.y),
+                0x00,
+            );
+        }
+    }
+
+    for layout_box in layout.boxes.iter().flatten() {
+        let (x0, y0) = to_pixel(layout_box.x, layout_box.y);
+        let (x1, y1) = to_pixel(
+            layout_box.x + layout_box.width,
+            layout_box.y + layout_box.height,
+        );
+
+        // Comments have no border, so show them as a lighter bar
+        let shade = if layout_box.bordered { 0x40 } else { 0xb0 };
+
+        if layout_box.bordered {
+            image.fill_rect((x0, y0), (x1, y1), 0xff);
+        }
+
+        image.draw_line((x0, y0), (x1, y0), shade);
+        image.draw_line((x0, y1), (x1, y1), shade);
+        if layout_box.bordered {
+            image.draw_line((x0, y0), (x0, y1), shade);
+            image.draw_line((x1, y0), (x1, y1), shade);
+        }
+    }
+
+    let mut png_bytes: Vec<u8> = vec![];
+    let mut encoder = png::Encoder::new(&mut png_bytes, width, height);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image.pixels)?;
+    writer.finish()?;
+
+    Ok(png_bytes)
+}
+
+/// A grayscale image that ignores anything drawn outside of it
+struct Thumbnail {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Thumbnail {
+    fn set_pixel(&mut self, x: i64, y: i64, shade: u8) {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return;
+        }
+
+        self.pixels[(y as u32 * self.width + x as u32) as usize] = shade;
+    }
+
+    fn fill_rect(&mut self, from: (i64, i64), to: (i64, i64), shade: u8) {
+        for y in from.1..=to.1 {
+            for x in from.0..=to.0 {
+                self.set_pixel(x, y, shade);
+            }
+        }
+    }
+
+    /// Bresenham's line algorithm
+    fn draw_line(&mut self, from: (i64, i64), to: (i64, i64), shade: u8) {
+        let (mut x, mut y) = from;
+        let dx = (to.0 - x).abs();
+        let dy = -(to.1 - y).abs();
+        let step_x = if x < to.0 { 1 } else { -1 };
+        let step_y = if y < to.1 { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            self.set_pixel(x, y, shade);
+
+            if x == to.0 && y == to.1 {
+                break;
+            }
+
+            let doubled_error = 2 * error;
+            if doubled_error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled_error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pd_parser::parse_patch;
+
+    #[test]
+    fn renders_boxes_at_the_ends_of_the_coordinate_range() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;
+#X obj -2147483648 -2147483648 osc~ 440;
+#X obj 2147483647 2147483647 dac~;
+#X floatatom 0 0 1e15 0 0 0 - - -;
+#X connect 0 0 1 0;
+#X connect 0 0 1 1;
+",
+        )
+        .unwrap();
+
+        assert!(render_svg(&patch.root, "").contains("dac~"));
+        assert!(!render_png_thumbnail(&patch.root).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fits_the_bounding_box_to_boxes_away_from_the_origin() {
+        for (x, y) in [(-500, -300), (500, 300)] {
+            let patch = parse_patch(&format!(
+                "#N canvas 0 50 450 300 12;\n#X obj {x} {y} osc~;\n#X obj {} {} dac~;\n",
+                x + 10,
+                y + 40
+            ))
+            .unwrap();
+
+            let layout = CanvasLayout::new(&patch.root);
+            let dac = layout.boxes[1].as_ref().unwrap();
+
+            assert_eq!((layout.min_x, layout.min_y), (x - MARGIN, y - MARGIN));
+            assert_eq!(
+                (layout.max_x, layout.max_y),
+                (dac.x + dac.width + MARGIN, dac.y + dac.height + MARGIN)
+            );
+        }
+    }
+}
@@ -2,30 +2,227 @@ use actix_web::body::BoxBody;
 use actix_web::http::header::ContentType;
 use actix_web::{HttpRequest, HttpResponse, Responder};
 use anyhow::{anyhow, Result};
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
 use std::sync::Mutex;
+use tokio::fs;
 
+use crate::autofix::AutofixReport;
 use crate::boards::Board;
+use crate::build_options::{BuildOptions, BuildProfile};
+use crate::compiler_cache::CompilerCacheStats;
+use crate::env_config::EnvConfig;
+use crate::failure_explainer::FailureExplanation;
+use crate::heavy_compat::CompatibilityIssue;
+use crate::leases::Lease;
+use crate::lint::LintFinding;
+use crate::memory_estimate::MemoryEstimate;
+use crate::memory_layout::MemoryLayout;
+use crate::parameter_mapping::ParameterMapping;
+use crate::parameters::ParameterReport;
+use crate::pd_parser::{parse_patch, PdPatch};
+use crate::resource_usage::ResourceUsage;
+use crate::samples::EmbeddedSample;
 
+#[derive(Default)]
 pub struct PatchesStore {
     pub patches: PatchesMap,
     pub compilation_queue: Mutex<VecDeque<String>>,
+    pub leases: Mutex<HashMap<String, Lease>>,
+    pub groups: Mutex<HashMap<String, BuildGroup>>,
 }
 
 pub type PatchesMap = Mutex<HashMap<String, PatchMeta>>;
 
-#[derive(Serialize, Debug, Clone)]
+/// Put a patch whose build never finished back at the front of the queue
+pub fn requeue_patch(patches_store: &PatchesStore, patch_id: &str) {
+    let mut queue = patches_store.compilation_queue.lock().unwrap();
+    let mut patches = patches_store.patches.lock().unwrap();
+
+    if let Some(patch) = patches.get(patch_id) {
+        let requeued_patch = PatchMeta {
+            status: PatchStatus::Uploaded,
+            time_compile_start: None,
+            ..patch.clone()
+        };
+        patches.insert(patch_id.to_string(), requeued_patch);
+
+        queue.push_front(patch_id.to_string());
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PatchMeta {
     pub id: String,
     pub status: PatchStatus,
     pub board: Board,
+    pub memory_layout_requested: MemoryLayout,
+    /// The layout the patch was actually built with, after any automatic fallback
+    pub memory_layout: MemoryLayout,
+    pub build_options: BuildOptions,
+    pub build_profile: BuildProfile,
+    pub toolchain: String,
+    /// Set when the patch was uploaded for several boards at once
+    #[serde(default)]
+    pub group_id: Option<String>,
+    /// Set when the patch was retargeted from an earlier upload
+    #[serde(default)]
+    pub origin_patch_id: Option<String>,
+    /// Objects that Heavy compiles, but that may not behave as expected
+    #[serde(default)]
+    pub compatibility_warnings: Vec<CompatibilityIssue>,
+    /// Likely mistakes in the patch that still compile
+    #[serde(default)]
+    pub lint_findings: Vec<LintFinding>,
+    /// Set when the upload was rewritten to work around constructs Heavy can't handle
+    #[serde(default)]
+    pub autofix: Option<AutofixReport>,
+    /// Abstractions and other files uploaded with the patch, relative to `uploads/{id}/`
+    #[serde(default)]
+    pub project_files: Vec<String>,
+    /// Directories in `uploads/{id}/` that pd2dsy searches for abstractions
+    #[serde(default)]
+    pub search_paths: Vec<String>,
+    /// WAV files that were written into the patch's arrays, rather than stored as project files
+    #[serde(default)]
+    pub samples: Vec<EmbeddedSample>,
+    /// The patch's `@hv_param` receives and sends, compared to the board's controls
+    #[serde(default)]
+    pub parameters: Option<ParameterReport>,
+    /// Parameters that were renamed to the board's controls before building
+    #[serde(default)]
+    pub parameter_mappings: Vec<ParameterMapping>,
+    /// Delay lines and tables in the patch, compared to what the memory layout can hold
+    #[serde(default)]
+    pub memory_estimate: Option<MemoryEstimate>,
+    pub compiler_cache: Option<CompilerCacheStats>,
+    pub resource_usage: Option<ResourceUsage>,
     pub filename: String,
     pub time_upload: DateTime,
     pub time_compile_start: Option<DateTime>,
     pub time_compile_end: Option<DateTime>,
 }
 
+impl PatchMeta {
+    /// Name of the uploaded patch in the uploads directory
+    pub fn patch_upload_filename(&self) -> String {
+        format!("{}.pd", self.id)
+    }
+
+    /// Name of the patch as it was uploaded, before the auto-fix rewrote it
+    pub fn original_patch_upload_filename(&self) -> String {
+        format!("{}.original.pd", self.id)
+    }
+
+    /// Name of the patch before its parameters were renamed to the board's controls
+    pub fn unmapped_patch_upload_filename(&self) -> String {
+        format!("{}.unmapped.pd", self.id)
+    }
+
+    /// Name of a file uploaded with the patch, inside the uploads directory
+    pub fn project_file_upload_filename(&self, path: &str) -> String {
+        format!("{}/{}", self.id, path)
+    }
+
+    /// Directory pd2dsy should search for abstractions, inside the uploads directory
+    pub fn search_path_upload_dirname(&self, search_path: &str) -> String {
+        if search_path.is_empty() {
+            self.id.clone()
+        } else {
+            format!("{}/{}", self.id, search_path)
+        }
+    }
+
+    /// Name of the uploaded custom board definition in the uploads directory
+    pub fn board_def_upload_filename(&self) -> String {
+        format!("{}_board_def.json", self.id)
+    }
+
+    /// Every file in the uploads directory that is needed to compile the patch
+    pub fn upload_filenames(&self) -> Vec<String> {
+        let mut filenames = vec![self.patch_upload_filename()];
+
+        for path in self.project_files.iter() {
+            filenames.push(self.project_file_upload_filename(path));
+        }
+
+        if let Board::SeedCustomJson = self.board {
+            filenames.push(self.board_def_upload_filename());
+        }
+
+        filenames
+    }
+
+    /// Name of the compiled program in the downloads directory
+    pub fn binary_filename(&self) -> String {
+        self.artifact_filename("bin")
+    }
+
+    /// Name of the ELF file with debug symbols, only kept for debug builds
+    pub fn elf_filename(&self) -> String {
+        self.artifact_filename("elf")
+    }
+
+    /// Name of the zipped C++ project in the downloads directory
+    pub fn source_archive_filename(&self) -> String {
+        format!("daisy-{}-src.zip", self.id)
+    }
+
+    /// Every file a successful build leaves in the downloads directory
+    pub fn artifact_filenames(&self) -> Vec<String> {
+        let mut filenames = vec![self.binary_filename(), self.source_archive_filename()];
+
+        if self.build_profile.has_debug_symbols() {
+            filenames.push(self.elf_filename());
+        }
+
+        filenames
+    }
+
+    fn artifact_filename(&self, extension: &str) -> String {
+        match self.build_profile {
+            BuildProfile::Release => format!("daisy-{}.{}", self.id, extension),
+            _ => format!(
+                "daisy-{}-{}.{}",
+                self.id,
+                self.build_profile.to_str(),
+                extension
+            ),
+        }
+    }
+}
+
+/// Path of a file in one of the workspace directories, like `uploads` or `downloads`
+pub fn get_workspace_path(env_config: &EnvConfig, dir_name: &str, filename: &str) -> PathBuf {
+    let mut path = env_config.dir_workspace.clone();
+    path.push(dir_name);
+    path.push(filename);
+
+    path
+}
+
+/// Remove a patch's uploads and artifacts from the workspace, for builds that aren't kept
+pub async fn remove_patch_files(patch: &PatchMeta, env_config: &EnvConfig) {
+    let upload_paths = patch
+        .upload_filenames()
+        .into_iter()
+        .map(|filename| get_workspace_path(env_config, "uploads", &filename));
+    let artifact_paths = patch
+        .artifact_filenames()
+        .into_iter()
+        .map(|filename| get_workspace_path(env_config, "downloads", &filename));
+
+    for path in upload_paths.chain(artifact_paths) {
+        // Most artifacts will not exist after a failed build
+        let _ = fs::remove_file(path).await;
+    }
+
+    if !patch.project_files.is_empty() {
+        let _ = fs::remove_dir_all(get_workspace_path(env_config, "uploads", &patch.id)).await;
+    }
+}
+
 impl Responder for PatchMeta {
     type Body = BoxBody;
 
@@ -38,7 +235,16 @@ impl Responder for PatchMeta {
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
+/// One upload built for several boards, with a child patch per board
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BuildGroup {
+    pub id: String,
+    pub filename: String,
+    pub patch_ids: Vec<String>,
+    pub time_upload: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum PatchStatus {
     Uploaded,
     Compiling,
@@ -46,6 +252,7 @@ pub enum PatchStatus {
     Failed {
         summary: String,
         details: Option<String>,
+        explanation: Option<FailureExplanation>,
     },
 }
 
@@ -63,6 +270,18 @@ impl Serialize for DateTime {
     }
 }
 
+impl<'de> Deserialize<'de> for DateTime {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        let inner = value.parse().map_err(serde::de::Error::custom)?;
+
+        Ok(DateTime { inner })
+    }
+}
+
 impl DateTime {
     pub fn now() -> Self {
         DateTime {
@@ -71,10 +290,92 @@ impl DateTime {
     }
 }
 
-pub fn validate_patch_file_contents(file_contents: &str) -> Result<()> {
-    if file_contents.contains("#N canvas") {
-        Ok(())
-    } else {
-        Err(anyhow!("File does not appear to be a Pd patch"))
+pub fn validate_patch_file_contents(file_contents: &str) -> Result<PdPatch> {
+    match parse_patch(file_contents) {
+        Ok(patch) => Ok(patch),
+        Err(err) => Err(anyhow!(
+            "File does not appear to be a valid Pd patch ({err})"
+        )),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A patch as it is right after being uploaded
+    pub fn uploaded_patch(id: &str) -> PatchMeta {
+        PatchMeta {
+            id: id.to_string(),
+            status: PatchStatus::Uploaded,
+            board: Board::Pod,
+            memory_layout_requested: MemoryLayout::Flash,
+            memory_layout: MemoryLayout::Flash,
+            build_options: BuildOptions::default(),
+            build_profile: BuildProfile::Release,
+            toolchain: crate::toolchains::DEFAULT_TOOLCHAIN_NAME.to_string(),
+            group_id: None,
+            origin_patch_id: None,
+            compatibility_warnings: vec![],
+            lint_findings: vec![],
+            parameters: None,
+            autofix: None,
+            project_files: vec![],
+            search_paths: vec![],
+            samples: vec![],
+            memory_estimate: None,
+            parameter_mappings: vec![],
+            compiler_cache: None,
+            resource_usage: None,
+            filename: "patch.pd".to_string(),
+            time_upload: DateTime::now(),
+            time_compile_start: None,
+            time_compile_end: None,
+        }
+    }
+
+    /// A store holding `patches`, queued in the order given
+    pub fn queued_store(patches: Vec<PatchMeta>) -> PatchesStore {
+        let patches_store = PatchesStore::default();
+
+        for patch in patches {
+            patches_store
+                .compilation_queue
+                .lock()
+                .unwrap()
+                .push_back(patch.id.clone());
+            patches_store
+                .patches
+                .lock()
+                .unwrap()
+                .insert(patch.id.clone(), patch);
+        }
+
+        patches_store
+    }
+
+    #[test]
+    fn requeues_at_the_front_as_uploaded() {
+        let patches_store = queued_store(vec![uploaded_patch("a")]);
+        patches_store.patches.lock().unwrap().insert(
+            "b".to_string(),
+            PatchMeta {
+                status: PatchStatus::Compiling,
+                time_compile_start: Some(DateTime::now()),
+                ..uploaded_patch("b")
+            },
+        );
+
+        requeue_patch(&patches_store, "b");
+        requeue_patch(&patches_store, "missing");
+
+        assert_eq!(
+            *patches_store.compilation_queue.lock().unwrap(),
+            vec!["b".to_string(), "a".to_string()]
+        );
+
+        let patch = patches_store.patches.lock().unwrap()["b"].clone();
+        assert!(matches!(patch.status, PatchStatus::Uploaded));
+        assert!(patch.time_compile_start.is_none());
     }
 }
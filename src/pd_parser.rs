@@ -0,0 +1,940 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use thiserror::Error;
+
+/// Largest array any memory layout could hold, 64 MB of SDRAM full of 32-bit values.
+/// Checked before anything is allocated, since the size comes straight from the upload.
+pub const MAX_ARRAY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Coordinates are clamped to this, so code placing boxes can't overflow on absurd positions
+const MAX_COORDINATE: i32 = 1_000_000;
+
+/// Highest inlet or outlet number a connection can use
+const MAX_IOLET: usize = 1024;
+
+/// Signal objects whose outlets send control messages
+const CONTROL_OUTPUT_SIGNAL_OBJECTS: &[&str] = &["env~", "snapshot~", "samplerate~"];
+
+#[derive(Error, Debug)]
+#[error("line {line}: {message}")]
+pub struct PdParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Where an item was found in the patch file
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// Line the record starts on, counting from 1
+    pub line: usize,
+    /// Index of the record in `PdPatch::records`
+    pub record: usize,
+}
+
+/// One `;`-terminated statement, with its tokens still escaped the way Pd wrote them.
+/// Keeping these around lets other features rewrite a patch without losing anything.
+#[derive(Serialize, Debug, Clone)]
+pub struct Record {
+    pub line: usize,
+    /// Byte offsets of the record in the file, up to and including its closing `;`
+    pub start: usize,
+    pub end: usize,
+    pub tokens: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub enum Atom {
+    Float(f64),
+    Symbol(String),
+    Comma,
+    Semicolon,
+}
+
+impl Atom {
+    fn from_token(token: &str) -> Atom {
+        match token {
+            "," | "\\," => Atom::Comma,
+            "\\;" => Atom::Semicolon,
+            _ => {
+                let starts_like_number = token
+                    .chars()
+                    .next()
+                    .map(|c| c.is_ascii_digit() || c == '-' || c == '+' || c == '.')
+                    .unwrap_or(false);
+
+                match (starts_like_number, token.parse::<f64>()) {
+                    (true, Ok(value)) => Atom::Float(value),
+                    _ => Atom::Symbol(token.replace('\\', "")),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Atom::Float(value) => write!(f, "{value}"),
+            Atom::Symbol(value) => write!(f, "{value}"),
+            Atom::Comma => write!(f, ","),
+            Atom::Semicolon => write!(f, ";"),
+        }
+    }
+}
+
+/// The main patch window, a subpatch or a graph
+#[derive(Serialize, Debug)]
+pub struct Canvas {
+    pub name: Option<String>,
+    pub position: Position,
+    /// Everything that can be connected, in the order Pd numbers them
+    pub elements: Vec<Element>,
+    pub connections: Vec<Connection>,
+    pub arrays: Vec<PdArray>,
+    pub declarations: Vec<Vec<Atom>>,
+    /// Where a graph saved in the old `#N graph` format goes in its parent, since its `#X pop` has no coordinates
+    #[serde(skip)]
+    legacy_graph_position: Option<(i32, i32)>,
+}
+
+impl Canvas {
+    fn new(name: Option<String>, position: Position) -> Self {
+        Canvas {
+            name,
+            position,
+            elements: vec![],
+            connections: vec![],
+            arrays: vec![],
+            declarations: vec![],
+            legacy_graph_position: None,
+        }
+    }
+
+    fn push_element(&mut self, x: i32, y: i32, position: Position, kind: ElementKind) {
+        self.elements.push(Element {
+            index: self.elements.len(),
+            x,
+            y,
+            position,
+            kind,
+        });
+    }
+
+    fn validate_connections(&self) -> Result<(), PdParseError> {
+        for connection in self.connections.iter() {
+            for index in [connection.source, connection.sink] {
+                if index >= self.elements.len() {
+                    return Err(PdParseError {
+                        line: connection.position.line,
+                        message: format!(
+                            "connection refers to object {} but the canvas only has {}",
+                            index,
+                            self.elements.len()
+                        ),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Element {
+    /// Index within the canvas, as used by `#X connect`
+    pub index: usize,
+    pub x: i32,
+    pub y: i32,
+    pub position: Position,
+    pub kind: ElementKind,
+}
+
+impl Element {
+    /// Tilde objects process audio, everything else runs at control rate
+    pub fn is_signal_object(&self) -> bool {
+        match &self.kind {
+            ElementKind::Object { name, .. } => name.ends_with('~'),
+            _ => false,
+        }
+    }
+
+    /// Signal objects that send audio out of their outlets
+    pub fn has_signal_outlets(&self) -> bool {
+        match &self.kind {
+            ElementKind::Object { name, .. } => {
+                self.is_signal_object() && !CONTROL_OUTPUT_SIGNAL_OBJECTS.contains(&name.as_str())
+            }
+            _ => false,
+        }
+    }
+
+    /// Boxes known to send control messages. Subpatches and abstractions can have either kind of outlet,
+    /// so they don't count.
+    pub fn is_control_source(&self, known_abstractions: &HashSet<String>) -> bool {
+        match &self.kind {
+            ElementKind::Object { name, .. } if known_abstractions.contains(name) => false,
+            ElementKind::Object { .. } => !self.has_signal_outlets(),
+            ElementKind::Message { .. } | ElementKind::AtomBox { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub enum ElementKind {
+    Object {
+        name: String,
+        args: Vec<Atom>,
+    },
+    Message {
+        atoms: Vec<Atom>,
+    },
+    Comment {
+        text: String,
+    },
+    /// `floatatom`, `symbolatom` or `listbox`
+    AtomBox {
+        kind: String,
+        args: Vec<Atom>,
+    },
+    Subpatch {
+        canvas: Box<Canvas>,
+    },
+    Graph {
+        canvas: Box<Canvas>,
+    },
+    Scalar {
+        args: Vec<Atom>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct Connection {
+    pub source: usize,
+    pub outlet: usize,
+    pub sink: usize,
+    pub inlet: usize,
+    pub position: Position,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PdArray {
+    pub name: String,
+    pub size: usize,
+    /// Whether the contents are saved in the patch file
+    pub save_contents: bool,
+    pub values: Vec<f64>,
+    pub position: Position,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PdPatch {
+    pub root: Canvas,
+    pub records: Vec<Record>,
+}
+
+impl PdPatch {
+    /// Every element in the patch, including the ones nested in subpatches and graphs
+    pub fn elements(&self) -> Vec<ElementRef<'_>> {
+        let mut elements = vec![];
+        collect_elements(&self.root, &mut vec![], &mut elements);
+
+        elements
+    }
+}
+
+/// An element along with the subpatches it is nested in, outermost first
+pub struct ElementRef<'a> {
+    pub element: &'a Element,
+    pub canvas_path: Vec<String>,
+}
+
+impl ElementRef<'_> {
+    /// Human-readable location like `line 12 in [pd voice]`
+    pub fn location(&self) -> String {
+        let line = format!("line {}", self.element.position.line);
+
+        if self.canvas_path.is_empty() {
+            return line;
+        }
+
+        let subpatches: Vec<String> = self
+            .canvas_path
+            .iter()
+            .map(|name| format!("[pd {name}]"))
+            .collect();
+
+        format!("{} in {}", line, subpatches.join(" > "))
+    }
+}
+
+fn collect_elements<'a>(
+    canvas: &'a Canvas,
+    canvas_path: &mut Vec<String>,
+    elements: &mut Vec<ElementRef<'a>>,
+) {
+    for element in canvas.elements.iter() {
+        elements.push(ElementRef {
+            element,
+            canvas_path: canvas_path.clone(),
+        });
+
+        if let ElementKind::Subpatch { canvas: subpatch }
+        | ElementKind::Graph { canvas: subpatch } = &element.kind
+        {
+            canvas_path.push(subpatch.name.clone().unwrap_or_default());
+            collect_elements(subpatch, canvas_path, elements);
+            canvas_path.pop();
+        }
+    }
+}
+
+/// Parse the contents of a `.pd` file into its canvases, objects and connections
+pub fn parse_patch(contents: &str) -> Result<PdPatch, PdParseError> {
+    let records = split_records(contents)?;
+
+    let first_record_is_canvas = records
+        .first()
+        .map(|record| {
+            record
+                .tokens
+                .starts_with(&["#N".to_string(), "canvas".to_string()])
+        })
+        .unwrap_or(false);
+
+    if !first_record_is_canvas {
+        return Err(PdParseError {
+            line: 1,
+            message: "a Pd patch must start with #N canvas".to_string(),
+        });
+    }
+
+    // The canvas currently being read is at the top, the main canvas at the bottom
+    let mut canvas_stack: Vec<Canvas> = vec![];
+
+    for (record_index, record) in records.iter().enumerate() {
+        let position = Position {
+            line: record.line,
+            record: record_index,
+        };
+
+        parse_record(record, position, &mut canvas_stack)?;
+    }
+
+    let last_line = records.last().map(|record| record.line).unwrap_or(1);
+
+    let root = match canvas_stack.len() {
+        1 => canvas_stack.pop().unwrap(),
+        _ => {
+            return Err(PdParseError {
+                line: last_line,
+                message: "a subpatch is never closed with #X restore".to_string(),
+            })
+        }
+    };
+
+    root.validate_connections()?;
+
+    Ok(PdPatch { root, records })
+}
+
+fn parse_record(
+    record: &Record,
+    position: Position,
+    canvas_stack: &mut Vec<Canvas>,
+) -> Result<(), PdParseError> {
+    let tokens = &record.tokens;
+    let error = |message: &str| PdParseError {
+        line: record.line,
+        message: message.to_string(),
+    };
+
+    let record_type = (
+        tokens[0].as_str(),
+        tokens.get(1).map(String::as_str).unwrap_or(""),
+    );
+
+    if let ("#N", "canvas") = record_type {
+        // Subpatches are saved with their name, the main canvas with its font size instead
+        let name = if canvas_stack.is_empty() {
+            None
+        } else {
+            tokens.get(6).map(|name| name.replace('\\', ""))
+        };
+        canvas_stack.push(Canvas::new(name, position));
+
+        return Ok(());
+    }
+
+    if let ("#N", "graph") = record_type {
+        if canvas_stack.is_empty() {
+            return Err(error("#N graph found outside of a canvas"));
+        }
+
+        // Older versions of Pd save graphs as `#N graph name x1 y1 x2 y2 px1 py1 px2 py2`, closed by `#X pop`
+        let name = tokens.get(2).map(|name| name.replace('\\', ""));
+        let mut graph_canvas = Canvas::new(name, position);
+        graph_canvas.legacy_graph_position = Some(parse_point(tokens, 7).unwrap_or((0, 0)));
+        canvas_stack.push(graph_canvas);
+
+        return Ok(());
+    }
+
+    let canvas = canvas_stack
+        .last_mut()
+        .ok_or_else(|| error("record found outside of a canvas"))?;
+
+    match record_type {
+        ("#N", _) => {
+            // Data structure definitions like `#N struct` do not affect the graph
+        }
+        ("#X", "obj") => {
+            let (x, y) = parse_coordinates(tokens, record.line)?;
+            let atoms = parse_box_atoms(&tokens[4..]);
+
+            let kind = match atoms.split_first() {
+                Some((name, args)) => ElementKind::Object {
+                    name: name.to_string(),
+                    args: args.to_vec(),
+                },
+                // An empty object box
+                None => ElementKind::Object {
+                    name: "".to_string(),
+                    args: vec![],
+                },
+            };
+            canvas.push_element(x, y, position, kind);
+        }
+        ("#X", "msg") => {
+            let (x, y) = parse_coordinates(tokens, record.line)?;
+            let atoms = parse_box_atoms(&tokens[4..]);
+            canvas.push_element(x, y, position, ElementKind::Message { atoms });
+        }
+        ("#X", "text") => {
+            let (x, y) = parse_coordinates(tokens, record.line)?;
+            let text = parse_box_atoms(&tokens[4..])
+                .iter()
+                .map(|atom| atom.to_string())
+                .collect::<Vec<String>>()
+                .join(" ");
+            canvas.push_element(x, y, position, ElementKind::Comment { text });
+        }
+        ("#X", kind @ ("floatatom" | "symbolatom" | "listbox")) => {
+            let (x, y) = parse_coordinates(tokens, record.line)?;
+            let args = parse_box_atoms(&tokens[4..]);
+            let kind = kind.to_string();
+            canvas.push_element(x, y, position, ElementKind::AtomBox { kind, args });
+        }
+        ("#X", "scalar") => {
+            let args = parse_box_atoms(&tokens[2..]);
+            canvas.push_element(0, 0, position, ElementKind::Scalar { args });
+        }
+        ("#X", "restore") => {
+            if canvas_stack.len() < 2 {
+                return Err(error("#X restore without a matching #N canvas"));
+            }
+
+            let subpatch_canvas = canvas_stack.pop().unwrap();
+            subpatch_canvas.validate_connections()?;
+
+            let (x, y) = parse_coordinates(tokens, record.line)?;
+            let canvas = Box::new(subpatch_canvas);
+            let kind = match tokens.get(4).map(String::as_str) {
+                Some("graph") => ElementKind::Graph { canvas },
+                _ => ElementKind::Subpatch { canvas },
+            };
+
+            let parent_canvas = canvas_stack.last_mut().unwrap();
+            parent_canvas.push_element(x, y, position, kind);
+        }
+        ("#X", "pop") => {
+            let graph_position = canvas.legacy_graph_position;
+            let (x, y) = match (canvas_stack.len(), graph_position) {
+                (2.., Some(graph_position)) => graph_position,
+                _ => return Err(error("#X pop without a matching #N graph")),
+            };
+
+            let graph_canvas = canvas_stack.pop().unwrap();
+            graph_canvas.validate_connections()?;
+
+            let kind = ElementKind::Graph {
+                canvas: Box::new(graph_canvas),
+            };
+            canvas_stack
+                .last_mut()
+                .unwrap()
+                .push_element(x, y, position, kind);
+        }
+        ("#X", "connect") => {
+            let numbers: Vec<usize> = tokens
+                .get(2..6)
+                .ok_or_else(|| error("#X connect needs four numbers"))?
+                .iter()
+                .map(|token| token.parse::<usize>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| error("#X connect needs four numbers"))?;
+
+            if numbers[1] > MAX_IOLET || numbers[3] > MAX_IOLET {
+                return Err(error(&format!(
+                    "#X connect uses iolet {}, no object has that many",
+                    numbers[1].max(numbers[3])
+                )));
+            }
+
+            canvas.connections.push(Connection {
+                source: numbers[0],
+                outlet: numbers[1],
+                sink: numbers[2],
+                inlet: numbers[3],
+                position,
+            });
+        }
+        ("#X", "array") => {
+            let name = tokens
+                .get(2)
+                .ok_or_else(|| error("#X array needs a name"))?
+                .replace('\\', "");
+            let size = tokens
+                .get(3)
+                .and_then(|token| token.parse::<f64>().ok())
+                .filter(|size| *size >= 0.0)
+                .ok_or_else(|| error("#X array needs a size"))?;
+            if size > MAX_ARRAY_SIZE as f64 {
+                return Err(error(&format!(
+                    "#X array {name} is larger than the {MAX_ARRAY_SIZE} values a Daisy can hold"
+                )));
+            }
+            let size = size as usize;
+            let flags = tokens
+                .get(5)
+                .and_then(|token| token.parse::<u32>().ok())
+                .unwrap_or(0);
+
+            canvas.arrays.push(PdArray {
+                name,
+                size,
+                save_contents: flags & 1 == 1,
+                values: vec![],
+                position,
+            });
+        }
+        ("#X", "declare") => {
+            let atoms = tokens[2..]
+                .iter()
+                .map(|token| Atom::from_token(token))
+                .collect();
+            canvas.declarations.push(atoms);
+        }
+        ("#X", "coords" | "f") => {
+            // Graph-on-parent settings and box widths do not affect the graph
+        }
+        ("#A", _) => {
+            let array = canvas
+                .arrays
+                .last_mut()
+                .ok_or_else(|| error("#A array data without an #X array"))?;
+
+            // Newer versions of Pd save `#A <offset> values...`, older ones `#A set values...`
+            let offset = match tokens.get(1).map(String::as_str) {
+                Some("set") => 0,
+                Some(token) => token
+                    .parse::<usize>()
+                    .map_err(|_| error("#A array data needs an offset"))?,
+                None => 0,
+            };
+
+            for (i, token) in tokens.iter().skip(2).enumerate() {
+                let value = token
+                    .parse::<f64>()
+                    .map_err(|_| error("#A array data must be numbers"))?;
+
+                // The offset comes from the upload, so it can't be trusted to size anything
+                if offset.saturating_add(i) >= array.size {
+                    return Err(error(&format!(
+                        "#A array data goes past the end of {}, which has {} values",
+                        array.name, array.size
+                    )));
+                }
+
+                if array.values.len() <= offset + i {
+                    array.values.resize(offset + i + 1, 0.0);
+                }
+                array.values[offset + i] = value;
+            }
+        }
+        ("#X", _) => {
+            // Record types from other Pd versions that don't change what gets compiled
+        }
+        (other, _) => {
+            return Err(error(&format!("unknown record type {other}")));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_coordinates(tokens: &[String], line: usize) -> Result<(i32, i32), PdParseError> {
+    parse_point(tokens, 2).ok_or_else(|| PdParseError {
+        line,
+        message: format!("{} {} needs x and y coordinates", tokens[0], tokens[1]),
+    })
+}
+
+/// The x and y coordinates at `tokens[x_index]` and the token after it, within `MAX_COORDINATE`
+fn parse_point(tokens: &[String], x_index: usize) -> Option<(i32, i32)> {
+    let max = f64::from(MAX_COORDINATE);
+    let parse = |token: Option<&String>| {
+        token
+            .and_then(|token| token.parse::<f64>().ok())
+            .map(|value| value.clamp(-max, max) as i32)
+    };
+
+    Some((parse(tokens.get(x_index))?, parse(tokens.get(x_index + 1))?))
+}
+
+/// The atoms in a box, without the `, f <width>` suffix that Pd adds to resized boxes
+fn parse_box_atoms(tokens: &[String]) -> Vec<Atom> {
+    let tokens = match tokens {
+        [rest @ .., comma, f, _width] if comma == "," && f == "f" => rest,
+        _ => tokens,
+    };
+
+    tokens.iter().map(|token| Atom::from_token(token)).collect()
+}
+
+/// Split the file into records at every unescaped `;`
+fn split_records(contents: &str) -> Result<Vec<Record>, PdParseError> {
+    let mut records: Vec<Record> = vec![];
+
+    let mut tokens: Vec<String> = vec![];
+    let mut token = String::new();
+    let mut line = 1;
+    // Line and byte offset of the record being read
+    let mut record_start: Option<(usize, usize)> = None;
+
+    let mut chars = contents.char_indices();
+
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '\\' => {
+                record_start.get_or_insert((line, offset));
+                token.push(c);
+
+                if let Some((_, escaped)) = chars.next() {
+                    if escaped == '\n' {
+                        line += 1;
+                    }
+                    token.push(escaped);
+                }
+            }
+            ';' => {
+                flush_token(&mut token, &mut tokens);
+
+                if let Some((start_line, start)) = record_start.take() {
+                    records.push(Record {
+                        line: start_line,
+                        start,
+                        end: offset + 1,
+                        tokens: std::mem::take(&mut tokens),
+                    });
+                }
+            }
+            ',' => {
+                record_start.get_or_insert((line, offset));
+                flush_token(&mut token, &mut tokens);
+                tokens.push(",".to_string());
+            }
+            c if c.is_whitespace() => {
+                flush_token(&mut token, &mut tokens);
+
+                if c == '\n' {
+                    line += 1;
+                }
+            }
+            c => {
+                record_start.get_or_insert((line, offset));
+                token.push(c);
+            }
+        }
+    }
+
+    if let Some((start_line, _)) = record_start {
+        return Err(PdParseError {
+            line: start_line,
+            message: "the last record is missing its closing ;".to_string(),
+        });
+    }
+
+    Ok(records)
+}
+
+fn flush_token(token: &mut String, tokens: &mut Vec<String>) {
+    if !token.is_empty() {
+        tokens.push(std::mem::take(token));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root_objects(patch: &PdPatch) -> Vec<(&str, &[Atom])> {
+        patch
+            .root
+            .elements
+            .iter()
+            .filter_map(|element| match &element.kind {
+                ElementKind::Object { name, args } => Some((name.as_str(), args.as_slice())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_escaped_semicolons_commas_and_dollars() {
+        let patch = parse_patch(
+            r"#N canvas 0 50 450 300 12;
+#X msg 10 10 \; pd dsp 1 \, foo \$1;
+#X obj 10 40 r \$0-in;
+",
+        )
+        .unwrap();
+
+        assert_eq!(patch.records.len(), 3);
+        match &patch.root.elements[0].kind {
+            ElementKind::Message { atoms } => assert_eq!(
+                atoms,
+                &vec![
+                    Atom::Semicolon,
+                    Atom::Symbol("pd".to_string()),
+                    Atom::Symbol("dsp".to_string()),
+                    Atom::Float(1.0),
+                    Atom::Comma,
+                    Atom::Symbol("foo".to_string()),
+                    Atom::Symbol("$1".to_string()),
+                ]
+            ),
+            other => panic!("expected a message, got {other:?}"),
+        }
+        assert_eq!(
+            root_objects(&patch),
+            vec![("r", &[Atom::Symbol("$0-in".to_string())][..])]
+        );
+    }
+
+    #[test]
+    fn keeps_record_byte_offsets() {
+        let contents = "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~ 440;\n";
+        let patch = parse_patch(contents).unwrap();
+
+        let record = &patch.records[1];
+        assert_eq!(
+            &contents[record.start..record.end],
+            "#X obj 10 10 osc~ 440;"
+        );
+        assert_eq!(record.line, 2);
+    }
+
+    #[test]
+    fn nests_subpatches_until_restore() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;
+#N canvas 0 50 450 300 outer 0;
+#N canvas 0 50 450 300 inner 0;
+#X obj 10 10 osc~;
+#X restore 20 20 pd inner;
+#X restore 30 30 pd outer;
+#X obj 40 40 dac~;
+#X connect 0 0 1 0;
+",
+        )
+        .unwrap();
+
+        assert_eq!(patch.root.elements.len(), 2);
+        let outer = match &patch.root.elements[0].kind {
+            ElementKind::Subpatch { canvas } => canvas,
+            other => panic!("expected a subpatch, got {other:?}"),
+        };
+        assert_eq!(outer.name.as_deref(), Some("outer"));
+        assert_eq!(
+            (patch.root.elements[0].x, patch.root.elements[0].y),
+            (30, 30)
+        );
+
+        let inner = match &outer.elements[0].kind {
+            ElementKind::Subpatch { canvas } => canvas,
+            other => panic!("expected a subpatch, got {other:?}"),
+        };
+        assert_eq!(inner.name.as_deref(), Some("inner"));
+        assert_eq!(inner.elements.len(), 1);
+
+        let locations: Vec<String> = patch
+            .elements()
+            .iter()
+            .map(|element_ref| element_ref.location())
+            .collect();
+        assert!(locations.contains(&"line 4 in [pd outer] > [pd inner]".to_string()));
+    }
+
+    #[test]
+    fn rejects_unbalanced_subpatches() {
+        let unclosed = "#N canvas 0 50 450 300 12;\n#N canvas 0 50 450 300 sub 0;\n";
+        assert!(parse_patch(unclosed).is_err());
+
+        let extra_restore = "#N canvas 0 50 450 300 12;\n#X restore 0 0 pd sub;\n";
+        assert!(parse_patch(extra_restore).is_err());
+    }
+
+    #[test]
+    fn reads_array_data_with_offsets_and_set() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;
+#N canvas 0 50 450 250 (subpatch) 0;
+#X array offsets 4 float 1;
+#A 0 0.1 0.2;
+#A 2 0.3 0.4;
+#X array legacy 2 float 1;
+#A set 1 2;
+#X coords 0 1 4 -1 200 140 1;
+#X restore 10 10 graph;
+",
+        )
+        .unwrap();
+
+        let graph = match &patch.root.elements[0].kind {
+            ElementKind::Graph { canvas } => canvas,
+            other => panic!("expected a graph, got {other:?}"),
+        };
+        assert_eq!(graph.arrays[0].values, vec![0.1, 0.2, 0.3, 0.4]);
+        assert!(graph.arrays[0].save_contents);
+        assert_eq!(graph.arrays[1].values, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn reads_legacy_graphs() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;
+#N graph graph1 0 -1 99 1 108 248 308 98;
+#X array table1 100 float 0;
+#X pop;
+#X obj 10 10 tabread table1;
+#X some-future-record 1 2 3;
+",
+        )
+        .unwrap();
+
+        assert_eq!(patch.root.elements.len(), 2);
+        let element = &patch.root.elements[0];
+        assert_eq!((element.x, element.y), (108, 248));
+        match &element.kind {
+            ElementKind::Graph { canvas } => {
+                assert_eq!(canvas.name.as_deref(), Some("graph1"));
+                assert_eq!(canvas.arrays[0].name, "table1");
+                assert_eq!(canvas.arrays[0].size, 100);
+            }
+            other => panic!("expected a graph, got {other:?}"),
+        }
+
+        assert!(parse_patch("#N canvas 0 50 450 300 12;\n#X pop;\n").is_err());
+    }
+
+    #[test]
+    fn strips_box_width_suffixes() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;\n#X obj 10 10 metro 100, f 12;\n#X msg 10 40 bang, f 8;\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            root_objects(&patch),
+            vec![("metro", &[Atom::Float(100.0)][..])]
+        );
+        match &patch.root.elements[1].kind {
+            ElementKind::Message { atoms } => {
+                assert_eq!(atoms, &vec![Atom::Symbol("bang".to_string())])
+            }
+            other => panic!("expected a message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn validates_connection_indices() {
+        let valid = "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~;\n#X obj 10 40 dac~;\n#X connect 0 0 1 0;\n";
+        assert_eq!(parse_patch(valid).unwrap().root.connections.len(), 1);
+
+        let out_of_range = "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~;\n#X connect 0 0 5 0;\n";
+        let err = parse_patch(out_of_range).unwrap_err();
+        assert_eq!(err.line, 3);
+
+        let in_subpatch = "#N canvas 0 50 450 300 12;
+#N canvas 0 50 450 300 sub 0;
+#X obj 10 10 osc~;
+#X connect 0 0 1 0;
+#X restore 0 0 pd sub;
+";
+        assert!(parse_patch(in_subpatch).is_err());
+
+        let not_numbers = "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~;\n#X connect a 0 0 0;\n";
+        assert!(parse_patch(not_numbers).is_err());
+
+        let huge_outlet =
+            "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~;\n#X connect 0 99999999 0 0;\n";
+        assert!(parse_patch(huge_outlet).is_err());
+    }
+
+    #[test]
+    fn clamps_coordinates() {
+        let patch = parse_patch(
+            "#N canvas 0 50 450 300 12;\n#X obj 1e300 -99999999999 osc~;\n#X obj 12.7 -3 dac~;\n",
+        )
+        .unwrap();
+
+        let points: Vec<(i32, i32)> = patch
+            .root
+            .elements
+            .iter()
+            .map(|element| (element.x, element.y))
+            .collect();
+        assert_eq!(points, vec![(MAX_COORDINATE, -MAX_COORDINATE), (12, -3)]);
+    }
+
+    #[test]
+    fn rejects_array_data_past_the_end_without_allocating() {
+        let patch = "#N canvas 0 50 450 300 12;
+#N canvas 0 50 450 250 (subpatch) 0;
+#X array a 10 float 1;
+#A 4000000000 1;
+#X restore 10 10 graph;
+";
+        let err = parse_patch(patch).unwrap_err();
+        assert_eq!(err.line, 4);
+
+        let overflowing_offset = format!(
+            "#N canvas 0 50 450 300 12;\n#N canvas 0 50 450 250 (subpatch) 0;\n#X array a 10 float 1;\n#A {} 1 2;\n#X restore 10 10 graph;\n",
+            usize::MAX
+        );
+        assert!(parse_patch(&overflowing_offset).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_array_sizes() {
+        let patch = "#N canvas 0 50 450 300 12;
+#N canvas 0 50 450 250 (subpatch) 0;
+#X array a 9999999999999999999 float 0;
+#X restore 10 10 graph;
+";
+        let err = parse_patch(patch).unwrap_err();
+        assert_eq!(err.line, 3);
+
+        let negative = patch.replace("9999999999999999999", "-1");
+        assert!(parse_patch(&negative).is_err());
+    }
+
+    #[test]
+    fn requires_a_canvas_and_closing_semicolon() {
+        assert!(parse_patch("#X obj 10 10 osc~;\n").is_err());
+        assert!(parse_patch("#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~").is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use reqwest::StatusCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::compilation_worker::{compile_patch, get_finished_patch, remove_aborted_build};
+use crate::env_config::{get_env_config, EnvConfig};
+use crate::leases::{LeaseRequest, LeaseResponse};
+use crate::patches::{get_workspace_path, remove_patch_files, DateTime, PatchMeta, PatchStatus};
+use crate::resource_usage::ResourceUsage;
+use crate::shutdown::run_until_drain_deadline;
+
+/// HTTP client for the server's remote worker API
+struct ServerClient {
+    http: reqwest::Client,
+    server_url: String,
+    worker_token: String,
+    worker_name: String,
+}
+
+impl ServerClient {
+    fn new(env_config: &EnvConfig) -> Self {
+        ServerClient {
+            http: reqwest::Client::new(),
+            server_url: env_config.server_url.clone().unwrap(),
+            worker_token: env_config.worker_token.clone().unwrap(),
+            worker_name: env_config.worker_name.clone(),
+        }
+    }
+
+    async fn lease_job(&self) -> Result<Option<LeaseResponse>> {
+        let response = self
+            .http
+            .post(format!("{}/api/worker/lease", self.server_url))
+            .header("Authentication", &self.worker_token)
+            .json(&LeaseRequest {
+                worker_name: self.worker_name.clone(),
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+
+        Ok(Some(response.json().await?))
+    }
+
+    /// Returns `false` when the server no longer knows about the lease
+    async fn send_heartbeat(&self, lease_id: &str) -> Result<bool> {
+        let response = self
+            .http
+            .post(format!(
+                "{}/api/worker/leases/{lease_id}/heartbeat",
+                self.server_url
+            ))
+            .header("Authentication", &self.worker_token)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+
+        response.error_for_status()?;
+
+        Ok(true)
+    }
+
+    async fn download_file(&self, lease_id: &str, filename: &str) -> Result<Vec<u8>> {
+        let response = self
+            .http
+            .get(format!(
+                "{}/api/worker/leases/{lease_id}/files/{filename}",
+                self.server_url
+            ))
+            .header("Authentication", &self.worker_token)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    async fn upload_artifact(
+        &self,
+        lease_id: &str,
+        filename: &str,
+        contents: Vec<u8>,
+    ) -> Result<()> {
+        self.http
+            .put(format!(
+                "{}/api/worker/leases/{lease_id}/artifacts/{filename}",
+                self.server_url
+            ))
+            .header("Authentication", &self.worker_token)
+            .body(contents)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn complete_job(&self, lease_id: &str, finished_patch: &PatchMeta) -> Result<()> {
+        self.http
+            .post(format!(
+                "{}/api/worker/leases/{lease_id}/complete",
+                self.server_url
+            ))
+            .header("Authentication", &self.worker_token)
+            .json(finished_patch)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Hand the patch back to the server so it can be built elsewhere
+    async fn release_job(&self, lease_id: &str) -> Result<()> {
+        self.http
+            .post(format!(
+                "{}/api/worker/leases/{lease_id}/release",
+                self.server_url
+            ))
+            .header("Authentication", &self.worker_token)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+pub async fn run_remote_worker(stop_signal: CancellationToken) {
+    let env_config = get_env_config();
+    let client = ServerClient::new(&env_config);
+
+    info!(
+        "Starting remote worker {}, leasing jobs from {}",
+        client.worker_name, client.server_url
+    );
+
+    loop {
+        let lease_result = tokio::select! {
+            lease_result = client.lease_job() => lease_result,
+
+            _ = stop_signal.cancelled() => {
+                break;
+            }
+        };
+
+        match lease_result {
+            Ok(Some(job)) => {
+                let processing = process_job(&client, &job, &env_config);
+                let drain_deadline = Duration::from_secs(env_config.shutdown_drain_secs);
+
+                match run_until_drain_deadline(processing, &stop_signal, drain_deadline).await {
+                    Some(Ok(())) => {}
+                    Some(Err(err)) => {
+                        error!("Failed to process patch {}: {err}", job.patch.id);
+
+                        remove_aborted_build(&job.patch).await;
+                        report_failed_job(&client, &job, &err).await;
+                    }
+                    None => {
+                        remove_aborted_build(&job.patch).await;
+
+                        match client.release_job(&job.lease_id).await {
+                            Ok(()) => info!("Handed patch {} back to the server", job.patch.id),
+                            Err(err) => warn!(
+                                "Could not hand patch {} back, the server will re-queue it once the lease expires: {err}",
+                                job.patch.id
+                            ),
+                        }
+                    }
+                }
+
+                remove_patch_files(&job.patch, &env_config).await;
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Could not lease a job from the server: {err}"),
+        }
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {
+                continue;
+            }
+
+            _ = stop_signal.cancelled() => {
+                break;
+            }
+        };
+    }
+
+    info!("gracefully shutting down remote worker...");
+}
+
+/// Tell the server the patch failed, so it isn't leased out again only to fail the same way.
+/// If even that doesn't get through, hand the lease back instead of letting it expire.
+async fn report_failed_job(client: &ServerClient, job: &LeaseResponse, err: &anyhow::Error) {
+    let failed_patch = PatchMeta {
+        status: PatchStatus::Failed {
+            summary: format!("the build worker could not finish this patch: {err}"),
+            details: None,
+            explanation: None,
+        },
+        time_compile_end: Some(DateTime::now()),
+        ..job.patch.clone()
+    };
+
+    let report_err = match client.complete_job(&job.lease_id, &failed_patch).await {
+        Ok(()) => return,
+        Err(report_err) => report_err,
+    };
+
+    warn!(
+        "Could not report patch {} as failed, handing it back: {report_err}",
+        job.patch.id
+    );
+
+    if let Err(release_err) = client.release_job(&job.lease_id).await {
+        warn!(
+            "Could not hand patch {} back, the server will re-queue it once the lease expires: {release_err}",
+            job.patch.id
+        );
+    }
+}
+
+async fn process_job(
+    client: &ServerClient,
+    job: &LeaseResponse,
+    env_config: &EnvConfig,
+) -> Result<()> {
+    let patch = &job.patch;
+
+    for filename in patch.upload_filenames() {
+        let contents = client.download_file(&job.lease_id, &filename).await?;
+        let path = get_workspace_path(env_config, "uploads", &filename);
+
+        // Abstractions are kept in a directory per patch
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        fs::write(path, contents).await?;
+    }
+
+    let lease_lost = Arc::new(AtomicBool::new(false));
+    let heartbeat_stop = CancellationToken::new();
+    let heartbeat_interval = Duration::from_secs(job.lease_duration_secs / 3);
+
+    let mut resource_usage = ResourceUsage::default();
+
+    let compile = async {
+        let compilation_result = compile_patch(patch, &mut resource_usage).await;
+        heartbeat_stop.cancel();
+
+        compilation_result
+    };
+
+    let heartbeat = async {
+        loop {
+            tokio::select! {
+                _ = sleep(heartbeat_interval) => {}
+
+                _ = heartbeat_stop.cancelled() => {
+                    break;
+                }
+            };
+
+            match client.send_heartbeat(&job.lease_id).await {
+                Ok(true) => debug!("Sent heartbeat for lease {}", job.lease_id),
+                Ok(false) => {
+                    lease_lost.store(true, Ordering::SeqCst);
+                    break;
+                }
+                Err(err) => warn!("Failed to send heartbeat for lease {}: {err}", job.lease_id),
+            }
+        }
+    };
+
+    let (compilation_result, _) = tokio::join!(compile, heartbeat);
+
+    if lease_lost.load(Ordering::SeqCst) {
+        return Err(anyhow!(
+            "lease {} expired before the build finished",
+            job.lease_id
+        ));
+    }
+
+    let finished_patch = get_finished_patch(patch.clone(), compilation_result, resource_usage);
+
+    if let PatchStatus::Compiled = finished_patch.status {
+        for filename in finished_patch.artifact_filenames() {
+            let contents = fs::read(get_workspace_path(env_config, "downloads", &filename)).await?;
+            client
+                .upload_artifact(&job.lease_id, &filename, contents)
+                .await?;
+        }
+    }
+
+    client.complete_job(&job.lease_id, &finished_patch).await?;
+
+    Ok(())
+}
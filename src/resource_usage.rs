@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+use std::thread;
+
+use crate::patches::PatchMeta;
+
+/// Resources used by all the child processes of a single build
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResourceUsage {
+    pub cpu_user_secs: f64,
+    pub cpu_system_secs: f64,
+    pub peak_rss_kb: u64,
+    /// Size of the build directory once the program was compiled
+    pub build_dir_bytes: u64,
+}
+
+impl ResourceUsage {
+    fn add_process(&mut self, rusage: &libc::rusage) {
+        self.cpu_user_secs += timeval_to_secs(&rusage.ru_utime);
+        self.cpu_system_secs += timeval_to_secs(&rusage.ru_stime);
+        // Linux reports the peak resident set size in kilobytes
+        self.peak_rss_kb = self.peak_rss_kb.max(rusage.ru_maxrss as u64);
+    }
+}
+
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Resource usage across every build that reported it, for the admin stats
+#[derive(Serialize, Debug)]
+pub struct ResourceUsageStats {
+    pub builds: usize,
+    pub total_cpu_secs: f64,
+    pub average_cpu_secs: f64,
+    pub max_peak_rss_kb: u64,
+    pub total_build_dir_bytes: u64,
+    pub most_expensive_patches: Vec<PatchResourceUsage>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PatchResourceUsage {
+    pub patch_id: String,
+    pub filename: String,
+    pub cpu_secs: f64,
+    pub peak_rss_kb: u64,
+}
+
+const MOST_EXPENSIVE_PATCHES_LIMIT: usize = 10;
+
+/// Run a command to completion, adding its CPU time and memory to `resource_usage`.
+///
+/// This uses `wait4` instead of tokio's process handling, since that is the only way to
+/// get the usage of a single child, including the compilers that `make` spawns.
+///
+/// The command runs in its own process group. If the returned future is dropped before
+/// the command exits, for example when a build is aborted on shutdown, the whole group is killed.
+pub async fn run_command(
+    mut command: Command,
+    resource_usage: &mut ResourceUsage,
+) -> io::Result<CommandOutput> {
+    command.process_group(0);
+
+    let mut child = command.spawn()?;
+    let process_group_guard = ProcessGroupGuard { pid: child.id() };
+
+    let (output, rusage) = tokio::task::spawn_blocking(move || {
+        let stdout_reader = child.stdout.take().map(spawn_pipe_reader);
+        let stderr_reader = child.stderr.take().map(spawn_pipe_reader);
+
+        let (status, rusage) = wait_with_rusage(child.id())?;
+
+        let output = CommandOutput {
+            status,
+            stdout: join_pipe_reader(stdout_reader),
+            stderr: join_pipe_reader(stderr_reader),
+        };
+
+        Ok::<_, io::Error>((output, rusage))
+    })
+    .await??;
+
+    std::mem::forget(process_group_guard);
+
+    resource_usage.add_process(&rusage);
+
+    Ok(output)
+}
+
+/// Kills a command's process group unless the command finished first
+struct ProcessGroupGuard {
+    pid: u32,
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        // SAFETY: kill has no memory safety requirements, a negative pid targets the process group
+        unsafe {
+            libc::kill(-(self.pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+fn spawn_pipe_reader<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut contents = vec![];
+        let _ = pipe.read_to_end(&mut contents);
+        contents
+    })
+}
+
+fn join_pipe_reader(reader: Option<thread::JoinHandle<Vec<u8>>>) -> Vec<u8> {
+    reader
+        .map(|handle| handle.join().unwrap_or_default())
+        .unwrap_or_default()
+}
+
+fn wait_with_rusage(pid: u32) -> io::Result<(ExitStatus, libc::rusage)> {
+    let mut status: libc::c_int = 0;
+    // SAFETY: rusage is a plain C struct, all zeroes is a valid value
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        // SAFETY: the pointers are valid for the duration of the call, and the pid belongs
+        // to a child that nothing else waits on
+        let result = unsafe { libc::wait4(pid as libc::pid_t, &mut status, 0, &mut rusage) };
+
+        if result != -1 {
+            break;
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    Ok((ExitStatus::from_raw(status), rusage))
+}
+
+fn timeval_to_secs(timeval: &libc::timeval) -> f64 {
+    timeval.tv_sec as f64 + timeval.tv_usec as f64 / 1_000_000.0
+}
+
+/// Total size of the files in a directory, without following symlinks
+pub fn get_dir_size(dir: &Path) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => get_dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+pub fn aggregate_resource_usage(patches: &HashMap<String, PatchMeta>) -> ResourceUsageStats {
+    let mut patch_usages: Vec<PatchResourceUsage> = vec![];
+    let mut total_build_dir_bytes = 0;
+
+    for patch in patches.values() {
+        if let Some(resource_usage) = &patch.resource_usage {
+            total_build_dir_bytes += resource_usage.build_dir_bytes;
+
+            patch_usages.push(PatchResourceUsage {
+                patch_id: patch.id.clone(),
+                filename: patch.filename.clone(),
+                cpu_secs: resource_usage.cpu_user_secs + resource_usage.cpu_system_secs,
+                peak_rss_kb: resource_usage.peak_rss_kb,
+            });
+        }
+    }
+
+    let builds = patch_usages.len();
+    let total_cpu_secs: f64 = patch_usages.iter().map(|usage| usage.cpu_secs).sum();
+    let max_peak_rss_kb = patch_usages
+        .iter()
+        .map(|usage| usage.peak_rss_kb)
+        .max()
+        .unwrap_or(0);
+
+    patch_usages.sort_by(|a, b| b.cpu_secs.total_cmp(&a.cpu_secs));
+    patch_usages.truncate(MOST_EXPENSIVE_PATCHES_LIMIT);
+
+    ResourceUsageStats {
+        builds,
+        total_cpu_secs,
+        average_cpu_secs: if builds > 0 {
+            total_cpu_secs / builds as f64
+        } else {
+            0.0
+        },
+        max_peak_rss_kb,
+        total_build_dir_bytes,
+        most_expensive_patches: patch_usages,
+    }
+}
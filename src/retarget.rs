@@ -0,0 +1,281 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::boards::Board;
+use crate::build_options::{BuildOptions, BuildProfile};
+use crate::env_config::get_env_config;
+use crate::memory_estimate::estimate_memory;
+use crate::memory_layout::MemoryLayout;
+use crate::parameter_mapping::{apply_parameter_mappings, ParameterMapping};
+use crate::parameters::{board_controls, check_parameters};
+use crate::patches::{
+    get_workspace_path, validate_patch_file_contents, DateTime, PatchMeta, PatchStatus,
+};
+use crate::samples::memory_layout_for_samples;
+use crate::toolchains::get_toolchain;
+
+/// Anything left out is copied from the original patch
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RetargetRequest {
+    pub board: Option<Board>,
+    pub memory_layout: Option<MemoryLayout>,
+    pub build_profile: Option<BuildProfile>,
+    pub toolchain: Option<String>,
+    pub build_options: Option<BuildOptions>,
+    pub parameter_mappings: Option<Vec<ParameterMapping>>,
+}
+
+/// Create a new patch from the files stored for an earlier upload
+pub async fn retarget_patch(origin: &PatchMeta, request: RetargetRequest) -> Result<PatchMeta> {
+    let board = request.board.unwrap_or_else(|| origin.board.clone());
+
+    // The board definition can only be reused if the original upload had one
+    if let (Board::SeedCustomJson, false) = (&board, origin.board == Board::SeedCustomJson) {
+        return Err(anyhow!(
+            "The original upload has no custom board definition to build for the Seed"
+        ));
+    }
+
+    let toolchain = match request.toolchain {
+        Some(toolchain_name) => get_toolchain(&toolchain_name)
+            .ok_or_else(|| anyhow!("Unknown toolchain: {toolchain_name}"))?
+            .name
+            .clone(),
+        None => origin.toolchain.clone(),
+    };
+
+    let build_options = request
+        .build_options
+        .unwrap_or_else(|| origin.build_options.clone());
+    build_options.validate()?;
+
+    // Samples were resampled into the patch when it was uploaded
+    if !origin.samples.is_empty()
+        && build_options.effective_sample_rate() != origin.build_options.effective_sample_rate()
+    {
+        return Err(anyhow!(
+            "The patch's samples were converted to {} Hz, upload it again to build at {} Hz",
+            origin.build_options.effective_sample_rate(),
+            build_options.effective_sample_rate()
+        ));
+    }
+
+    let memory_layout_requested = request
+        .memory_layout
+        .unwrap_or_else(|| origin.memory_layout_requested.clone());
+
+    let parameter_mappings = request
+        .parameter_mappings
+        .unwrap_or_else(|| origin.parameter_mappings.clone());
+
+    // The parameters depend on the board's controls, and the memory estimate on the layout
+    // and sample rate, so they are worked out again from the original files
+    let unmapped_patch_contents = if origin.parameter_mappings.is_empty() {
+        read_upload(&origin.patch_upload_filename()).await?
+    } else {
+        read_upload(&origin.unmapped_patch_upload_filename()).await?
+    };
+    let board_def_contents = match board {
+        Board::SeedCustomJson => Some(read_upload(&origin.board_def_upload_filename()).await?),
+        _ => None,
+    };
+    let controls = board_controls(&board, board_def_contents.as_deref())?;
+
+    let mut patch_contents = unmapped_patch_contents.clone();
+    let mut patch = validate_patch_file_contents(&patch_contents)?;
+    if !parameter_mappings.is_empty() {
+        patch_contents =
+            apply_parameter_mappings(&patch_contents, &patch, &parameter_mappings, &controls)?;
+        patch = validate_patch_file_contents(&patch_contents)?;
+    }
+
+    let parameters = check_parameters(&patch, &controls);
+    let program_bytes = estimate_memory(
+        &patch,
+        &memory_layout_requested,
+        build_options.effective_sample_rate(),
+    )
+    .program_bytes;
+    let memory_layout =
+        memory_layout_for_samples(&memory_layout_requested, &origin.samples, program_bytes);
+    let memory_estimate = estimate_memory(
+        &patch,
+        &memory_layout,
+        build_options.effective_sample_rate(),
+    );
+
+    let patch_meta = PatchMeta {
+        id: Uuid::new_v4().to_string(),
+        status: PatchStatus::Uploaded,
+        board,
+        memory_layout_requested,
+        memory_layout,
+        build_options,
+        build_profile: request
+            .build_profile
+            .unwrap_or_else(|| origin.build_profile.clone()),
+        toolchain,
+        group_id: None,
+        origin_patch_id: Some(origin.id.clone()),
+        compatibility_warnings: origin.compatibility_warnings.clone(),
+        lint_findings: origin.lint_findings.clone(),
+        parameters: Some(parameters),
+        parameter_mappings,
+        autofix: origin.autofix.clone(),
+        project_files: origin.project_files.clone(),
+        search_paths: origin.search_paths.clone(),
+        samples: origin.samples.clone(),
+        memory_estimate: Some(memory_estimate),
+        compiler_cache: None,
+        resource_usage: None,
+        filename: origin.filename.clone(),
+        time_upload: DateTime::now(),
+        time_compile_start: None,
+        time_compile_end: None,
+    };
+    debug!("Created retargeted patch meta: {:?}", &patch_meta);
+
+    write_upload(&patch_meta.patch_upload_filename(), &patch_contents).await?;
+
+    if !patch_meta.parameter_mappings.is_empty() {
+        write_upload(
+            &patch_meta.unmapped_patch_upload_filename(),
+            &unmapped_patch_contents,
+        )
+        .await?;
+    }
+
+    for path in origin.project_files.iter() {
+        copy_upload(
+            &origin.project_file_upload_filename(path),
+            &patch_meta.project_file_upload_filename(path),
+        )
+        .await?;
+    }
+
+    if patch_meta.autofix.is_some() {
+        copy_upload(
+            &origin.original_patch_upload_filename(),
+            &patch_meta.original_patch_upload_filename(),
+        )
+        .await?;
+    }
+
+    if let Board::SeedCustomJson = patch_meta.board {
+        copy_upload(
+            &origin.board_def_upload_filename(),
+            &patch_meta.board_def_upload_filename(),
+        )
+        .await?;
+    }
+
+    Ok(patch_meta)
+}
+
+async fn copy_upload(from_filename: &str, to_filename: &str) -> Result<()> {
+    let env_config = get_env_config();
+    let to_path = get_workspace_path(&env_config, "uploads", to_filename);
+
+    // Files uploaded alongside the patch live in a directory per patch
+    if let Some(dir) = to_path.parent() {
+        fs::create_dir_all(dir)
+            .await
+            .map_err(|_| anyhow!("Failed to copy the original upload"))?;
+    }
+
+    fs::copy(
+        get_workspace_path(&env_config, "uploads", from_filename),
+        to_path,
+    )
+    .await
+    .map_err(|_| anyhow!("Failed to copy the original upload"))?;
+
+    Ok(())
+}
+
+async fn write_upload(filename: &str, contents: &str) -> Result<()> {
+    fs::write(
+        get_workspace_path(&get_env_config(), "uploads", filename),
+        contents,
+    )
+    .await
+    .map_err(|_| anyhow!("Failed to save the retargeted patch"))
+}
+
+async fn read_upload(filename: &str) -> Result<String> {
+    fs::read_to_string(get_workspace_path(&get_env_config(), "uploads", filename))
+        .await
+        .map_err(|_| anyhow!("Failed to read the original upload"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env_config::tests::init_test_env;
+    use crate::patches::tests::uploaded_patch;
+
+    const PATCH: &str =
+        "#N canvas 0 50 450 300 12;\n#X obj 10 10 osc~ 440;\n#X obj 10 40 dac~;\n#X connect 0 0 1 0;\n";
+    const ABSTRACTION: &str = "#N canvas 0 50 450 300 12;\n#X obj 10 10 inlet~;\n";
+
+    #[tokio::test]
+    async fn copies_the_files_into_the_configured_workspace() {
+        let env_config = init_test_env();
+        let dir_uploads = env_config.dir_workspace.join("uploads");
+
+        let mut origin = uploaded_patch("retarget-origin");
+        origin.project_files = vec!["lib/gain.pd".to_string()];
+        fs::create_dir_all(dir_uploads.join("retarget-origin/lib"))
+            .await
+            .unwrap();
+        fs::write(dir_uploads.join(origin.patch_upload_filename()), PATCH)
+            .await
+            .unwrap();
+        fs::write(
+            dir_uploads.join(origin.project_file_upload_filename("lib/gain.pd")),
+            ABSTRACTION,
+        )
+        .await
+        .unwrap();
+
+        let retargeted = retarget_patch(
+            &origin,
+            RetargetRequest {
+                board: Some(Board::Patch),
+                memory_layout: Some(MemoryLayout::BootSram),
+                build_profile: None,
+                toolchain: None,
+                build_options: None,
+                parameter_mappings: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_ne!(retargeted.id, origin.id);
+        assert_eq!(retargeted.origin_patch_id, Some(origin.id.clone()));
+        assert_eq!(retargeted.board, Board::Patch);
+        assert_eq!(retargeted.memory_layout_requested, MemoryLayout::BootSram);
+        assert_eq!(retargeted.memory_layout, MemoryLayout::BootSram);
+        assert!(retargeted.memory_estimate.is_some());
+        assert_eq!(retargeted.project_files, origin.project_files);
+
+        assert_eq!(
+            fs::read_to_string(dir_uploads.join(retargeted.patch_upload_filename()))
+                .await
+                .unwrap(),
+            PATCH
+        );
+        assert_eq!(
+            fs::read_to_string(
+                dir_uploads.join(retargeted.project_file_upload_filename("lib/gain.pd"))
+            )
+            .await
+            .unwrap(),
+            ABSTRACTION
+        );
+    }
+}
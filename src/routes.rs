@@ -1,20 +1,42 @@
+use actix_files::NamedFile;
 use actix_multipart::Multipart;
 use actix_web::body::BoxBody;
 use actix_web::http::header::ContentType;
-use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Result};
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse, Responder, Result};
 use askama::Template;
 use lazy_static::lazy_static;
 use log::{info, warn};
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
 
+use crate::dependency_checks::{DependencyStatus, DependencyStore};
 use crate::env_config::get_env_config;
-use crate::patches::{PatchMeta, PatchesStore};
+use crate::leases::{
+    complete_lease, get_leased_patch, lease_next_patch, release_lease, renew_lease, LeaseRequest,
+};
+use crate::lint::{lint_patch, LintFinding};
+use crate::patch_graph::{find_canvas, read_uploaded_patch, render_png_thumbnail, render_svg};
+use crate::patches::{validate_patch_file_contents, BuildGroup, PatchMeta, PatchesStore};
+use crate::resource_usage::aggregate_resource_usage;
+use crate::retarget::{retarget_patch, RetargetRequest};
+use crate::self_test::SelfTestStore;
+use crate::toolchains::{get_toolchains, Toolchain};
 use crate::upload::process_patch_upload;
+use crate::worker_supervisor::WorkerHealth;
 
 #[derive(Template)]
 #[template(path = "home.html")]
-struct HomeTemplate;
+struct HomeTemplate<'a> {
+    toolchains: &'a [Toolchain],
+}
+
+#[derive(Template)]
+#[template(path = "group.html")]
+struct GroupTemplate<'a> {
+    group: &'a BuildGroup,
+    patches: &'a [PatchMeta],
+}
 
 #[derive(Template)]
 #[template(path = "about.html")]
@@ -25,7 +47,7 @@ struct AboutTemplate<'a> {
 #[derive(Template)]
 #[template(path = "upload_success.html")]
 struct UploadSuccessTemplate<'a> {
-    patch_id: &'a str,
+    patch: &'a PatchMeta,
 }
 
 #[derive(Serialize, Debug)]
@@ -45,6 +67,42 @@ impl Responder for PatchListResponse {
     }
 }
 
+#[derive(Serialize, Debug)]
+struct ToolchainListResponse {
+    toolchains: &'static [Toolchain],
+}
+
+impl Responder for ToolchainListResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = serde_json::to_string(&self).unwrap();
+
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
+#[derive(Serialize)]
+struct BuildGroupResponse<'a> {
+    #[serde(flatten)]
+    group: &'a BuildGroup,
+    patches: Vec<PatchMeta>,
+}
+
+#[derive(Serialize)]
+struct LintResponse {
+    findings: Vec<LintFinding>,
+}
+
+#[derive(Serialize)]
+struct ReadinessReport {
+    ready: bool,
+    problems: Vec<String>,
+    dependencies: Vec<DependencyStatus>,
+}
+
 lazy_static! {
     static ref ABOUT_CONTENT: String = {
         let md_contents = include_str!("../templates/about_content.md");
@@ -56,7 +114,11 @@ lazy_static! {
 
 #[get("/")]
 pub async fn index_route() -> Result<HttpResponse> {
-    let res_body = HomeTemplate.render().unwrap();
+    let res_body = HomeTemplate {
+        toolchains: get_toolchains(),
+    }
+    .render()
+    .unwrap();
 
     Ok(HttpResponse::Ok().content_type("text/html").body(res_body))
 }
@@ -82,13 +144,11 @@ pub async fn patch_page_route(
     let patches = patches_store.patches.lock().unwrap();
 
     match patches.get(&patch_id) {
-        Some(_patch_meta) => {
+        Some(patch_meta) => {
             // TODO: create a different template
-            let res_body = UploadSuccessTemplate {
-                patch_id: &patch_id,
-            }
-            .render()
-            .unwrap();
+            let res_body = UploadSuccessTemplate { patch: patch_meta }
+                .render()
+                .unwrap();
 
             Ok(HttpResponse::Ok().content_type("text/html").body(res_body))
         }
@@ -101,6 +161,99 @@ pub async fn patch_page_route(
     }
 }
 
+#[derive(Deserialize)]
+struct PatchGraphQuery {
+    /// Element indices leading to a subpatch, like `3.0`
+    subpatch: Option<String>,
+}
+
+#[get("/patches/{patch_id}/graph.svg")]
+async fn patch_graph_route(
+    path: web::Path<String>,
+    query: web::Query<PatchGraphQuery>,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    let patch_meta = match get_patch_meta(&path.into_inner(), &patches_store) {
+        Some(patch_meta) => patch_meta,
+        None => return HttpResponse::NotFound().body("Not found!"),
+    };
+
+    let patch = match read_uploaded_patch(&patch_meta).await {
+        Ok(patch) => patch,
+        Err(reason) => {
+            warn!("Error rendering patch {}: {reason}", patch_meta.id);
+
+            return HttpResponse::InternalServerError()
+                .body(format!("Error rendering patch: {reason}"));
+        }
+    };
+
+    let subpatch_path = query.subpatch.clone().unwrap_or_default();
+
+    match find_canvas(&patch, &subpatch_path) {
+        Some(canvas) => HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .body(render_svg(canvas, &subpatch_path)),
+        None => HttpResponse::NotFound().body("Subpatch not found!"),
+    }
+}
+
+#[get("/patches/{patch_id}/thumbnail.png")]
+async fn patch_thumbnail_route(
+    path: web::Path<String>,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    let patch_meta = match get_patch_meta(&path.into_inner(), &patches_store) {
+        Some(patch_meta) => patch_meta,
+        None => return HttpResponse::NotFound().body("Not found!"),
+    };
+
+    let thumbnail = read_uploaded_patch(&patch_meta)
+        .await
+        .and_then(|patch| render_png_thumbnail(&patch.root));
+
+    match thumbnail {
+        Ok(thumbnail) => HttpResponse::Ok().content_type("image/png").body(thumbnail),
+        Err(reason) => {
+            warn!(
+                "Error rendering thumbnail for patch {}: {reason}",
+                patch_meta.id
+            );
+
+            HttpResponse::InternalServerError().body(format!("Error rendering thumbnail: {reason}"))
+        }
+    }
+}
+
+#[get("/groups/{group_id}")]
+pub async fn group_page_route(
+    path: web::Path<String>,
+    patches_store: web::Data<PatchesStore>,
+) -> Result<HttpResponse> {
+    let group_id = path.into_inner();
+
+    let patches = patches_store.patches.lock().unwrap();
+    let groups = patches_store.groups.lock().unwrap();
+
+    match groups.get(&group_id) {
+        Some(group) => {
+            let group_patches = get_group_patches(group, &patches);
+
+            let res_body = GroupTemplate {
+                group,
+                patches: &group_patches,
+            }
+            .render()
+            .unwrap();
+
+            Ok(HttpResponse::Ok().content_type("text/html").body(res_body))
+        }
+        None => Ok(HttpResponse::NotFound()
+            .content_type("text/html")
+            .body("Not found!")),
+    }
+}
+
 #[post("/upload")]
 pub async fn upload_route(
     payload: Multipart,
@@ -109,20 +262,33 @@ pub async fn upload_route(
     info!("Starting the upload endpoint...");
 
     match process_patch_upload(payload).await {
-        Ok(patch_meta) => {
-            let patch_id = patch_meta.id.clone();
-
-            let mut patches = patches_store.patches.lock().unwrap();
-            patches.insert(patch_id.clone(), patch_meta);
-
+        Ok(patch_upload) => {
             let mut queue = patches_store.compilation_queue.lock().unwrap();
-            queue.push_back(patch_id.clone());
+            let mut patches = patches_store.patches.lock().unwrap();
 
-            let res_body = UploadSuccessTemplate {
-                patch_id: &patch_id,
+            for patch_meta in patch_upload.patches.iter() {
+                patches.insert(patch_meta.id.clone(), patch_meta.clone());
+                queue.push_back(patch_meta.id.clone());
             }
-            .render()
-            .unwrap();
+
+            let res_body = match &patch_upload.group {
+                Some(group) => {
+                    let mut groups = patches_store.groups.lock().unwrap();
+                    groups.insert(group.id.clone(), group.clone());
+
+                    GroupTemplate {
+                        group,
+                        patches: &patch_upload.patches,
+                    }
+                    .render()
+                    .unwrap()
+                }
+                None => UploadSuccessTemplate {
+                    patch: &patch_upload.patches[0],
+                }
+                .render()
+                .unwrap(),
+            };
 
             Ok(HttpResponse::Ok().content_type("text/html").body(res_body))
         }
@@ -156,6 +322,55 @@ async fn list_patches_route(
     }
 }
 
+#[get("/api/admin/stats")]
+async fn admin_stats_route(
+    req: HttpRequest,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    if is_authenticated(&req) {
+        let patches = patches_store.patches.lock().unwrap();
+        let resource_usage_stats = aggregate_resource_usage(&patches);
+
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&resource_usage_stats).unwrap())
+    } else {
+        HttpResponse::Unauthorized().body("You are not authenticated!")
+    }
+}
+
+#[get("/api/selftest")]
+async fn self_test_route(self_test_store: web::Data<SelfTestStore>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(self_test_store.get_ref()).unwrap())
+}
+
+#[get("/api/groups/{group_id}")]
+async fn get_group_by_id_route(
+    path: web::Path<String>,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    let group_id = path.into_inner();
+
+    let patches = patches_store.patches.lock().unwrap();
+    let groups = patches_store.groups.lock().unwrap();
+
+    match groups.get(&group_id) {
+        Some(group) => {
+            let group_response = BuildGroupResponse {
+                group,
+                patches: get_group_patches(group, &patches),
+            };
+
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(serde_json::to_string(&group_response).unwrap())
+        }
+        None => HttpResponse::NotFound().body("Not found!"),
+    }
+}
+
 #[get("/api/patches/{patch_id}")]
 async fn get_patch_by_id_route(
     path: web::Path<String>,
@@ -175,23 +390,312 @@ async fn get_patch_by_id_route(
     }
 }
 
+#[post("/api/patches/{patch_id}/retarget")]
+async fn retarget_patch_route(
+    path: web::Path<String>,
+    retarget_request: web::Json<RetargetRequest>,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    let patch_id = path.into_inner();
+
+    let origin = match patches_store.patches.lock().unwrap().get(&patch_id) {
+        Some(patch_meta) => patch_meta.clone(),
+        None => return HttpResponse::NotFound().body("Not found!"),
+    };
+
+    match retarget_patch(&origin, retarget_request.into_inner()).await {
+        Ok(patch_meta) => {
+            info!("Retargeted patch {} as {}", origin.id, patch_meta.id);
+
+            let mut queue = patches_store.compilation_queue.lock().unwrap();
+            let mut patches = patches_store.patches.lock().unwrap();
+
+            patches.insert(patch_meta.id.clone(), patch_meta.clone());
+            queue.push_back(patch_meta.id.clone());
+
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(serde_json::to_string(&patch_meta).unwrap())
+        }
+        Err(reason) => {
+            warn!("Error retargeting patch: {reason}");
+
+            HttpResponse::BadRequest().body(format!("Error retargeting your patch: {reason}"))
+        }
+    }
+}
+
+/// Lint a patch without building it, the request body being the contents of the `.pd` file
+#[post("/api/lint")]
+async fn lint_route(patch_contents: String) -> impl Responder {
+    match validate_patch_file_contents(&patch_contents) {
+        Ok(patch) => HttpResponse::Ok().content_type(ContentType::json()).body(
+            serde_json::to_string(&LintResponse {
+                findings: lint_patch(&patch, &HashSet::new()),
+            })
+            .unwrap(),
+        ),
+        Err(reason) => {
+            HttpResponse::BadRequest().body(format!("Error reading your patch: {reason}"))
+        }
+    }
+}
+
+#[get("/api/toolchains")]
+async fn list_toolchains_route() -> impl Responder {
+    ToolchainListResponse {
+        toolchains: get_toolchains(),
+    }
+}
+
+#[post("/api/worker/lease")]
+async fn worker_lease_route(
+    req: HttpRequest,
+    lease_request: web::Json<LeaseRequest>,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    if !is_worker_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("You are not authenticated!");
+    }
+
+    match lease_next_patch(&patches_store, &lease_request.worker_name) {
+        Some(lease_response) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&lease_response).unwrap()),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+#[post("/api/worker/leases/{lease_id}/heartbeat")]
+async fn worker_heartbeat_route(
+    req: HttpRequest,
+    path: web::Path<String>,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    if !is_worker_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("You are not authenticated!");
+    }
+
+    let lease_id = path.into_inner();
+
+    if renew_lease(&patches_store, &lease_id) {
+        HttpResponse::Ok().body("Lease renewed")
+    } else {
+        HttpResponse::NotFound().body("Lease not found")
+    }
+}
+
+#[get("/api/worker/leases/{lease_id}/files/{filename:.*}")]
+async fn worker_file_route(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    patches_store: web::Data<PatchesStore>,
+) -> Result<HttpResponse> {
+    if !is_worker_authenticated(&req) {
+        return Ok(HttpResponse::Unauthorized().body("You are not authenticated!"));
+    }
+
+    let (lease_id, filename) = path.into_inner();
+
+    match get_leased_patch(&patches_store, &lease_id) {
+        Some(patch_meta) if patch_meta.upload_filenames().contains(&filename) => {
+            let mut file_path = get_env_config().dir_workspace;
+            file_path.push("uploads");
+            file_path.push(&filename);
+
+            Ok(NamedFile::open_async(file_path).await?.into_response(&req))
+        }
+        _ => Ok(HttpResponse::NotFound().body("File not found")),
+    }
+}
+
+#[put("/api/worker/leases/{lease_id}/artifacts/{filename}")]
+async fn worker_artifact_route(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    body: web::Bytes,
+    patches_store: web::Data<PatchesStore>,
+) -> Result<HttpResponse> {
+    if !is_worker_authenticated(&req) {
+        return Ok(HttpResponse::Unauthorized().body("You are not authenticated!"));
+    }
+
+    let (lease_id, filename) = path.into_inner();
+
+    match get_leased_patch(&patches_store, &lease_id) {
+        Some(patch_meta) if patch_meta.artifact_filenames().contains(&filename) => {
+            let mut file_path = get_env_config().dir_workspace;
+            file_path.push("downloads");
+            file_path.push(&filename);
+
+            web::block(move || fs::write(file_path, body)).await??;
+
+            Ok(HttpResponse::Ok().body("Artifact saved"))
+        }
+        _ => Ok(HttpResponse::NotFound().body("Unexpected artifact")),
+    }
+}
+
+#[post("/api/worker/leases/{lease_id}/complete")]
+async fn worker_complete_route(
+    req: HttpRequest,
+    path: web::Path<String>,
+    finished_patch: web::Json<PatchMeta>,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    if !is_worker_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("You are not authenticated!");
+    }
+
+    let lease_id = path.into_inner();
+
+    if complete_lease(&patches_store, &lease_id, finished_patch.into_inner()) {
+        HttpResponse::Ok().body("Build results saved")
+    } else {
+        HttpResponse::NotFound().body("Lease not found")
+    }
+}
+
+#[post("/api/worker/leases/{lease_id}/release")]
+async fn worker_release_route(
+    req: HttpRequest,
+    path: web::Path<String>,
+    patches_store: web::Data<PatchesStore>,
+) -> impl Responder {
+    if !is_worker_authenticated(&req) {
+        return HttpResponse::Unauthorized().body("You are not authenticated!");
+    }
+
+    let lease_id = path.into_inner();
+
+    if release_lease(&patches_store, &lease_id) {
+        HttpResponse::Ok().body("Lease released")
+    } else {
+        HttpResponse::NotFound().body("Lease not found")
+    }
+}
+
 #[get("/health/live")]
 pub async fn liveness_probe_route() -> impl Responder {
     HttpResponse::Ok().body("App is live")
 }
 
 #[get("/health/ready")]
-pub async fn readiness_probe_route() -> impl Responder {
-    HttpResponse::Ok().body("App is ready to receive traffic")
+pub async fn readiness_probe_route(
+    worker_health: web::Data<WorkerHealth>,
+    self_test_store: web::Data<SelfTestStore>,
+    dependency_store: web::Data<DependencyStore>,
+) -> impl Responder {
+    let env_config = get_env_config();
+    let mut problems: Vec<String> = vec![];
+
+    if env_config.local_compilation_worker && !worker_health.is_healthy() {
+        problems.push(format!(
+            "Compilation worker is stuck or crashing ({} restarts)",
+            worker_health.restarts()
+        ));
+    }
+
+    if env_config.self_test_enabled {
+        match self_test_store.latest_passed() {
+            None => problems.push("Self-test has not finished yet".to_string()),
+            Some(false) => {
+                problems.push("Self-test failed, see /api/selftest for details".to_string())
+            }
+            Some(true) => {}
+        }
+    }
+
+    let dependencies = match dependency_store.latest_statuses.lock().unwrap().clone() {
+        Some(dependencies) => dependencies,
+        None => {
+            problems.push("Dependency checks have not finished yet".to_string());
+            vec![]
+        }
+    };
+
+    for dependency in dependencies.iter().filter(|dependency| !dependency.ok) {
+        problems.push(format!("Missing dependency: {}", dependency.name));
+    }
+
+    let readiness_report = ReadinessReport {
+        ready: problems.is_empty(),
+        problems,
+        dependencies,
+    };
+
+    let mut response = if readiness_report.ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+
+    response
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&readiness_report).unwrap())
+}
+
+/// Copy a patch's metadata out of the store, so the lock isn't held while reading its files
+fn get_patch_meta(patch_id: &str, patches_store: &PatchesStore) -> Option<PatchMeta> {
+    let patches = patches_store.patches.lock().unwrap();
+
+    patches.get(patch_id).cloned()
+}
+
+fn get_group_patches(group: &BuildGroup, patches: &HashMap<String, PatchMeta>) -> Vec<PatchMeta> {
+    group
+        .patch_ids
+        .iter()
+        .filter_map(|patch_id| patches.get(patch_id).cloned())
+        .collect()
 }
 
 fn is_authenticated(req: &HttpRequest) -> bool {
-    match req.headers().get("Authentication") {
-        Some(auth_header) => {
-            let header_value = auth_header.to_str().unwrap();
+    matches_auth_header(req, get_env_config().admin_token)
+}
+
+fn is_worker_authenticated(req: &HttpRequest) -> bool {
+    matches_auth_header(req, get_env_config().worker_token)
+}
 
-            header_value == get_env_config().admin_token
+fn matches_auth_header(req: &HttpRequest, token: Option<String>) -> bool {
+    match (req.headers().get("Authentication"), token) {
+        (Some(auth_header), Some(token)) => {
+            let header_value = auth_header.to_str().unwrap_or_default();
+
+            header_value == token
         }
-        None => false,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn requires_the_exact_token_in_the_authentication_header() {
+        let token = Some("secret".to_string());
+
+        let matching = TestRequest::default()
+            .insert_header(("Authentication", "secret"))
+            .to_http_request();
+        assert!(matches_auth_header(&matching, token.clone()));
+
+        for header in ["wrong", "secret ", "Bearer secret", ""] {
+            let request = TestRequest::default()
+                .insert_header(("Authentication", header))
+                .to_http_request();
+            assert!(!matches_auth_header(&request, token.clone()), "{header:?}");
+        }
+
+        let authorization = TestRequest::default()
+            .insert_header(("Authorization", "secret"))
+            .to_http_request();
+        assert!(!matches_auth_header(&authorization, token));
+
+        // Without a configured token, nothing gets in
+        assert!(!matches_auth_header(&matching, None));
     }
 }
@@ -0,0 +1,393 @@
+use anyhow::{anyhow, Result};
+use hound::{SampleFormat, WavReader};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+use crate::memory_layout::MemoryLayout;
+use crate::patch_edits::PatchEdits;
+use crate::patch_files::PatchFile;
+use crate::pd_parser::{Canvas, ElementKind, PdArray, PdPatch};
+
+/// Samples are built into the program and copied into SDRAM when it starts, so they
+/// have to fit next to the code in QSPI flash, the smaller of the two
+const SAMPLE_BUDGET_BYTES: usize = 6 * 1024 * 1024;
+
+/// Heavy stores every table value as a 32-bit float
+const BYTES_PER_VALUE: usize = 4;
+
+/// Sample rates a WAV file may claim, since the converted length is worked out from it
+const MIN_SOURCE_SAMPLE_RATE: u32 = 8000;
+const MAX_SOURCE_SAMPLE_RATE: u32 = 192000;
+
+/// Pd saves array contents in chunks of this many values per `#A` record
+const VALUES_PER_RECORD: usize = 1000;
+
+/// A WAV file that was written into one of the patch's arrays
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddedSample {
+    pub filename: String,
+    pub array: String,
+    /// Length of the array after resampling
+    pub frames: usize,
+    pub source_channels: u16,
+    pub source_sample_rate: u32,
+    pub source_bits: u16,
+}
+
+/// The array a sample belongs in, named after the file
+fn array_name(sample_file: &PatchFile) -> &str {
+    let filename = sample_file.path.rsplit('/').next().unwrap_or_default();
+
+    match filename.rsplit_once('.') {
+        Some((stem, _)) => stem,
+        None => filename,
+    }
+}
+
+/// Samples are too big for the internal SRAM, so the heap has to move out to SDRAM,
+/// which only happens when the program is loaded by the bootloader. Programs that are
+/// too big for BOOT_SRAM once the samples are built in move on to BOOT_QSPI.
+pub fn memory_layout_for_samples(
+    memory_layout: &MemoryLayout,
+    samples: &[EmbeddedSample],
+    program_bytes: usize,
+) -> MemoryLayout {
+    if samples.is_empty() {
+        return memory_layout.clone();
+    }
+
+    let mut layout = match memory_layout {
+        MemoryLayout::Flash => MemoryLayout::BootSram,
+        _ => memory_layout.clone(),
+    };
+
+    while program_bytes > layout.program_budget_bytes() {
+        match layout.fallback() {
+            Some(fallback) => layout = fallback,
+            None => break,
+        }
+    }
+
+    layout
+}
+
+/// Write every uploaded WAV file into the array with the same name, like `kick.wav` into
+/// the `kick` array, returning the rewritten patch
+pub fn embed_samples(
+    contents: &str,
+    patch: &PdPatch,
+    sample_files: &[PatchFile],
+    sample_rate: u32,
+) -> Result<(String, Vec<EmbeddedSample>)> {
+    let mut arrays: Vec<&PdArray> = vec![];
+    collect_arrays(&patch.root, &mut arrays);
+
+    let mut edits = PatchEdits::default();
+    let mut samples: Vec<EmbeddedSample> = vec![];
+    let mut total_bytes = 0;
+
+    for sample_file in sample_files {
+        let array_name = array_name(sample_file);
+
+        if samples.iter().any(|sample| sample.array == array_name) {
+            return Err(anyhow!(
+                "There is more than one sample for the \"{array_name}\" array"
+            ));
+        }
+        let array = arrays
+            .iter()
+            .find(|array| array.name == array_name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "The sample {} needs an array called \"{}\" in the main patch to go in",
+                    sample_file.path,
+                    array_name
+                )
+            })?;
+
+        let remaining_bytes = SAMPLE_BUDGET_BYTES - total_bytes;
+        let (values, sample) = decode_wav(sample_file, sample_rate, remaining_bytes)?;
+
+        total_bytes += values.len() * BYTES_PER_VALUE;
+
+        write_array(patch, array, &values, &mut edits);
+        samples.push(sample);
+    }
+
+    Ok((edits.apply(contents, &patch.records), samples))
+}
+
+fn collect_arrays<'a>(canvas: &'a Canvas, arrays: &mut Vec<&'a PdArray>) {
+    arrays.extend(canvas.arrays.iter());
+
+    for element in canvas.elements.iter() {
+        if let ElementKind::Subpatch { canvas } | ElementKind::Graph { canvas } = &element.kind {
+            collect_arrays(canvas, arrays);
+        }
+    }
+}
+
+/// Resize the array to fit the sample, mark it to keep its contents, and replace those contents
+fn write_array(patch: &PdPatch, array: &PdArray, values: &[f32], edits: &mut PatchEdits) {
+    let array_record = array.position.record;
+
+    // `#X array name size float flags`, where flag 1 saves the contents
+    let mut tokens = patch.records[array_record].tokens.clone();
+    tokens[3] = values.len().to_string();
+    let flags = tokens
+        .get(5)
+        .and_then(|flags| flags.parse::<u32>().ok())
+        .unwrap_or(0);
+    tokens.truncate(5);
+    tokens.push((flags | 1).to_string());
+    edits.replaced.insert(array_record, tokens);
+
+    // Drop whatever contents were saved before
+    for (record_index, record) in patch.records.iter().enumerate().skip(array_record + 1) {
+        if record.tokens[0] != "#A" {
+            break;
+        }
+
+        edits.removed.insert(record_index);
+    }
+
+    let data_records = edits.inserted.entry(array_record + 1).or_default();
+    for (chunk_index, chunk) in values.chunks(VALUES_PER_RECORD).enumerate() {
+        let mut tokens = vec![
+            "#A".to_string(),
+            (chunk_index * VALUES_PER_RECORD).to_string(),
+        ];
+        tokens.extend(chunk.iter().map(|value| value.to_string()));
+
+        data_records.push(tokens);
+    }
+}
+
+/// Read a WAV file as mono floats at the patch's sample rate, as long as it fits in `remaining_bytes`
+fn decode_wav(
+    sample_file: &PatchFile,
+    sample_rate: u32,
+    remaining_bytes: usize,
+) -> Result<(Vec<f32>, EmbeddedSample)> {
+    let error =
+        |err: hound::Error| anyhow!("Could not read the sample {}: {}", sample_file.path, err);
+
+    let reader = WavReader::new(Cursor::new(&sample_file.contents)).map_err(error)?;
+    let spec = reader.spec();
+
+    if !(MIN_SOURCE_SAMPLE_RATE..=MAX_SOURCE_SAMPLE_RATE).contains(&spec.sample_rate) {
+        return Err(anyhow!(
+            "The sample {} has a sample rate of {} Hz, expected {} to {} Hz",
+            sample_file.path,
+            spec.sample_rate,
+            MIN_SOURCE_SAMPLE_RATE,
+            MAX_SOURCE_SAMPLE_RATE
+        ));
+    }
+
+    // Check the converted length before decoding anything, so a bad header can't ask for too much memory
+    let frames = resampled_length(reader.duration() as usize, spec.sample_rate, sample_rate);
+    if frames.saturating_mul(BYTES_PER_VALUE) > remaining_bytes {
+        return Err(anyhow!(
+            "The samples need more than {} MB of memory once converted to {} Hz",
+            SAMPLE_BUDGET_BYTES / 1024 / 1024,
+            sample_rate
+        ));
+    }
+
+    let interleaved: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .collect::<Result<_, _>>()
+            .map_err(error)?,
+        SampleFormat::Int => {
+            let full_scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+
+            reader
+                .into_samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / full_scale))
+                .collect::<Result<_, _>>()
+                .map_err(error)?
+        }
+    };
+
+    // Average the channels, since Heavy tables only hold one
+    let channels = spec.channels.max(1) as usize;
+    let mono: Vec<f32> = interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect();
+
+    let values = resample(&mono, spec.sample_rate, sample_rate);
+
+    let sample = EmbeddedSample {
+        filename: sample_file.path.clone(),
+        array: array_name(sample_file).to_string(),
+        frames: values.len(),
+        source_channels: spec.channels,
+        source_sample_rate: spec.sample_rate,
+        source_bits: spec.bits_per_sample,
+    };
+
+    Ok((values, sample))
+}
+
+/// Linear interpolation is enough for one-off conversion of samples that get played back as tables
+fn resample(values: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || values.is_empty() {
+        return values.to_vec();
+    }
+
+    let step = from_rate as f64 / to_rate as f64;
+
+    (0..resampled_length(values.len(), from_rate, to_rate))
+        .map(|frame| {
+            let position = frame as f64 * step;
+            let index = position.floor() as usize;
+            let fraction = (position - index as f64) as f32;
+
+            let current = values[index.min(values.len() - 1)];
+            let next = values[(index + 1).min(values.len() - 1)];
+
+            current + (next - current) * fraction
+        })
+        .collect()
+}
+
+fn resampled_length(frames: usize, from_rate: u32, to_rate: u32) -> usize {
+    if from_rate == to_rate || frames == 0 {
+        return frames;
+    }
+
+    ((frames as f64) * to_rate as f64 / from_rate as f64)
+        .floor()
+        .max(1.0) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_estimate::estimate_memory;
+    use crate::pd_parser::parse_patch;
+    use hound::{WavSpec, WavWriter};
+
+    const PATCH: &str = "#N canvas 0 50 450 300 12;
+#N canvas 0 50 450 250 (subpatch) 0;
+#X array kick 10 float 0;
+#X coords 0 1 10 -1 200 140 1 0 0;
+#X restore 20 20 graph;
+";
+
+    fn wav_file(path: &str, sample_rate: u32, channels: u16, frames: usize) -> PatchFile {
+        let spec = WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+
+        let mut contents = Cursor::new(vec![]);
+        let mut writer = WavWriter::new(&mut contents, spec).unwrap();
+        for frame in 0..frames {
+            for channel in 0..channels {
+                let value = if channel == 0 {
+                    (frame % 100) as i16 * 100
+                } else {
+                    0
+                };
+                writer.write_sample(value).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+
+        PatchFile {
+            path: path.to_string(),
+            contents: contents.into_inner(),
+        }
+    }
+
+    #[test]
+    fn writes_resampled_mono_sample_into_array() {
+        let patch = parse_patch(PATCH).unwrap();
+        let sample_files = [wav_file("samples/kick.wav", 24000, 2, 100)];
+
+        let (contents, samples) = embed_samples(PATCH, &patch, &sample_files, 48000).unwrap();
+
+        assert_eq!(samples[0].array, "kick");
+        assert_eq!(samples[0].frames, 200);
+        assert_eq!(samples[0].source_channels, 2);
+
+        let rewritten = parse_patch(&contents).unwrap();
+        let graph = match &rewritten.root.elements[0].kind {
+            ElementKind::Graph { canvas } => canvas,
+            other => panic!("expected a graph, got {other:?}"),
+        };
+        assert_eq!(graph.arrays[0].size, 200);
+        assert!(graph.arrays[0].save_contents);
+        assert_eq!(graph.arrays[0].values.len(), 200);
+    }
+
+    #[test]
+    fn rejects_samples_without_an_array() {
+        let patch = parse_patch(PATCH).unwrap();
+        let sample_files = [wav_file("snare.wav", 48000, 1, 10)];
+
+        assert!(embed_samples(PATCH, &patch, &sample_files, 48000).is_err());
+    }
+
+    #[test]
+    fn rejects_implausible_sample_rates_before_allocating() {
+        let patch = parse_patch(PATCH).unwrap();
+        // 200k frames claimed at 1 Hz would resample to 9.6 billion frames at 48 kHz
+        let sample_files = [wav_file("kick.wav", 1, 1, 200_000)];
+
+        assert!(embed_samples(PATCH, &patch, &sample_files, 48000).is_err());
+    }
+
+    #[test]
+    fn rejects_samples_over_the_budget_before_allocating() {
+        let patch = parse_patch(PATCH).unwrap();
+        // 0.5M frames at 8 kHz would take 12 MB at 48 kHz
+        let sample_files = [wav_file("kick.wav", 8000, 1, 500_000)];
+
+        let err = embed_samples(PATCH, &patch, &sample_files, 48000).unwrap_err();
+        assert!(err.to_string().contains("MB of memory"));
+    }
+
+    #[test]
+    fn moves_to_the_smallest_bootloader_layout_the_samples_fit_in() {
+        let layout_for = |frames: usize, memory_layout: MemoryLayout| {
+            let patch = parse_patch(PATCH).unwrap();
+            let sample_files = [wav_file("kick.wav", 48000, 1, frames)];
+            let (contents, samples) = embed_samples(PATCH, &patch, &sample_files, 48000).unwrap();
+
+            let patch = parse_patch(&contents).unwrap();
+            let program_bytes = estimate_memory(&patch, &memory_layout, 48000).program_bytes;
+
+            memory_layout_for_samples(&memory_layout, &samples, program_bytes)
+        };
+
+        assert_eq!(
+            layout_for(1000, MemoryLayout::Flash),
+            MemoryLayout::BootSram
+        );
+        assert_eq!(
+            layout_for(1000, MemoryLayout::BootQspi),
+            MemoryLayout::BootQspi
+        );
+        // 200k frames take 800 KB, more than BOOT_SRAM can load
+        assert_eq!(
+            layout_for(200_000, MemoryLayout::Flash),
+            MemoryLayout::BootQspi
+        );
+        assert_eq!(
+            layout_for(200_000, MemoryLayout::BootSram),
+            MemoryLayout::BootQspi
+        );
+
+        assert_eq!(
+            memory_layout_for_samples(&MemoryLayout::Flash, &[], 1024 * 1024),
+            MemoryLayout::Flash
+        );
+    }
+}
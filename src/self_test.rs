@@ -0,0 +1,206 @@
+use log::{error, info};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::boards::Board;
+use crate::build_options::{BuildOptions, BuildProfile};
+use crate::compilation_worker::compile_patch;
+use crate::env_config::{get_env_config, EnvConfig};
+use crate::memory_layout::MemoryLayout;
+use crate::patches::{get_workspace_path, remove_patch_files, DateTime, PatchMeta, PatchStatus};
+use crate::resource_usage::ResourceUsage;
+use crate::toolchains::DEFAULT_TOOLCHAIN_NAME;
+
+/// A tiny patch that every board should be able to compile
+const CANARY_PATCH: &str = include_str!("../canary/canary.pd");
+const CANARY_BOARD_DEF: &str = include_str!("../canary/seed_board_def.json");
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SelfTestResult {
+    pub board: Board,
+    pub passed: bool,
+    pub error: Option<String>,
+    pub duration_secs: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub time_start: DateTime,
+    pub time_end: DateTime,
+    pub results: Vec<SelfTestResult>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct SelfTestStore {
+    pub running: Mutex<bool>,
+    pub latest_report: Mutex<Option<SelfTestReport>>,
+}
+
+impl SelfTestStore {
+    /// Whether the most recent self-test passed. `None` until the first one finishes.
+    pub fn latest_passed(&self) -> Option<bool> {
+        self.latest_report
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|report| report.passed)
+    }
+}
+
+/// Sets `running` for as long as it lives, so it is cleared even when the self-test is cancelled or panics
+struct RunningGuard<'a> {
+    running: &'a Mutex<bool>,
+}
+
+impl<'a> RunningGuard<'a> {
+    fn start(running: &'a Mutex<bool>) -> Self {
+        *running.lock().unwrap() = true;
+
+        RunningGuard { running }
+    }
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        *self.running.lock().unwrap_or_else(PoisonError::into_inner) = false;
+    }
+}
+
+/// Compile the canary patch for every board on startup, and again on an interval
+pub async fn spawn_self_test(self_test_store: Arc<SelfTestStore>, stop_signal: CancellationToken) {
+    let env_config = get_env_config();
+
+    loop {
+        let running = RunningGuard::start(&self_test_store.running);
+
+        let report = tokio::select! {
+            report = run_self_test(&env_config) => report,
+
+            _ = stop_signal.cancelled() => {
+                break;
+            }
+        };
+
+        if report.passed {
+            info!("Self-test passed for all boards");
+        } else {
+            error!("Self-test failed, the app will not report itself as ready");
+        }
+
+        *self_test_store.latest_report.lock().unwrap() = Some(report);
+        drop(running);
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(env_config.self_test_interval_secs)) => {
+                continue;
+            }
+
+            _ = stop_signal.cancelled() => {
+                break;
+            }
+        };
+    }
+
+    info!("gracefully shutting down self-test...");
+}
+
+async fn run_self_test(env_config: &EnvConfig) -> SelfTestReport {
+    info!("Running self-test...");
+
+    let time_start = DateTime::now();
+    let mut results: Vec<SelfTestResult> = vec![];
+
+    for board in Board::all() {
+        let started_at = Instant::now();
+
+        let error = match compile_canary(&board, env_config).await {
+            Ok(()) => None,
+            Err(err) => {
+                error!("Self-test failed for board {}: {}", board.to_str(), err);
+                Some(err)
+            }
+        };
+
+        results.push(SelfTestResult {
+            board,
+            passed: error.is_none(),
+            error,
+            duration_secs: started_at.elapsed().as_secs_f64(),
+        });
+    }
+
+    SelfTestReport {
+        passed: results.iter().all(|result| result.passed),
+        time_start,
+        time_end: DateTime::now(),
+        results,
+    }
+}
+
+async fn compile_canary(board: &Board, env_config: &EnvConfig) -> Result<(), String> {
+    let patch = PatchMeta {
+        id: format!("selftest-{}-{}", board.to_str(), Uuid::new_v4()),
+        status: PatchStatus::Uploaded,
+        board: board.clone(),
+        memory_layout_requested: MemoryLayout::Flash,
+        memory_layout: MemoryLayout::Flash,
+        build_options: BuildOptions::default(),
+        build_profile: BuildProfile::Release,
+        toolchain: DEFAULT_TOOLCHAIN_NAME.to_string(),
+        group_id: None,
+        origin_patch_id: None,
+        compatibility_warnings: vec![],
+        lint_findings: vec![],
+        parameters: None,
+        autofix: None,
+        project_files: vec![],
+        search_paths: vec![],
+        samples: vec![],
+        memory_estimate: None,
+        parameter_mappings: vec![],
+        compiler_cache: None,
+        resource_usage: None,
+        filename: "canary.pd".to_string(),
+        time_upload: DateTime::now(),
+        time_compile_start: None,
+        time_compile_end: None,
+    };
+
+    let write_result = write_canary_files(&patch, env_config).await;
+
+    let compilation_result = match write_result {
+        Ok(()) => compile_patch(&patch, &mut ResourceUsage::default())
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(format!("could not write canary patch: {err}")),
+    };
+
+    remove_patch_files(&patch, env_config).await;
+
+    compilation_result
+}
+
+async fn write_canary_files(patch: &PatchMeta, env_config: &EnvConfig) -> std::io::Result<()> {
+    fs::write(
+        get_workspace_path(env_config, "uploads", &patch.patch_upload_filename()),
+        CANARY_PATCH,
+    )
+    .await?;
+
+    if let Board::SeedCustomJson = patch.board {
+        fs::write(
+            get_workspace_path(env_config, "uploads", &patch.board_def_upload_filename()),
+            CANARY_BOARD_DEF,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,219 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::env_config::EnvConfig;
+use crate::patches::{BuildGroup, PatchMeta, PatchStatus, PatchesStore};
+
+/// Where the queue is saved between restarts, inside the workspace directory
+const QUEUE_FILENAME: &str = "queue.json";
+
+#[derive(Serialize, Deserialize)]
+struct SavedQueue {
+    patches: Vec<PatchMeta>,
+    groups: Vec<BuildGroup>,
+}
+
+/// Run `work` to completion, unless the stop signal fires and it is still running once
+/// the drain deadline has passed. Returns `None` when the work was aborted.
+pub async fn run_until_drain_deadline<F: Future>(
+    work: F,
+    stop_signal: &CancellationToken,
+    drain_deadline: Duration,
+) -> Option<F::Output> {
+    tokio::pin!(work);
+
+    tokio::select! {
+        output = &mut work => return Some(output),
+
+        _ = stop_signal.cancelled() => {}
+    };
+
+    info!(
+        "Shutting down, waiting up to {}s for the current build to finish...",
+        drain_deadline.as_secs()
+    );
+
+    tokio::select! {
+        output = &mut work => {
+            info!("Current build finished before shutting down");
+            Some(output)
+        }
+
+        _ = sleep(drain_deadline) => {
+            warn!("Current build did not finish in time, aborting it");
+            None
+        }
+    }
+}
+
+/// Save every patch that still needs to be built, including the ones leased to remote workers
+pub fn save_queue(patches_store: &PatchesStore, env_config: &EnvConfig) {
+    let queue = patches_store.compilation_queue.lock().unwrap();
+    let patches = patches_store.patches.lock().unwrap();
+    let leases = patches_store.leases.lock().unwrap();
+    let groups = patches_store.groups.lock().unwrap();
+
+    let leased_patch_ids = leases.values().map(|lease| &lease.patch_id);
+
+    let saved_patches: Vec<PatchMeta> = queue
+        .iter()
+        .chain(leased_patch_ids)
+        .filter_map(|patch_id| patches.get(patch_id))
+        .map(|patch| PatchMeta {
+            status: PatchStatus::Uploaded,
+            time_compile_start: None,
+            ..patch.clone()
+        })
+        .collect();
+
+    let saved_groups: Vec<BuildGroup> = groups
+        .values()
+        .filter(|group| {
+            saved_patches
+                .iter()
+                .any(|patch| patch.group_id.as_ref() == Some(&group.id))
+        })
+        .cloned()
+        .collect();
+
+    if saved_patches.is_empty() {
+        info!("Compilation queue is empty, nothing to save");
+        return;
+    }
+
+    let saved_queue = SavedQueue {
+        patches: saved_patches,
+        groups: saved_groups,
+    };
+
+    let queue_path = get_queue_path(env_config);
+
+    match fs::write(&queue_path, serde_json::to_string(&saved_queue).unwrap()) {
+        Ok(()) => info!(
+            "Saved {} queued patches to {}",
+            saved_queue.patches.len(),
+            queue_path.display()
+        ),
+        Err(err) => error!(
+            "Failed to save {} queued patches to {}: {}",
+            saved_queue.patches.len(),
+            queue_path.display(),
+            err
+        ),
+    }
+}
+
+/// Put back any patches that were still queued when the server last shut down
+pub fn restore_queue(patches_store: &PatchesStore, env_config: &EnvConfig) {
+    let queue_path = get_queue_path(env_config);
+
+    let contents = match fs::read_to_string(&queue_path) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+
+    let saved_queue: SavedQueue = match serde_json::from_str(&contents) {
+        Ok(saved_queue) => saved_queue,
+        Err(err) => {
+            error!(
+                "Ignoring invalid saved queue {}: {}",
+                queue_path.display(),
+                err
+            );
+            return;
+        }
+    };
+
+    let mut queue = patches_store.compilation_queue.lock().unwrap();
+    let mut patches = patches_store.patches.lock().unwrap();
+    let mut groups = patches_store.groups.lock().unwrap();
+
+    for patch in saved_queue.patches.iter() {
+        queue.push_back(patch.id.clone());
+        patches.insert(patch.id.clone(), patch.clone());
+    }
+
+    for group in saved_queue.groups {
+        groups.insert(group.id.clone(), group);
+    }
+
+    info!(
+        "Restored {} queued patches from {}",
+        saved_queue.patches.len(),
+        queue_path.display()
+    );
+
+    // The queue is only restored once, it is saved again on the next shutdown
+    if let Err(err) = fs::remove_file(&queue_path) {
+        warn!("Failed to remove {}: {}", queue_path.display(), err);
+    }
+}
+
+fn get_queue_path(env_config: &EnvConfig) -> PathBuf {
+    let mut queue_path = env_config.dir_workspace.clone();
+    queue_path.push(QUEUE_FILENAME);
+
+    queue_path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compilation_worker::get_compiling_patch;
+    use crate::env_config::tests::init_test_env;
+    use crate::leases::lease_next_patch;
+    use crate::patches::requeue_patch;
+    use crate::patches::tests::{queued_store, uploaded_patch};
+
+    #[test]
+    fn restores_in_progress_patches_as_queued_in_order() {
+        let env_config = init_test_env();
+        let patches_store = queued_store(vec![
+            uploaded_patch("leased"),
+            uploaded_patch("aborted"),
+            uploaded_patch("queued"),
+        ]);
+
+        lease_next_patch(&patches_store, "remote").unwrap();
+
+        // The local build that was still running at the drain deadline
+        let aborted_id = patches_store
+            .compilation_queue
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap();
+        let aborted_patch = patches_store.patches.lock().unwrap()[&aborted_id].clone();
+        patches_store
+            .patches
+            .lock()
+            .unwrap()
+            .insert(aborted_id.clone(), get_compiling_patch(aborted_patch));
+        requeue_patch(&patches_store, &aborted_id);
+
+        save_queue(&patches_store, &env_config);
+
+        let restored_store = PatchesStore::default();
+        restore_queue(&restored_store, &env_config);
+
+        assert_eq!(
+            *restored_store.compilation_queue.lock().unwrap(),
+            vec![
+                "aborted".to_string(),
+                "queued".to_string(),
+                "leased".to_string()
+            ]
+        );
+        for patch in restored_store.patches.lock().unwrap().values() {
+            assert!(matches!(patch.status, PatchStatus::Uploaded));
+            assert!(patch.time_compile_start.is_none());
+        }
+        assert!(!get_queue_path(&env_config).exists());
+    }
+}
@@ -0,0 +1,70 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::env_config::get_env_config;
+
+pub const DEFAULT_TOOLCHAIN_NAME: &str = "stable";
+
+lazy_static! {
+    static ref TOOLCHAINS: Vec<Toolchain> = load_toolchains();
+}
+
+/// An installed pd2dsy checkout, along with the library and compiler versions it builds with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Toolchain {
+    pub name: String,
+
+    #[serde(skip_serializing)]
+    pub dir_pd2dsy: PathBuf,
+
+    /// Directory with the `arm-none-eabi-*` binaries, if they are not already on the `PATH`
+    #[serde(skip_serializing, default)]
+    pub dir_arm_gcc_bin: Option<PathBuf>,
+
+    pub pd2dsy_revision: String,
+    pub libdaisy_version: String,
+    pub daisysp_version: String,
+    pub arm_gcc_version: String,
+}
+
+pub fn get_toolchains() -> &'static [Toolchain] {
+    &TOOLCHAINS
+}
+
+pub fn get_toolchain(name: &str) -> Option<&'static Toolchain> {
+    TOOLCHAINS.iter().find(|toolchain| toolchain.name == name)
+}
+
+fn load_toolchains() -> Vec<Toolchain> {
+    let env_config = get_env_config();
+
+    let toolchains: Vec<Toolchain> = match env_config.toolchains_file {
+        Some(toolchains_file) => {
+            let file_contents = fs::read_to_string(&toolchains_file).unwrap_or_else(|_| {
+                panic!("Could not read toolchains file: {:?}", toolchains_file)
+            });
+
+            serde_json::from_str(&file_contents).expect("Invalid toolchains file")
+        }
+        None => vec![Toolchain {
+            name: DEFAULT_TOOLCHAIN_NAME.to_string(),
+            dir_pd2dsy: env_config.dir_pd2dsy,
+            dir_arm_gcc_bin: None,
+            pd2dsy_revision: "unknown".to_string(),
+            libdaisy_version: "unknown".to_string(),
+            daisysp_version: "unknown".to_string(),
+            arm_gcc_version: "unknown".to_string(),
+        }],
+    };
+
+    if !toolchains
+        .iter()
+        .any(|toolchain| toolchain.name == DEFAULT_TOOLCHAIN_NAME)
+    {
+        panic!("Toolchains file must define a toolchain named \"{DEFAULT_TOOLCHAIN_NAME}\"");
+    }
+
+    toolchains
+}
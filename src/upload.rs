@@ -11,8 +11,26 @@ use std::io::prelude::*;
 use std::str::FromStr;
 use uuid::Uuid;
 
+use crate::autofix::{autofix_patch, AutofixReport};
 use crate::boards::Board;
-use crate::patches::{validate_patch_file_contents, DateTime, PatchMeta, PatchStatus};
+use crate::build_options::{BuildOptions, BuildProfile, BUILD_OPTION_FORM_FIELDS};
+use crate::env_config::get_env_config;
+use crate::heavy_compat::check_heavy_compatibility;
+use crate::lint::lint_patch;
+use crate::memory_estimate::estimate_memory;
+use crate::memory_layout::MemoryLayout;
+use crate::parameter_mapping::{
+    apply_parameter_mappings, parse_parameter_mappings, ParameterMapping,
+};
+use crate::parameters::{board_controls, check_parameters};
+use crate::patch_files::{
+    abstraction_names, choose_main_patch, expand_uploaded_files, resolve_search_paths, PatchFile,
+};
+use crate::patches::{
+    get_workspace_path, validate_patch_file_contents, BuildGroup, DateTime, PatchMeta, PatchStatus,
+};
+use crate::samples::{embed_samples, memory_layout_for_samples, EmbeddedSample};
+use crate::toolchains::{get_toolchain, DEFAULT_TOOLCHAIN_NAME};
 
 lazy_static! {
     static ref REGEX_FILENAME: Regex = Regex::new(r#"filename="(.*?)""#).unwrap();
@@ -20,42 +38,86 @@ lazy_static! {
 
 enum UploadFormItem {
     BoardOption(Board),
+    MemoryLayoutOption(MemoryLayout),
+    BuildProfileOption(BuildProfile),
+    ToolchainOption(String),
+    AutofixOption(bool),
+    MainPatchOption(String),
+    ParameterMappingsOption(Vec<ParameterMapping>),
+    BuildOption {
+        name: String,
+        value: String,
+    },
     BoardDefinitionUpload {
         filename: String,
         file_contents: String,
     },
+    /// A patch, an abstraction or an archive of them
     PatchFileUpload {
         filename: String,
-        file_contents: String,
+        file_contents: Vec<u8>,
     },
     Unrecognized,
 }
 
-pub async fn process_patch_upload(mut payload: Multipart) -> Result<PatchMeta> {
-    let mut board_in: Option<Board> = None;
+/// The patches created by one upload, one per requested board
+pub struct PatchUpload {
+    pub patches: Vec<PatchMeta>,
+    /// Only set when the upload targets more than one board
+    pub group: Option<BuildGroup>,
+}
+
+pub async fn process_patch_upload(mut payload: Multipart) -> Result<PatchUpload> {
+    let mut boards_in: Vec<Board> = vec![];
+    let mut memory_layout_in: Option<MemoryLayout> = None;
+    let mut build_options = BuildOptions::default();
+    let mut build_profile_in: Option<BuildProfile> = None;
+    let mut toolchain_in: Option<String> = None;
+    let mut autofix = false;
 
     let mut board_def_filename_in: Option<String> = None;
     let mut board_def_contents_in: Option<String> = None;
 
-    let mut patch_filename_in: Option<String> = None;
-    let mut patch_contents_in: Option<String> = None;
+    let mut patch_files_in: Vec<(String, Vec<u8>)> = vec![];
+    let mut main_patch_in: Option<String> = None;
+    let mut parameter_mappings: Vec<ParameterMapping> = vec![];
 
     while let Some(item) = payload.next().await {
         let mut field = item?;
         debug!("Item is a field: {:?}", field);
 
-        let mut field_contents: String = "".to_string();
+        // Archives are binary, so text fields are only decoded once the whole field is in
+        let mut field_contents: Vec<u8> = vec![];
 
         while let Some(chunk) = field.next().await {
-            let chunk_bytes = chunk?;
-            let chunk_contents = std::str::from_utf8(&chunk_bytes)?;
-            debug!("chunk contents:\n{}", chunk_contents);
-
-            field_contents.push_str(chunk_contents);
+            field_contents.extend_from_slice(&chunk?);
         }
 
-        match parse_upload_form_item(&field, &field_contents) {
-            UploadFormItem::BoardOption(board_value) => board_in = Some(board_value),
+        match parse_upload_form_item(&field, field_contents)? {
+            UploadFormItem::BoardOption(board_value) => {
+                if !boards_in.contains(&board_value) {
+                    boards_in.push(board_value);
+                }
+            }
+            UploadFormItem::MemoryLayoutOption(memory_layout_value) => {
+                memory_layout_in = Some(memory_layout_value)
+            }
+            UploadFormItem::BuildProfileOption(build_profile_value) => {
+                build_profile_in = Some(build_profile_value)
+            }
+            UploadFormItem::ToolchainOption(toolchain_value) => {
+                toolchain_in = Some(toolchain_value)
+            }
+            UploadFormItem::AutofixOption(autofix_value) => autofix = autofix_value,
+            UploadFormItem::MainPatchOption(main_patch_value) => {
+                main_patch_in = Some(main_patch_value)
+            }
+            UploadFormItem::ParameterMappingsOption(parameter_mappings_value) => {
+                parameter_mappings = parameter_mappings_value
+            }
+            UploadFormItem::BuildOption { name, value } => {
+                build_options.set_form_field(&name, &value)?;
+            }
             UploadFormItem::BoardDefinitionUpload {
                 filename: found_filename,
                 file_contents: found_contents,
@@ -67,8 +129,7 @@ pub async fn process_patch_upload(mut payload: Multipart) -> Result<PatchMeta> {
                 filename: found_filename,
                 file_contents: found_contents,
             } => {
-                patch_filename_in = Some(found_filename);
-                patch_contents_in = Some(found_contents);
+                patch_files_in.push((found_filename, found_contents));
             }
             UploadFormItem::Unrecognized => {
                 // Ignore missing files or unexpected items
@@ -76,19 +137,15 @@ pub async fn process_patch_upload(mut payload: Multipart) -> Result<PatchMeta> {
         }
     }
 
-    if board_in.is_none() {
+    if boards_in.is_empty() {
         return Err(anyhow!("Missing board option"));
     }
 
-    if patch_contents_in.is_none() {
+    if patch_files_in.is_empty() {
         return Err(anyhow!("Missing patch file"));
     }
 
-    if patch_filename_in.is_none() {
-        return Err(anyhow!("Missing filename"));
-    }
-
-    if let Some(Board::SeedCustomJson) = board_in {
+    if boards_in.contains(&Board::SeedCustomJson) {
         if board_def_filename_in.is_none() {
             return Err(anyhow!("Missing custom board definition filename"));
         }
@@ -98,49 +155,260 @@ pub async fn process_patch_upload(mut payload: Multipart) -> Result<PatchMeta> {
         }
     }
 
-    let board = board_in.unwrap();
-    let filename = patch_filename_in.unwrap();
-    let patch_contents = patch_contents_in.unwrap();
-
-    trace!("Board result: {:?}", board);
+    let memory_layout = memory_layout_in.unwrap_or(MemoryLayout::Flash);
+    let build_profile = build_profile_in.unwrap_or(BuildProfile::Release);
+    let toolchain = toolchain_in.unwrap_or_else(|| DEFAULT_TOOLCHAIN_NAME.to_string());
+
+    let mut project_files = expand_uploaded_files(patch_files_in)?;
+    let main_file =
+        project_files.remove(choose_main_patch(&project_files, main_patch_in.as_deref())?);
+    // Samples end up inside the patch, so they aren't kept as project files
+    let (sample_files, project_files): (Vec<PatchFile>, Vec<PatchFile>) =
+        project_files.into_iter().partition(PatchFile::is_sample);
+    let filename = main_file.path.clone();
+
+    trace!("Boards result: {:?}", boards_in);
+    trace!("Memory layout: {:?}", memory_layout);
+    trace!("Build options: {:?}", build_options);
+    trace!("Build profile: {:?}", build_profile);
+    trace!("Toolchain: {:?}", toolchain);
     trace!("Filename: {:?}", filename);
-    trace!("File contents: {:?}", patch_contents);
+    trace!(
+        "Other files: {:?}",
+        project_files
+            .iter()
+            .map(|file| &file.path)
+            .collect::<Vec<_>>()
+    );
     trace!("Board definition: {:?}", board_def_contents_in);
 
-    if !filename.ends_with(".pd") {
+    if !main_file.is_patch() {
         return Err(anyhow!("File does not appear to be a Pd patch"));
     }
 
-    validate_patch_file_contents(&patch_contents)?;
+    let patch_contents = String::from_utf8(main_file.contents.clone())
+        .map_err(|_| anyhow!("File does not appear to be a valid Pd patch"))?;
+    let mut parsed_patch = validate_patch_file_contents(&patch_contents)?;
 
-    let patch_id = Uuid::new_v4();
+    // A patch uploaded on its own has nothing to search for
+    let search_paths = if project_files.is_empty() {
+        vec![]
+    } else {
+        resolve_search_paths(&main_file, &parsed_patch)?
+    };
+    let known_abstractions = abstraction_names(&project_files, &search_paths);
+
+    // The original upload is kept next to the rewritten one, which is what gets built
+    let mut original_patch_contents: Option<String> = None;
+    let mut autofix_report: Option<AutofixReport> = None;
+    let mut patch_contents = patch_contents;
+
+    if autofix {
+        if let Some((fixed_contents, report)) =
+            autofix_patch(&patch_contents, &parsed_patch, &known_abstractions)
+        {
+            debug!("Auto-fix changes: {:?}", report.changes);
+
+            parsed_patch = validate_patch_file_contents(&fixed_contents)?;
+            original_patch_contents = Some(std::mem::replace(&mut patch_contents, fixed_contents));
+            autofix_report = Some(report);
+        }
+    }
 
-    let patch_meta = PatchMeta {
-        id: patch_id.to_string(),
-        status: PatchStatus::Uploaded,
-        board,
-        filename,
-        time_upload: DateTime::now(),
-        time_compile_start: None,
-        time_compile_end: None,
+    let mut compatibility = check_heavy_compatibility(&parsed_patch, &known_abstractions, true);
+
+    // Abstractions are compiled into the patch, so they need to work with Heavy too
+    for abstraction_file in project_files.iter().filter(|file| file.is_patch()) {
+        let abstraction_contents =
+            String::from_utf8(abstraction_file.contents.clone()).map_err(|_| {
+                anyhow!(
+                    "{} does not appear to be a valid Pd patch",
+                    abstraction_file.path
+                )
+            })?;
+        let abstraction = validate_patch_file_contents(&abstraction_contents)
+            .map_err(|err| anyhow!("{}: {}", abstraction_file.path, err))?;
+
+        let abstraction_compatibility =
+            // Automatic fixes only rewrite the main patch, not the abstractions it uses
+            check_heavy_compatibility(&abstraction, &known_abstractions, false);
+        for (issues, abstraction_issues) in [
+            (
+                &mut compatibility.unsupported,
+                abstraction_compatibility.unsupported,
+            ),
+            (
+                &mut compatibility.warnings,
+                abstraction_compatibility.warnings,
+            ),
+        ] {
+            issues.extend(abstraction_issues.into_iter().map(|mut issue| {
+                issue.location = format!("{} {}", abstraction_file.path, issue.location);
+                issue
+            }));
+        }
+    }
+
+    if !compatibility.unsupported.is_empty() {
+        let issues: Vec<String> = compatibility
+            .unsupported
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect();
+
+        return Err(anyhow!(
+            "Heavy cannot compile this patch: {}",
+            issues.join("; ")
+        ));
+    }
+
+    build_options.validate()?;
+
+    let mut samples: Vec<EmbeddedSample> = vec![];
+    if !sample_files.is_empty() {
+        // Decoding and resampling is CPU-bound, so it stays off the async executor
+        let contents = patch_contents.clone();
+        let sample_rate = build_options.effective_sample_rate();
+        let (contents_with_samples, embedded_samples) = web::block(move || {
+            let patch = validate_patch_file_contents(&contents)?;
+            embed_samples(&contents, &patch, &sample_files, sample_rate)
+        })
+        .await??;
+        debug!("Embedded samples: {:?}", embedded_samples);
+
+        parsed_patch = validate_patch_file_contents(&contents_with_samples)?;
+        patch_contents = contents_with_samples;
+        samples = embedded_samples;
+    }
+
+    let lint_findings = lint_patch(&parsed_patch, &known_abstractions);
+
+    // The program size is the same for every layout, only the budgets it is compared to change
+    let program_bytes = estimate_memory(
+        &parsed_patch,
+        &memory_layout,
+        build_options.effective_sample_rate(),
+    )
+    .program_bytes;
+    let build_memory_layout = memory_layout_for_samples(&memory_layout, &samples, program_bytes);
+    let memory_estimate = estimate_memory(
+        &parsed_patch,
+        &build_memory_layout,
+        build_options.effective_sample_rate(),
+    );
+    debug!("Memory estimate: {:?}", memory_estimate);
+
+    let group_id = match boards_in.len() {
+        1 => None,
+        _ => Some(Uuid::new_v4().to_string()),
     };
-    debug!("Created patch meta: {:?}", &patch_meta);
 
-    write_patch_to_disk(&patch_meta.id, &patch_contents).await?;
+    let mut patches: Vec<PatchMeta> = vec![];
+
+    // Every board gets its own copy of the uploaded files, so each child builds independently
+    for board in boards_in {
+        let patch_id = Uuid::new_v4();
+
+        let controls = board_controls(&board, board_def_contents_in.as_deref())?;
+
+        // Each board has its own control names, so every child gets its own renamed copy
+        let mut board_patch_contents = patch_contents.clone();
+        let mut parameters = check_parameters(&parsed_patch, &controls);
+        if !parameter_mappings.is_empty() {
+            board_patch_contents = apply_parameter_mappings(
+                &patch_contents,
+                &parsed_patch,
+                &parameter_mappings,
+                &controls,
+            )
+            .map_err(|err| anyhow!("{}: {}", board.display_name(), err))?;
+            parameters = check_parameters(
+                &validate_patch_file_contents(&board_patch_contents)?,
+                &controls,
+            );
+        }
+
+        let patch_meta = PatchMeta {
+            id: patch_id.to_string(),
+            status: PatchStatus::Uploaded,
+            board,
+            memory_layout_requested: memory_layout.clone(),
+            memory_layout: build_memory_layout.clone(),
+            build_options: build_options.clone(),
+            build_profile: build_profile.clone(),
+            toolchain: toolchain.clone(),
+            group_id: group_id.clone(),
+            origin_patch_id: None,
+            compatibility_warnings: compatibility.warnings.clone(),
+            lint_findings: lint_findings.clone(),
+            parameters: Some(parameters),
+            parameter_mappings: parameter_mappings.clone(),
+            autofix: autofix_report.clone(),
+            project_files: project_files.iter().map(|file| file.path.clone()).collect(),
+            search_paths: search_paths.clone(),
+            samples: samples.clone(),
+            memory_estimate: Some(memory_estimate.clone()),
+            compiler_cache: None,
+            resource_usage: None,
+            filename: filename.clone(),
+            time_upload: DateTime::now(),
+            time_compile_start: None,
+            time_compile_end: None,
+        };
+        debug!("Created patch meta: {:?}", &patch_meta);
+
+        write_patch_to_disk(&patch_meta.patch_upload_filename(), &board_patch_contents).await?;
+
+        if !patch_meta.parameter_mappings.is_empty() {
+            write_patch_to_disk(
+                &patch_meta.unmapped_patch_upload_filename(),
+                &patch_contents,
+            )
+            .await?;
+        }
+
+        if let Some(original_patch_contents) = &original_patch_contents {
+            write_patch_to_disk(
+                &patch_meta.original_patch_upload_filename(),
+                original_patch_contents,
+            )
+            .await?;
+        }
+
+        for project_file in project_files.iter() {
+            write_project_file_to_disk(
+                &patch_meta.project_file_upload_filename(&project_file.path),
+                &project_file.contents,
+            )
+            .await?;
+        }
+
+        if let (Board::SeedCustomJson, Some(board_def_contents)) =
+            (&patch_meta.board, &board_def_contents_in)
+        {
+            write_board_def_to_disk(&patch_meta.board_def_upload_filename(), board_def_contents)
+                .await?;
+        }
 
-    if let Some(board_def_contents) = board_def_contents_in {
-        write_board_def_to_disk(&patch_meta.id, &board_def_contents).await?;
+        patches.push(patch_meta);
     }
 
-    Ok(patch_meta)
+    let group = group_id.map(|group_id| BuildGroup {
+        id: group_id,
+        filename,
+        patch_ids: patches.iter().map(|patch| patch.id.clone()).collect(),
+        time_upload: DateTime::now(),
+    });
+
+    Ok(PatchUpload { patches, group })
 }
 
-async fn write_patch_to_disk(patch_id: &str, file_contents: &str) -> Result<()> {
-    let patch_id = patch_id.to_string();
+async fn write_patch_to_disk(filename: &str, file_contents: &str) -> Result<()> {
+    let path = get_workspace_path(&get_env_config(), "uploads", filename);
     let file_contents = file_contents.to_string();
 
     let result = web::block(move || {
-        let mut file = fs::File::create(format!("workspace/uploads/{patch_id}.pd")).unwrap();
+        let mut file = fs::File::create(path)?;
 
         file.write_all(file_contents.as_bytes())
     })
@@ -152,13 +420,31 @@ async fn write_patch_to_disk(patch_id: &str, file_contents: &str) -> Result<()>
     }
 }
 
-async fn write_board_def_to_disk(patch_id: &str, file_contents: &str) -> Result<()> {
-    let patch_id = patch_id.to_string();
+async fn write_project_file_to_disk(filename: &str, file_contents: &[u8]) -> Result<()> {
+    let path = get_workspace_path(&get_env_config(), "uploads", filename);
+    let file_contents = file_contents.to_vec();
+
+    let result = web::block(move || {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        fs::write(path, file_contents)
+    })
+    .await?;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow!("Failed to save the uploaded files to disk")),
+    }
+}
+
+async fn write_board_def_to_disk(filename: &str, file_contents: &str) -> Result<()> {
+    let path = get_workspace_path(&get_env_config(), "uploads", filename);
     let file_contents = file_contents.to_string();
 
     let result = web::block(move || {
-        let mut file =
-            fs::File::create(format!("workspace/uploads/{patch_id}_board_def.json")).unwrap();
+        let mut file = fs::File::create(path)?;
 
         file.write_all(file_contents.as_bytes())
     })
@@ -170,26 +456,76 @@ async fn write_board_def_to_disk(patch_id: &str, file_contents: &str) -> Result<
     }
 }
 
-fn parse_upload_form_item(multipart_field: &Field, chunk_contents: &str) -> UploadFormItem {
+fn parse_upload_form_item(
+    multipart_field: &Field,
+    field_contents: Vec<u8>,
+) -> Result<UploadFormItem> {
     let content_disposition = multipart_field.content_disposition();
 
-    match (&content_disposition.disposition, multipart_field.name()) {
+    // Patch files are kept as bytes, since they can be archives
+    if let (&DispositionType::FormData, "pd_patch") =
+        (&content_disposition.disposition, multipart_field.name())
+    {
+        let filename = get_filename(content_disposition);
+
+        return Ok(match (filename, field_contents.is_empty()) {
+            (Some(filename), false) => {
+                debug!("Parsed a file upload: {}", filename);
+                UploadFormItem::PatchFileUpload {
+                    filename,
+                    file_contents: field_contents,
+                }
+            }
+            _ => UploadFormItem::Unrecognized,
+        });
+    }
+
+    let chunk_contents = std::str::from_utf8(&field_contents)?;
+    debug!("Field contents:\n{}", chunk_contents);
+
+    let form_item = match (&content_disposition.disposition, multipart_field.name()) {
         (&DispositionType::FormData, "board") => {
             let board_option = Board::from_str(chunk_contents).unwrap();
             debug!("Parsed a board option: {:?}", board_option);
             UploadFormItem::BoardOption(board_option)
         }
-        (&DispositionType::FormData, "pd_patch") => {
-            let filename = get_filename(content_disposition);
-            match (filename, chunk_contents.is_empty()) {
-                (Some(filename), false) => {
-                    debug!("Parsed a file upload: {}", filename);
-                    UploadFormItem::PatchFileUpload {
-                        filename,
-                        file_contents: chunk_contents.to_string(),
-                    }
-                }
-                _ => UploadFormItem::Unrecognized,
+        (&DispositionType::FormData, "memory_layout") => {
+            let memory_layout_option = MemoryLayout::from_str(chunk_contents)
+                .map_err(|_| anyhow!("Invalid memory layout: {chunk_contents}"))?;
+            debug!("Parsed a memory layout option: {:?}", memory_layout_option);
+            UploadFormItem::MemoryLayoutOption(memory_layout_option)
+        }
+        (&DispositionType::FormData, "build_profile") => {
+            let build_profile_option = BuildProfile::from_str(chunk_contents)
+                .map_err(|_| anyhow!("Invalid build profile: {chunk_contents}"))?;
+            debug!("Parsed a build profile option: {:?}", build_profile_option);
+            UploadFormItem::BuildProfileOption(build_profile_option)
+        }
+        (&DispositionType::FormData, "toolchain") => {
+            let toolchain = get_toolchain(chunk_contents)
+                .ok_or_else(|| anyhow!("Unknown toolchain: {chunk_contents}"))?;
+            debug!("Parsed a toolchain option: {}", toolchain.name);
+            UploadFormItem::ToolchainOption(toolchain.name.clone())
+        }
+        (&DispositionType::FormData, "main_patch") if !chunk_contents.trim().is_empty() => {
+            debug!("Parsed a main patch option: {}", chunk_contents);
+            UploadFormItem::MainPatchOption(chunk_contents.trim().to_string())
+        }
+        (&DispositionType::FormData, "parameter_mappings") => {
+            let parameter_mappings_option = parse_parameter_mappings(chunk_contents)?;
+            debug!("Parsed parameter mappings: {:?}", parameter_mappings_option);
+            UploadFormItem::ParameterMappingsOption(parameter_mappings_option)
+        }
+        (&DispositionType::FormData, "autofix") => {
+            let autofix_option = matches!(chunk_contents.trim(), "on" | "true");
+            debug!("Parsed an auto-fix option: {}", autofix_option);
+            UploadFormItem::AutofixOption(autofix_option)
+        }
+        (&DispositionType::FormData, name) if BUILD_OPTION_FORM_FIELDS.contains(&name) => {
+            debug!("Parsed a build option: {}", name);
+            UploadFormItem::BuildOption {
+                name: name.to_string(),
+                value: chunk_contents.to_string(),
             }
         }
         (&DispositionType::FormData, "board_def") => {
@@ -206,7 +542,9 @@ fn parse_upload_form_item(multipart_field: &Field, chunk_contents: &str) -> Uplo
             }
         }
         _ => UploadFormItem::Unrecognized,
-    }
+    };
+
+    Ok(form_item)
 }
 
 fn get_filename(content_disposition: &ContentDisposition) -> Option<String> {
@@ -0,0 +1,287 @@
+use log::{error, info};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::compilation_worker::spawn_worker;
+use crate::patches::{PatchMeta, PatchStatus, PatchesStore};
+
+/// The idle worker polls the queue every few seconds, so it is stuck if it hasn't for this long
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// No build takes this long, a worker still on the same patch after it is stuck
+const MAX_BUILD_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// The worker is unhealthy while it has crashed this often within `WORKER_RESTART_WINDOW`
+const MAX_RECENT_RESTARTS: usize = 3;
+
+const WORKER_RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+const WORKER_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+pub struct WorkerHealth {
+    last_poll: Mutex<Instant>,
+    /// The patch being built and when the build started
+    current_build: Mutex<Option<(String, Instant)>>,
+    recent_restarts: Mutex<VecDeque<Instant>>,
+    restarts: AtomicUsize,
+}
+
+impl WorkerHealth {
+    pub fn new() -> Self {
+        WorkerHealth {
+            last_poll: Mutex::new(Instant::now()),
+            current_build: Mutex::new(None),
+            recent_restarts: Mutex::new(VecDeque::new()),
+            restarts: AtomicUsize::new(0),
+        }
+    }
+
+    /// Called every time the worker looks at the queue
+    pub fn polled(&self) {
+        *self
+            .last_poll
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+    }
+
+    /// Healthy while the worker keeps polling the queue or its current build is within
+    /// `MAX_BUILD_DURATION`, and it isn't crashing over and over
+    pub fn is_healthy(&self) -> bool {
+        let making_progress = match &*self
+            .current_build
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            Some((_, started)) => started.elapsed() < MAX_BUILD_DURATION,
+            None => {
+                self.last_poll
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .elapsed()
+                    < WORKER_IDLE_TIMEOUT
+            }
+        };
+
+        making_progress && self.recent_restarts() < MAX_RECENT_RESTARTS
+    }
+
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::SeqCst)
+    }
+
+    pub fn set_current_patch(&self, patch_id: Option<String>) {
+        *self
+            .current_build
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) =
+            patch_id.map(|patch_id| (patch_id, Instant::now()));
+    }
+
+    fn take_current_patch(&self) -> Option<String> {
+        self.current_build
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .map(|(patch_id, _)| patch_id)
+    }
+
+    /// Count a crash, returning the total number of restarts
+    fn record_restart(&self) -> usize {
+        self.recent_restarts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(Instant::now());
+
+        self.restarts.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn recent_restarts(&self) -> usize {
+        let mut recent_restarts = self
+            .recent_restarts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        while let Some(restart) = recent_restarts.front() {
+            if restart.elapsed() < WORKER_RESTART_WINDOW {
+                break;
+            }
+            recent_restarts.pop_front();
+        }
+
+        recent_restarts.len()
+    }
+}
+
+/// Run the compilation worker, restarting it whenever it panics
+pub async fn supervise_worker(
+    patches_store: Arc<PatchesStore>,
+    worker_health: Arc<WorkerHealth>,
+    stop_signal: CancellationToken,
+) {
+    let worker = {
+        let patches_store = Arc::clone(&patches_store);
+        let worker_health = Arc::clone(&worker_health);
+        let stop_signal = stop_signal.clone();
+
+        move || {
+            spawn_worker(
+                Arc::clone(&patches_store),
+                Arc::clone(&worker_health),
+                stop_signal.clone(),
+            )
+        }
+    };
+
+    restart_on_panic(worker, &patches_store, &worker_health, &stop_signal).await;
+}
+
+async fn restart_on_panic<Worker, WorkerFuture>(
+    worker: Worker,
+    patches_store: &PatchesStore,
+    worker_health: &WorkerHealth,
+    stop_signal: &CancellationToken,
+) where
+    Worker: Fn() -> WorkerFuture,
+    WorkerFuture: Future<Output = ()> + Send + 'static,
+{
+    loop {
+        worker_health.polled();
+
+        let worker_result = tokio::spawn(worker()).await;
+
+        match worker_result {
+            Ok(()) => break,
+            Err(err) if err.is_panic() => {
+                let restarts = worker_health.record_restart();
+                error!("Compilation worker crashed (restart #{restarts})");
+
+                clear_poison(patches_store);
+
+                if let Some(patch_id) = worker_health.take_current_patch() {
+                    mark_patch_crashed(&patch_id, patches_store);
+                }
+            }
+            Err(_) => break,
+        }
+
+        tokio::select! {
+            _ = sleep(WORKER_RESTART_DELAY) => {
+                info!("Restarting compilation worker...");
+            }
+
+            _ = stop_signal.cancelled() => {
+                break;
+            }
+        };
+    }
+}
+
+/// A worker that panicked while holding a lock poisons it, but what it guards is still usable.
+/// Clearing it keeps route handlers and the next worker from failing on it.
+fn clear_poison(patches_store: &PatchesStore) {
+    patches_store.patches.clear_poison();
+    patches_store.compilation_queue.clear_poison();
+    patches_store.leases.clear_poison();
+    patches_store.groups.clear_poison();
+}
+
+fn mark_patch_crashed(patch_id: &str, patches_store: &PatchesStore) {
+    let mut patches = patches_store
+        .patches
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    if let Some(patch) = patches.get(patch_id) {
+        error!(
+            "Marking patch {} as failed after the worker crashed",
+            patch_id
+        );
+
+        let failed_patch = PatchMeta {
+            status: PatchStatus::Failed {
+                summary: "the compilation worker crashed while building this patch".to_string(),
+                details: None,
+                explanation: None,
+            },
+            ..patch.clone()
+        };
+        patches.insert(patch_id.to_string(), failed_patch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patches::tests::{queued_store, uploaded_patch};
+
+    #[tokio::test]
+    async fn restarts_a_crashed_worker_and_fails_its_patch() {
+        let patches_store = Arc::new(queued_store(vec![uploaded_patch("a")]));
+        let worker_health = Arc::new(WorkerHealth::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let worker = {
+            let patches_store = Arc::clone(&patches_store);
+            let worker_health = Arc::clone(&worker_health);
+            let runs = Arc::clone(&runs);
+
+            move || {
+                let patches_store = Arc::clone(&patches_store);
+                let worker_health = Arc::clone(&worker_health);
+                let is_first_run = runs.fetch_add(1, Ordering::SeqCst) == 0;
+
+                async move {
+                    if is_first_run {
+                        worker_health.set_current_patch(Some("a".to_string()));
+
+                        // Crash while holding the lock, poisoning it
+                        let _patches = patches_store.patches.lock().unwrap();
+                        panic!("worker crashed");
+                    }
+                }
+            }
+        };
+
+        restart_on_panic(
+            worker,
+            &patches_store,
+            &worker_health,
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(worker_health.restarts(), 1);
+        assert!(worker_health.take_current_patch().is_none());
+
+        let patches = patches_store.patches.lock().unwrap();
+        assert!(matches!(patches["a"].status, PatchStatus::Failed { .. }));
+    }
+
+    #[test]
+    fn unhealthy_when_crashing_repeatedly_or_stuck() {
+        let worker_health = WorkerHealth::new();
+        assert!(worker_health.is_healthy());
+
+        for _ in 0..MAX_RECENT_RESTARTS {
+            worker_health.record_restart();
+        }
+        assert!(!worker_health.is_healthy());
+        assert_eq!(worker_health.restarts(), MAX_RECENT_RESTARTS);
+
+        let worker_health = WorkerHealth::new();
+        worker_health.set_current_patch(Some("a".to_string()));
+        assert!(worker_health.is_healthy());
+
+        // The clock can be too close to boot to go back that far
+        if let Some(started) = Instant::now().checked_sub(MAX_BUILD_DURATION) {
+            *worker_health.current_build.lock().unwrap() = Some(("a".to_string(), started));
+            assert!(!worker_health.is_healthy());
+        }
+    }
+}